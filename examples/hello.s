@@ -0,0 +1,37 @@
+# A static RV64 executable without libc, built into examples/hello with
+#   llvm-mc -triple=riscv64 -mattr=+m,+c -filetype=obj hello.s -o hello.o
+#   ld.lld hello.o -o hello
+    .globl _start
+    .text
+_start:
+    ld a0, 0(sp)            # argc
+    call count_arguments
+    mv s0, a0
+    lla a0, message
+    call strlen
+    mv a2, a0
+    li a0, 1
+    lla a1, message
+    li a7, 64               # write
+    ecall
+    mv a0, s0
+    li a7, 93               # exit
+    ecall
+
+# Returns argc - 1, the number of arguments after the program name.
+count_arguments:
+    addi a0, a0, -1
+    ret
+
+strlen:
+    mv a1, a0
+1:  lbu a2, 0(a1)
+    beqz a2, 2f
+    addi a1, a1, 1
+    j 1b
+2:  sub a0, a1, a0
+    ret
+
+    .data
+message:
+    .string "Hello from an ELF executable\n"
@@ -0,0 +1,804 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::data;
+use crate::registers::Registers;
+
+// Major opcodes of the RV64IM base instructions.
+const LOAD: u32 = 0x03;
+const OP_IMM: u32 = 0x13;
+const AUIPC: u32 = 0x17;
+const OP_IMM_32: u32 = 0x1b;
+const STORE: u32 = 0x23;
+const OP: u32 = 0x33;
+const LUI: u32 = 0x37;
+const OP_32: u32 = 0x3b;
+const BRANCH: u32 = 0x63;
+const JALR: u32 = 0x67;
+const JAL: u32 = 0x6f;
+const MISC_MEM: u32 = 0x0f;
+const SYSTEM: u32 = 0x73;
+
+// Register-register instructions as (name, opcode, funct3, funct7).
+const R_TYPE: [(&str, u32, u32, u32); 28] = [
+    ("add", OP, 0, 0x00), ("sub", OP, 0, 0x20), ("sll", OP, 1, 0x00), ("slt", OP, 2, 0x00),
+    ("sltu", OP, 3, 0x00), ("xor", OP, 4, 0x00), ("srl", OP, 5, 0x00), ("sra", OP, 5, 0x20),
+    ("or", OP, 6, 0x00), ("and", OP, 7, 0x00),
+    ("mul", OP, 0, 0x01), ("mulh", OP, 1, 0x01), ("mulhsu", OP, 2, 0x01), ("mulhu", OP, 3, 0x01),
+    ("div", OP, 4, 0x01), ("divu", OP, 5, 0x01), ("rem", OP, 6, 0x01), ("remu", OP, 7, 0x01),
+    ("addw", OP_32, 0, 0x00), ("subw", OP_32, 0, 0x20), ("sllw", OP_32, 1, 0x00), ("srlw", OP_32, 5, 0x00),
+    ("sraw", OP_32, 5, 0x20), ("mulw", OP_32, 0, 0x01), ("divw", OP_32, 4, 0x01), ("divuw", OP_32, 5, 0x01),
+    ("remw", OP_32, 6, 0x01), ("remuw", OP_32, 7, 0x01)
+];
+
+// Register-immediate instructions as (name, opcode, funct3).
+const I_TYPE: [(&str, u32, u32); 7] = [
+    ("addi", OP_IMM, 0), ("slti", OP_IMM, 2), ("sltiu", OP_IMM, 3), ("xori", OP_IMM, 4),
+    ("ori", OP_IMM, 6), ("andi", OP_IMM, 7), ("addiw", OP_IMM_32, 0)
+];
+
+// Shifts by an immediate as (name, opcode, funct3, upper immediate bits, maximum shift).
+const SHIFTS: [(&str, u32, u32, u32, i64); 6] = [
+    ("slli", OP_IMM, 1, 0x000, 63), ("srli", OP_IMM, 5, 0x000, 63), ("srai", OP_IMM, 5, 0x400, 63),
+    ("slliw", OP_IMM_32, 1, 0x000, 31), ("srliw", OP_IMM_32, 5, 0x000, 31), ("sraiw", OP_IMM_32, 5, 0x400, 31)
+];
+
+const LOADS: [(&str, u32); 7] = [("lb", 0), ("lh", 1), ("lw", 2), ("ld", 3), ("lbu", 4), ("lhu", 5), ("lwu", 6)];
+const STORES: [(&str, u32); 4] = [("sb", 0), ("sh", 1), ("sw", 2), ("sd", 3)];
+const BRANCHES: [(&str, u32); 6] = [("beq", 0), ("bne", 1), ("blt", 4), ("bge", 5), ("bltu", 6), ("bgeu", 7)];
+
+// Branch pseudo instructions as (name, real branch, swap the operands, compares with zero).
+const BRANCH_ALIASES: [(&str, &str, bool, bool); 10] = [
+    ("bgt", "blt", true, false), ("ble", "bge", true, false), ("bgtu", "bltu", true, false), ("bleu", "bgeu", true, false),
+    ("beqz", "beq", false, true), ("bnez", "bne", false, true), ("bltz", "blt", false, true), ("bgez", "bge", false, true),
+    ("bgtz", "blt", true, true), ("blez", "bge", true, true)
+];
+
+const ZERO: u32 = 0;
+const RA: u32 = 1;
+const SP: u32 = 2;
+const T1: u32 = 6;
+
+// How a relocation patches the code, the RISC-V ELF relocation types.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RelocationKind {
+    // An auipc and jalr pair that calls the symbol.
+    Call,
+    // The upper 20 bits of the offset from an auipc to the symbol.
+    PcrelHi20,
+    // The lower 12 bits of the offset from the auipc, for an I-type instruction like addi or a load.
+    PcrelLo12,
+    // The same for a store.
+    PcrelLo12S,
+    // The upper 20 bits of the symbol's address, for lui.
+    Hi20,
+    // The lower 12 bits of the symbol's address, for an I-type instruction or a store.
+    Lo12I,
+    Lo12S
+}
+
+impl RelocationKind {
+    pub const ALL: [RelocationKind; 7] = [RelocationKind::Call, RelocationKind::PcrelHi20, RelocationKind::PcrelLo12,
+        RelocationKind::PcrelLo12S, RelocationKind::Hi20, RelocationKind::Lo12I, RelocationKind::Lo12S];
+
+    // The immediate of the instruction for a value, an absolute address or an offset from the auipc.
+    pub fn immediate(self, value: i64) -> i64 {
+        match self {
+            RelocationKind::Call | RelocationKind::PcrelHi20 | RelocationKind::Hi20 => hi20(value),
+            _ => value - (hi20(value) << 12)
+        }
+    }
+
+    pub fn is_pcrel(self) -> bool {
+        !matches!(self, RelocationKind::Hi20 | RelocationKind::Lo12I | RelocationKind::Lo12S)
+    }
+
+    // Put the immediate into the encoded instruction.
+    pub fn patch(self, word: u32, immediate: i64) -> u32 {
+        let immediate = immediate as u32;
+        match self {
+            RelocationKind::Call | RelocationKind::PcrelHi20 | RelocationKind::Hi20 => word & 0xfff | immediate << 12,
+            RelocationKind::PcrelLo12 | RelocationKind::Lo12I => word & 0xfffff | immediate << 20,
+            RelocationKind::PcrelLo12S | RelocationKind::Lo12S => word & 0x1fff07f | (immediate >> 5 & 0x7f) << 25 | (immediate & 0x1f) << 7
+        }
+    }
+}
+
+// The upper 20 bits of a value that the lower 12 bits are added to with sign extension.
+pub fn hi20(value: i64) -> i64 {
+    (value + 0x800) >> 12
+}
+
+// A reference from the code to a symbol whose address is not known while assembling.
+pub struct Relocation {
+    pub offset: u64,
+    pub kind: RelocationKind,
+    pub symbol: String,
+    pub addend: i64,
+    // Where the auipc is that pc relative offsets are from.
+    pub auipc: u64
+}
+
+// A %hi, %lo, %pcrel_hi or %pcrel_lo operand. The program has the value it has in the
+// interpreter, the assembler recomputes it for the addresses of the machine code.
+#[derive(Clone, Debug)]
+pub struct AddressOperand {
+    pub kind: RelocationKind,
+    pub symbol: String,
+    pub addend: i64,
+    // The symbol's program index for code labels, its address for data.
+    pub value: i64,
+    pub code: bool,
+    // The program index of the auipc that pc relative offsets are from.
+    pub auipc: usize
+}
+
+// The machine code of a program, with the byte address of every instruction of the program list.
+pub struct MachineCode {
+    pub code: Vec<u8>,
+    // addresses[i] is where program[i] starts, the last entry is the end of the code.
+    pub addresses: Vec<u64>,
+    pub relocations: Vec<Relocation>
+}
+
+impl MachineCode {
+    // The encoded words of program[index], 16 bit compressed instructions have their low two bits not both set.
+    pub fn words(&self, index: usize) -> Vec<u32> {
+        let (start, end) = (self.addresses[index] as usize, self.addresses[index + 1] as usize);
+        let mut words = Vec::new();
+        let mut position = start;
+        while position < end {
+            let half = u16::from_le_bytes([self.code[position], self.code[position + 1]]) as u32;
+            if half & 0b11 == 0b11 {
+                words.push(half | (u16::from_le_bytes([self.code[position + 2], self.code[position + 3]]) as u32) << 16);
+                position += 4;
+            } else {
+                words.push(half);
+                position += 2;
+            }
+        }
+        words
+    }
+
+    // Print the address and encoding of every instruction next to its text.
+    pub fn print_listing(&self, program: &[String]) {
+        for (i, instruction) in program.iter().enumerate() {
+            let mut address = self.addresses[i];
+            for (j, word) in self.words(i).into_iter().enumerate() {
+                let encoding = if word & 0b11 == 0b11 { format!("{:08x}", word) } else { format!("    {:04x}", word) };
+                println!("{:8x}:  {}  {}", address, encoding, if j == 0 { &instruction[..] } else { "" });
+                address += if word & 0b11 == 0b11 { 4 } else { 2 };
+            }
+        }
+    }
+}
+
+pub fn parse_register(name: &str) -> Result<u32, String> {
+    let name = name.trim();
+    if name == "fp" {
+        return Ok(8);
+    }
+    if let Some(number) = name.strip_prefix('x').and_then(|number| number.parse::<u32>().ok()) {
+        if number < 32 {
+            return Ok(number);
+        }
+    }
+    Registers::NAMES.iter().position(|register| *register == name)
+        .map(|number| number as u32)
+        .ok_or_else(|| format!("\"{}\" is not a register", name))
+}
+
+pub fn parse_immediate(str: &str) -> Result<i64, String> {
+    data::parse_integer(str).ok_or_else(|| format!("\"{}\" is not a number", str.trim()))
+}
+
+// A jump target or address operand, labels are numbers by now so a name is one that was not found.
+fn parse_target(str: &str) -> Result<i64, String> {
+    let str = str.trim();
+    parse_immediate(str).map_err(|err| {
+        if str.starts_with(|char: char| char.is_ascii_alphabetic() || char == '_' || char == '.') {
+            format!("the label \"{}\" was not found", str)
+        } else {
+            err
+        }
+    })
+}
+
+// Split "offset(register)" into the register and the offset.
+fn parse_memory_operand(str: &str) -> Result<(u32, i64), String> {
+    let str = str.trim();
+    let (offset, register) = str.strip_suffix(')').and_then(|str| str.split_once('('))
+        .ok_or_else(|| format!("\"{}\" is not a memory operand like 8(sp)", str))?;
+    let offset = if offset.trim().is_empty() { 0 } else { parse_immediate(offset)? };
+    Ok((parse_register(register)?, offset))
+}
+
+// The i, o, r and w bits of a fence's predecessor or successor set.
+fn parse_fence_set(str: &str) -> Result<u32, String> {
+    let str = str.trim();
+    let mut set = 0;
+    for char in str.chars() {
+        let bit = "wroi".find(char).ok_or_else(|| format!("\"{}\" is not a fence set like rw or iorw", str))?;
+        set |= 1 << bit;
+    }
+    Ok(set)
+}
+
+fn check_range(value: i64, bits: u32, what: &str) -> Result<(), String> {
+    let limit = 1i64 << (bits - 1);
+    if value < -limit || value >= limit {
+        return Err(format!("{} {} does not fit in {} bits", what, value, bits));
+    }
+    Ok(())
+}
+
+fn r_type(opcode: u32, funct3: u32, funct7: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn i_type(opcode: u32, funct3: u32, rd: u32, rs1: u32, immediate: i64) -> Result<u32, String> {
+    check_range(immediate, 12, "immediate")?;
+    Ok(((immediate as u32) & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode)
+}
+
+fn s_type(funct3: u32, rs1: u32, rs2: u32, immediate: i64) -> Result<u32, String> {
+    check_range(immediate, 12, "offset")?;
+    let immediate = immediate as u32;
+    Ok((immediate >> 5 & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (immediate & 0x1f) << 7 | STORE)
+}
+
+fn b_type(funct3: u32, rs1: u32, rs2: u32, offset: i64) -> Result<u32, String> {
+    check_range(offset, 13, "branch offset")?;
+    let offset = offset as u32;
+    Ok((offset >> 12 & 1) << 31 | (offset >> 5 & 0x3f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12
+        | (offset >> 1 & 0xf) << 8 | (offset >> 11 & 1) << 7 | BRANCH)
+}
+
+fn u_type(opcode: u32, rd: u32, upper: i64) -> u32 {
+    ((upper as u32) & 0xfffff) << 12 | rd << 7 | opcode
+}
+
+fn j_type(rd: u32, offset: i64) -> Result<u32, String> {
+    check_range(offset, 21, "jump offset")?;
+    let offset = offset as u32;
+    Ok((offset >> 20 & 1) << 31 | (offset >> 1 & 0x3ff) << 21 | (offset >> 11 & 1) << 20 | (offset >> 12 & 0xff) << 12 | rd << 7 | JAL)
+}
+
+// A sequence of lui, addiw, addi, slli and srli that loads any 64 bit value, the one LLVM's
+// assembler picks without the bit manipulation extensions, at most 8 instructions.
+pub fn load_immediate(rd: u32, value: i64) -> Vec<u32> {
+    let mut words = load_immediate_parts(rd, value);
+    // Trailing zeros can be shifted in at the end.
+    if value & 1 == 0 && words.len() > 2 {
+        let shift = value.trailing_zeros();
+        let mut shifted = load_immediate_parts(rd, value >> shift);
+        shifted.push(i_type(OP_IMM, 1, rd, rd, shift as i64).unwrap());
+        if shifted.len() < words.len() {
+            words = shifted;
+        }
+    }
+    // Leading zeros can be shifted in with srli, from the value shifted up with the bits below
+    // it all ones (like 0xffffffff from -1) or zeros.
+    if value > 0 && words.len() > 2 {
+        let shift = value.leading_zeros();
+        let ones = (1i64 << shift) - 1;
+        for shifted_value in [value << shift | ones, value << shift] {
+            let mut shifted = load_immediate_parts(rd, shifted_value);
+            shifted.push(i_type(OP_IMM, 5, rd, rd, shift as i64).unwrap());
+            if shifted.len() < words.len() {
+                words = shifted;
+            }
+        }
+    }
+    words
+}
+
+fn load_immediate_parts(rd: u32, value: i64) -> Vec<u32> {
+    if (-2048..2048).contains(&value) {
+        return vec![i_type(OP_IMM, 0, rd, ZERO, value).unwrap()];
+    }
+    if value >= i32::MIN as i64 && value <= i32::MAX as i64 {
+        let upper = (value + 0x800) >> 12;
+        let lower = value - (upper << 12);
+        let mut words = vec![u_type(LUI, rd, upper)];
+        if lower != 0 {
+            words.push(i_type(OP_IMM_32, 0, rd, rd, lower).unwrap());
+        }
+        return words;
+    }
+    let lower = (value << 52) >> 52;
+    let mut upper = value.wrapping_sub(lower) >> 12;
+    let mut shift = 12 + upper.trailing_zeros();
+    upper >>= shift - 12;
+    // An upper part that does not fit in 12 bits can often be loaded with lui, 12 bits shifted up.
+    if shift > 12 && !(-2048..2048).contains(&upper) && (i32::MIN as i64..=i32::MAX as i64).contains(&(upper << 12)) {
+        shift -= 12;
+        upper <<= 12;
+    }
+    let mut words = load_immediate_parts(rd, upper);
+    words.push(i_type(OP_IMM, 1, rd, rd, shift as i64).unwrap());
+    if lower != 0 {
+        words.push(i_type(OP_IMM, 0, rd, rd, lower).unwrap());
+    }
+    words
+}
+
+// Encode one instruction of the program list. Jump and branch targets are program indices,
+// address_of turns them into byte addresses, pc is the address of the instruction itself.
+pub fn encode(instruction: &str, pc: u64, address_of: &dyn Fn(usize) -> Option<u64>) -> Result<Vec<u32>, String> {
+    let (instruction_name, params) = instruction.split_once(' ').unwrap_or((instruction, ""));
+    let params: Vec<&str> = if params.trim().is_empty() { Vec::new() } else { params.split(',').map(|param| param.trim()).collect() };
+    let expect = |count: usize| if params.len() == count {
+        Ok(())
+    } else {
+        Err(format!("\"{}\" expects {} operands, got {}", instruction_name, count, params.len()))
+    };
+    let register = |i: usize| parse_register(params[i]);
+    let immediate = |i: usize| parse_immediate(params[i]);
+    let offset_to = |i: usize| -> Result<i64, String> {
+        let target = parse_target(params[i])?;
+        let address = address_of(target as usize).ok_or_else(|| format!("{} is not an instruction of the program", target))?;
+        Ok(address as i64 - pc as i64)
+    };
+
+    if let Some(&(_, opcode, funct3, funct7)) = R_TYPE.iter().find(|entry| entry.0 == instruction_name) {
+        expect(3)?;
+        return Ok(vec![r_type(opcode, funct3, funct7, register(0)?, register(1)?, register(2)?)]);
+    }
+    if let Some(&(_, opcode, funct3)) = I_TYPE.iter().find(|entry| entry.0 == instruction_name) {
+        expect(3)?;
+        return Ok(vec![i_type(opcode, funct3, register(0)?, register(1)?, immediate(2)?)?]);
+    }
+    // slriw is accepted by the evaluator as a spelling of srliw.
+    let shift_name = if instruction_name == "slriw" { "srliw" } else { instruction_name };
+    if let Some(&(_, opcode, funct3, upper, maximum)) = SHIFTS.iter().find(|entry| entry.0 == shift_name) {
+        expect(3)?;
+        let shift = immediate(2)?;
+        if !(0..=maximum).contains(&shift) {
+            return Err(format!("shift amount {} is out of range", shift));
+        }
+        return Ok(vec![i_type(opcode, funct3, register(0)?, register(1)?, (upper as i64) | shift)?]);
+    }
+    if let Some(&(_, funct3)) = LOADS.iter().find(|entry| entry.0 == instruction_name) {
+        expect(2)?;
+        let (base, offset) = parse_memory_operand(params[1])?;
+        return Ok(vec![i_type(LOAD, funct3, register(0)?, base, offset)?]);
+    }
+    if let Some(&(_, funct3)) = STORES.iter().find(|entry| entry.0 == instruction_name) {
+        expect(2)?;
+        let (base, offset) = parse_memory_operand(params[1])?;
+        return Ok(vec![s_type(funct3, base, register(0)?, offset)?]);
+    }
+    if let Some(&(_, funct3)) = BRANCHES.iter().find(|entry| entry.0 == instruction_name) {
+        expect(3)?;
+        return Ok(vec![b_type(funct3, register(0)?, register(1)?, offset_to(2)?)?]);
+    }
+    if let Some(&(_, branch, swap, zero)) = BRANCH_ALIASES.iter().find(|entry| entry.0 == instruction_name) {
+        let funct3 = BRANCHES.iter().find(|entry| entry.0 == branch).unwrap().1;
+        let (a, b, target) = if zero {
+            expect(2)?;
+            (register(0)?, ZERO, 1)
+        } else {
+            expect(3)?;
+            (register(0)?, register(1)?, 2)
+        };
+        let (rs1, rs2) = if swap { (b, a) } else { (a, b) };
+        return Ok(vec![b_type(funct3, rs1, rs2, offset_to(target)?)?]);
+    }
+
+    let word = match instruction_name {
+        "nop" => i_type(OP_IMM, 0, ZERO, ZERO, 0)?,
+        "mv" => {
+            expect(2)?;
+            i_type(OP_IMM, 0, register(0)?, register(1)?, 0)?
+        },
+        "not" => {
+            expect(2)?;
+            i_type(OP_IMM, 4, register(0)?, register(1)?, -1)?
+        },
+        "neg" | "negw" => {
+            expect(2)?;
+            let opcode = if instruction_name == "neg" { OP } else { OP_32 };
+            r_type(opcode, 0, 0x20, register(0)?, ZERO, register(1)?)
+        },
+        "sext.w" => {
+            expect(2)?;
+            i_type(OP_IMM_32, 0, register(0)?, register(1)?, 0)?
+        },
+        "seqz" => {
+            expect(2)?;
+            i_type(OP_IMM, 3, register(0)?, register(1)?, 1)?
+        },
+        "snez" => {
+            expect(2)?;
+            r_type(OP, 3, 0, register(0)?, ZERO, register(1)?)
+        },
+        "sltz" => {
+            expect(2)?;
+            r_type(OP, 2, 0, register(0)?, register(1)?, ZERO)
+        },
+        "sgtz" => {
+            expect(2)?;
+            r_type(OP, 2, 0, register(0)?, ZERO, register(1)?)
+        },
+        "inc" | "dec" => {
+            expect(1)?;
+            let rd = register(0)?;
+            i_type(OP_IMM, 0, rd, rd, if instruction_name == "inc" { 1 } else { -1 })?
+        },
+        // Data lives at absolute addresses in the virtual memory, so its address is loaded as a constant.
+        "li" | "la" | "lla" => {
+            expect(2)?;
+            let value = parse_target(params[1])?;
+            return Ok(load_immediate(register(0)?, value));
+        },
+        "lui" | "auipc" => {
+            expect(2)?;
+            let upper = immediate(1)?;
+            if !(-0x80000..0x100000).contains(&upper) {
+                return Err(format!("immediate {} does not fit in 20 bits", upper));
+            }
+            u_type(if instruction_name == "lui" { LUI } else { AUIPC }, register(0)?, upper)
+        },
+        "j" | "tail" => {
+            expect(1)?;
+            j_type(ZERO, offset_to(0)?)?
+        },
+        "jal" | "call" => match params.len() {
+            1 => j_type(RA, offset_to(0)?)?,
+            2 => j_type(register(0)?, offset_to(1)?)?,
+            _ => return Err(format!("\"{}\" expects 1 or 2 operands, got {}", instruction_name, params.len()))
+        },
+        "jr" => {
+            expect(1)?;
+            i_type(JALR, 0, ZERO, register(0)?, 0)?
+        },
+        "ret" => {
+            expect(0)?;
+            i_type(JALR, 0, ZERO, RA, 0)?
+        },
+        "jalr" => match params.len() {
+            1 => i_type(JALR, 0, RA, register(0)?, 0)?,
+            2 => {
+                let (base, offset) = parse_memory_operand(params[1])?;
+                i_type(JALR, 0, register(0)?, base, offset)?
+            },
+            3 => i_type(JALR, 0, register(0)?, register(1)?, immediate(2)?)?,
+            _ => return Err(format!("\"jalr\" expects 1 to 3 operands, got {}", params.len()))
+        },
+        // A fence without operands orders everything, like "fence iorw,iorw".
+        "fence" => match params.len() {
+            0 => 0xff << 20 | MISC_MEM,
+            2 => parse_fence_set(params[0])? << 24 | parse_fence_set(params[1])? << 20 | MISC_MEM,
+            _ => return Err(format!("\"fence\" expects 0 or 2 operands, got {}", params.len()))
+        },
+        "fence.tso" => {
+            expect(0)?;
+            0x8 << 28 | 0x33 << 20 | MISC_MEM
+        },
+        "fence.i" => {
+            expect(0)?;
+            1 << 12 | MISC_MEM
+        },
+        "ecall" => SYSTEM,
+        "ebreak" => 1 << 20 | SYSTEM,
+        _ => return Err(format!("can not encode \"{}\"", instruction_name))
+    };
+    Ok(vec![word])
+}
+
+// An lla that takes the address of code, as auipc and addi relative to pc so the address is right
+// wherever the code is loaded.
+fn encode_code_address(instruction: &str, pc: u64, address_of: &dyn Fn(usize) -> Option<u64>) -> Result<Vec<u32>, String> {
+    let params: Vec<&str> = instruction.split_once(' ').map_or("", |split| split.1).split(',').map(|param| param.trim()).collect();
+    if params.len() != 2 {
+        return Err(format!("\"lla\" expects 2 operands, got {}", params.len()));
+    }
+    let rd = parse_register(params[0])?;
+    let target = parse_target(params[1])?;
+    let address = address_of(target as usize).ok_or_else(|| format!("{} is not an instruction of the program", target))?;
+    let offset = address as i64 - pc as i64;
+    Ok(vec![u_type(AUIPC, rd, hi20(offset)), i_type(OP_IMM, 0, rd, rd, RelocationKind::PcrelLo12.immediate(offset))?])
+}
+
+// An instruction that refers to a symbol, with zero offsets that relocations fill in: lla as auipc
+// and addi, calls and tail calls as auipc and jalr.
+fn encode_symbol_reference(instruction: &str) -> Result<Vec<u32>, String> {
+    let (instruction_name, params) = instruction.split_once(' ').unwrap_or((instruction, ""));
+    let params: Vec<&str> = params.split(',').map(|param| param.trim()).collect();
+    match (instruction_name, params.len()) {
+        ("lla", 2) => {
+            let rd = parse_register(params[0])?;
+            Ok(vec![u_type(AUIPC, rd, 0), i_type(OP_IMM, 0, rd, rd, 0)?])
+        },
+        ("call", 1) | ("jal", 1) | ("jal", 2) | ("call", 2) | ("tail", 1) => {
+            let link = match params.len() {
+                2 => parse_register(params[0])?,
+                _ if instruction_name == "tail" => ZERO,
+                _ => RA
+            };
+            let scratch = if link == ZERO { T1 } else { link };
+            Ok(vec![u_type(AUIPC, scratch, 0), i_type(JALR, 0, link, scratch, 0)?])
+        },
+        _ => Err(format!("\"{}\" can not refer to a symbol", instruction))
+    }
+}
+
+// The relocations of encode_symbol_reference, by offset from the instruction.
+fn symbol_relocations(instruction: &str) -> Vec<(u64, RelocationKind)> {
+    if instruction.starts_with("lla") {
+        vec![(0, RelocationKind::PcrelHi20), (4, RelocationKind::PcrelLo12)]
+    } else {
+        vec![(0, RelocationKind::Call)]
+    }
+}
+
+// Compressed register numbers x8 - x15 as their 3 bit field.
+fn compressed_register(register: u32) -> Option<u16> {
+    if (8..16).contains(&register) { Some(register as u16 - 8) } else { None }
+}
+
+// Bits from..=to of value moved to position at.
+fn bits(value: u32, to: u32, from: u32, at: u32) -> u16 {
+    ((value >> from & ((1 << (to - from + 1)) - 1)) << at) as u16
+}
+
+// The 16 bit RVC form of a 32 bit instruction, if there is one. Jumps and branches are never
+// compressed, so the size of an instruction does not depend on where its target ends up.
+pub fn compress(word: u32) -> Option<u16> {
+    let opcode = word & 0x7f;
+    let rd = word >> 7 & 0x1f;
+    let funct3 = word >> 12 & 0x7;
+    let rs1 = word >> 15 & 0x1f;
+    let rs2 = word >> 20 & 0x1f;
+    let funct7 = word >> 25;
+    let i_immediate = (word as i32 >> 20) as i64;
+    let s_immediate = ((word as i32 >> 25) << 5) as i64 | (word >> 7 & 0x1f) as i64;
+    let fits = |value: i64, bits: u32| value >= -(1 << (bits - 1)) && value < (1 << (bits - 1));
+    let imm6 = |value: i64| -> u16 { bits(value as u32, 5, 5, 12) | bits(value as u32, 4, 0, 2) };
+
+    match (opcode, funct3) {
+        (OP_IMM, 0) if rd == ZERO && rs1 == ZERO && i_immediate == 0 => Some(0x0001), // c.nop
+        (OP_IMM, 0) if rd != ZERO && rs1 == ZERO && fits(i_immediate, 6) => Some(0x4001 | (rd as u16) << 7 | imm6(i_immediate)), // c.li
+        (OP_IMM, 0) if rd != ZERO && rs1 != ZERO && i_immediate == 0 => Some(0x8002 | (rd as u16) << 7 | (rs1 as u16) << 2), // c.mv
+        (OP_IMM, 0) if rd != ZERO && rd == rs1 && i_immediate != 0 && fits(i_immediate, 6) => Some(0x0001 | (rd as u16) << 7 | imm6(i_immediate)), // c.addi
+        (OP_IMM, 0) if rd == SP && rs1 == SP && i_immediate != 0 && i_immediate % 16 == 0 && fits(i_immediate, 10) => {
+            let value = i_immediate as u32;
+            Some(0x6101 | bits(value, 9, 9, 12) | bits(value, 4, 4, 6) | bits(value, 6, 6, 5) | bits(value, 8, 7, 3) | bits(value, 5, 5, 2)) // c.addi16sp
+        },
+        (OP_IMM, 0) if rs1 == SP && i_immediate > 0 && i_immediate < 1024 && i_immediate % 4 == 0 => {
+            let value = i_immediate as u32;
+            compressed_register(rd).map(|rd| bits(value, 5, 4, 11) | bits(value, 9, 6, 7) | bits(value, 2, 2, 6) | bits(value, 3, 3, 5) | rd << 2) // c.addi4spn
+        },
+        (OP_IMM_32, 0) if rd != ZERO && rd == rs1 && fits(i_immediate, 6) => Some(0x2001 | (rd as u16) << 7 | imm6(i_immediate)), // c.addiw
+        (LUI, _) if rd != ZERO && rd != SP => {
+            let upper = (word as i32 >> 12) as i64;
+            if upper != 0 && fits(upper, 6) { Some(0x6001 | (rd as u16) << 7 | imm6(upper)) } else { None } // c.lui
+        },
+        (OP_IMM, 1) if rd != ZERO && rd == rs1 && i_immediate != 0 => Some(0x0002 | (rd as u16) << 7 | imm6(i_immediate)), // c.slli
+        (OP_IMM, 5) if rd == rs1 && i_immediate & 0x3f != 0 => {
+            let arithmetic = i_immediate & 0x400 != 0;
+            let shift = i_immediate & 0x3f;
+            compressed_register(rd).map(|rd| 0x8001 | (arithmetic as u16) << 10 | rd << 7 | imm6(shift)) // c.srli, c.srai
+        },
+        (OP_IMM, 7) if rd == rs1 && fits(i_immediate, 6) => compressed_register(rd).map(|rd| 0x8801 | rd << 7 | imm6(i_immediate)), // c.andi
+        (OP, 0) if funct7 == 0 && rd != ZERO && rd == rs1 && rs2 != ZERO => Some(0x9002 | (rd as u16) << 7 | (rs2 as u16) << 2), // c.add
+        (OP, 0) if funct7 == 0 && rd != ZERO && rs1 == ZERO && rs2 != ZERO => Some(0x8002 | (rd as u16) << 7 | (rs2 as u16) << 2), // c.mv
+        (OP, _) | (OP_32, _) if rd == rs1 => {
+            let (rd, rs2) = (compressed_register(rd)?, compressed_register(rs2)?);
+            let low = match (opcode, funct3, funct7) {
+                (OP, 0, 0x20) => 0x8c01, // c.sub
+                (OP, 4, 0) => 0x8c21,    // c.xor
+                (OP, 6, 0) => 0x8c41,    // c.or
+                (OP, 7, 0) => 0x8c61,    // c.and
+                (OP_32, 0, 0x20) => 0x9c01, // c.subw
+                (OP_32, 0, 0) => 0x9c21, // c.addw
+                _ => return None
+            };
+            Some(low | rd << 7 | rs2 << 2)
+        },
+        (LOAD, 3) if rd != ZERO && rs1 == SP && (0..512).contains(&i_immediate) && i_immediate % 8 == 0 => {
+            let value = i_immediate as u32;
+            Some(0x6002 | bits(value, 5, 5, 12) | (rd as u16) << 7 | bits(value, 4, 3, 5) | bits(value, 8, 6, 2)) // c.ldsp
+        },
+        (LOAD, 2) if rd != ZERO && rs1 == SP && (0..256).contains(&i_immediate) && i_immediate % 4 == 0 => {
+            let value = i_immediate as u32;
+            Some(0x4002 | bits(value, 5, 5, 12) | (rd as u16) << 7 | bits(value, 4, 2, 4) | bits(value, 7, 6, 2)) // c.lwsp
+        },
+        (STORE, 3) if rs1 == SP && (0..512).contains(&s_immediate) && s_immediate % 8 == 0 => {
+            let value = s_immediate as u32;
+            Some(0xe002 | bits(value, 5, 3, 10) | bits(value, 8, 6, 7) | (rs2 as u16) << 2) // c.sdsp
+        },
+        (STORE, 2) if rs1 == SP && (0..256).contains(&s_immediate) && s_immediate % 4 == 0 => {
+            let value = s_immediate as u32;
+            Some(0xc002 | bits(value, 5, 2, 9) | bits(value, 7, 6, 7) | (rs2 as u16) << 2) // c.swsp
+        },
+        (LOAD, 3) | (STORE, 3) => {
+            let (offset, register) = if opcode == LOAD { (i_immediate, rd) } else { (s_immediate, rs2) };
+            if !(0..256).contains(&offset) || offset % 8 != 0 {
+                return None;
+            }
+            let value = offset as u32;
+            let base = if opcode == LOAD { 0x6000 } else { 0xe000 };
+            Some(base | bits(value, 5, 3, 10) | compressed_register(rs1)? << 7 | bits(value, 7, 6, 5) | compressed_register(register)? << 2) // c.ld, c.sd
+        },
+        (LOAD, 2) | (STORE, 2) => {
+            let (offset, register) = if opcode == LOAD { (i_immediate, rd) } else { (s_immediate, rs2) };
+            if !(0..128).contains(&offset) || offset % 4 != 0 {
+                return None;
+            }
+            let value = offset as u32;
+            let base = if opcode == LOAD { 0x4000 } else { 0xc000 };
+            Some(base | bits(value, 5, 3, 10) | compressed_register(rs1)? << 7 | bits(value, 2, 2, 6) | bits(value, 6, 6, 5) | compressed_register(register)? << 2) // c.lw, c.sw
+        },
+        (JALR, 0) if rs1 != ZERO && i_immediate == 0 && (rd == ZERO || rd == RA) => Some(if rd == ZERO { 0x8002 } else { 0x9002 } | (rs1 as u16) << 7), // c.jr, c.jalr
+        (SYSTEM, 0) if word == 1 << 20 | SYSTEM => Some(0x9002), // c.ebreak
+        _ => None
+    }
+}
+
+// Assemble a program whose labels were already replaced by program indices. code_references are
+// the indices of lla instructions that load the address of code, address_operands those with
+// %hi, %lo, %pcrel_hi or %pcrel_lo. With symbol_references, the instructions that refer to data
+// or undefined labels and the address operands get relocations instead of absolute addresses,
+// otherwise the address operands are for code loaded at base. None of them are ever compressed.
+pub fn assemble(program: &[String], code_references: &HashSet<usize>, address_operands: &HashMap<usize, AddressOperand>, symbol_references: Option<&HashMap<usize, (String, i64)>>, base: u64, compressed: bool) -> Result<MachineCode, (usize, String)> {
+    let symbol = |i: usize| symbol_references.and_then(|references| references.get(&i));
+    let encode = |i: usize, pc: u64, address_of: &dyn Fn(usize) -> Option<u64>| if symbol(i).is_some() {
+        encode_symbol_reference(&program[i])
+    } else if code_references.contains(&i) {
+        encode_code_address(&program[i], pc, address_of)
+    } else {
+        encode(&program[i], pc, address_of)
+    };
+    let compress = |i: usize, word: u32| if compressed && !code_references.contains(&i) && !address_operands.contains_key(&i) && symbol(i).is_none() { compress(word) } else { None };
+    // Only jumps and branches depend on the layout and they always take 4 bytes, so the sizes can
+    // be found by encoding everything with placeholder targets first. The placeholder is the
+    // instruction itself, an offset of 0 is in range for every jump and branch.
+    let mut addresses = Vec::with_capacity(program.len() + 1);
+    let mut address = 0;
+    for i in 0..program.len() {
+        addresses.push(address);
+        let placeholder = |_: usize| Some(address);
+        let words = encode(i, address, &placeholder).map_err(|err| (i, err))?;
+        for word in words {
+            address += if compress(i, word).is_some() { 2 } else { 4 };
+        }
+    }
+    addresses.push(address);
+
+    let address_of = |index: usize| addresses.get(index).copied();
+    let mut code = Vec::with_capacity(address as usize);
+    let mut relocations = Vec::new();
+    for (i, &address) in addresses[..program.len()].iter().enumerate() {
+        if let Some((symbol, addend)) = symbol(i) {
+            relocations.extend(symbol_relocations(&program[i]).into_iter().map(|(offset, kind)| Relocation { offset: address + offset, kind, symbol: symbol.clone(), addend: *addend, auipc: address }));
+        }
+        let mut words = encode(i, address, &address_of).map_err(|err| (i, err))?;
+        if let Some(operand) = address_operands.get(&i) {
+            let auipc = base + addresses[operand.auipc];
+            let immediate = if symbol_references.is_some() {
+                relocations.push(Relocation { offset: address, kind: operand.kind, symbol: operand.symbol.clone(), addend: operand.addend, auipc: addresses[operand.auipc] });
+                0
+            } else {
+                let target = if operand.code { (base + addresses[operand.value as usize]) as i64 } else { operand.value } + operand.addend;
+                operand.kind.immediate(if operand.kind.is_pcrel() { target - auipc as i64 } else { target })
+            };
+            words[0] = operand.kind.patch(words[0], immediate);
+        }
+        for word in words {
+            match compress(i, word) {
+                Some(half) => code.extend_from_slice(&half.to_le_bytes()),
+                None => code.extend_from_slice(&word.to_le_bytes())
+            }
+        }
+    }
+    Ok(MachineCode { code, addresses, relocations })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+
+    fn assemble_program(program: &[&str], compressed: bool) -> MachineCode {
+        let program: Vec<String> = program.iter().map(|line| line.to_string()).collect();
+        assemble(&program, &HashSet::new(), &HashMap::new(), None, 0, compressed).unwrap()
+    }
+
+    fn word_at(machine_code: &MachineCode, index: usize) -> u32 {
+        let address = machine_code.addresses[index] as usize;
+        u32::from_le_bytes(machine_code.code[address..address + 4].try_into().unwrap())
+    }
+
+    // A short branch more than 4 KiB past the start of the code.
+    #[test]
+    fn branches_far_from_the_start() {
+        let mut program = vec!["li a0, 3"];
+        program.extend(std::iter::repeat("addi a1, a1, 1").take(2100));
+        program.push("bnez a0, 2100");
+        program.push("j 0");
+        for compressed in [false, true] {
+            let machine_code = assemble_program(&program, compressed);
+            let offset = machine_code.addresses[2100] as i64 - machine_code.addresses[2101] as i64;
+            assert!(machine_code.addresses[2101] > 4096);
+            assert_eq!(word_at(&machine_code, 2101), b_type(1, 10, ZERO, offset).unwrap());
+            let back = -(machine_code.addresses[2102] as i64);
+            assert_eq!(word_at(&machine_code, 2102), j_type(ZERO, back).unwrap());
+        }
+    }
+    // Encodings from llvm-mc.
+    #[test]
+    fn loads_immediates() {
+        assert_eq!(load_immediate(10, 0x7ff), [0x7ff00513]);
+        assert_eq!(load_immediate(10, 0xffffffff), [0xfff00513, 0x02055513]);
+        assert_eq!(load_immediate(10, 0x123456789), [0x00092537, 0xa2b5051b, 0x00d51513, 0x78950513]);
+        assert_eq!(load_immediate(10, 0xffff0000ffff), [0xf0001537, 0x01451513, 0xfff50513, 0x01055513]);
+        assert_eq!(load_immediate(10, 0x3fffffff80000000), [0x80000537, 0xfff5051b, 0x01f51513]);
+    }
+    // Encode every instruction, decode it and encode the decoded text again.
+    #[test]
+    fn decodes_what_it_encodes() {
+        let program = [
+            "add a0, a1, a2", "subw t0, t1, t2", "mul s2, s3, s4", "divu a5, a6, a7", "remw t3, t4, t5",
+            "sltu a0, zero, a1", "addi sp, sp, -2048", "xori a0, a1, 2047", "sltiu t0, t1, 1", "addiw a0, a0, -1",
+            "slli a0, a1, 63", "srai t0, t1, 7", "srliw a2, a3, 31", "sraiw a4, a5, 1",
+            "lb a0, -1(s0)", "lhu a1, 2(sp)", "lwu a2, 4(a3)", "ld ra, 2040(sp)",
+            "sb a0, -2048(s1)", "sh a1, 6(a2)", "sw a3, 0(a4)", "sd s0, 16(sp)",
+            "lui a0, 0xfffff", "auipc t1, 0x12345", "jalr ra, 12(t0)", "jalr zero, 0(ra)",
+            "fence", "fence rw,w", "fence.tso", "fence.i", "ecall", "ebreak"
+        ];
+        for instruction in program {
+            let word = encode(instruction, 0, &|_| None).unwrap()[0];
+            let decoded = crate::decoder::decode(word, 0).unwrap();
+            assert_eq!(decoded.size, 4);
+            assert_eq!(encode(&decoded.text, 0, &|_| None).unwrap(), [word], "{} decoded as {}", instruction, decoded.text);
+        }
+    }
+
+    // Compressed encodings from llvm-mc, which expand to instructions that compress the same way.
+    #[test]
+    fn compresses_like_llvm() {
+        let program = [
+            ("nop", 0x0001),
+            ("li a0, -5", 0x556d),
+            ("mv a1, s0", 0x85a2),
+            ("addi a5, a5, 31", 0x07fd),
+            ("addi sp, sp, -64", 0x7139),
+            ("addi s1, sp, 16", 0x0804),
+            ("addiw a0, a0, -1", 0x357d),
+            ("lui a2, 31", 0x667d),
+            ("slli t0, t0, 3", 0x028e),
+            ("srli s0, s0, 12", 0x8031),
+            ("srai a4, a4, 63", 0x977d),
+            ("andi a3, a3, -8", 0x9ae1),
+            ("add a0, a0, t1", 0x951a),
+            ("sub s0, s0, a5", 0x8c1d),
+            ("xor a1, a1, a2", 0x8db1),
+            ("or a3, a3, a4", 0x8ed9),
+            ("and a5, a5, s1", 0x8fe5),
+            ("subw a0, a0, a1", 0x9d0d),
+            ("addw s1, s1, s0", 0x9ca1),
+            ("ld ra, 24(sp)", 0x60e2),
+            ("lw t2, 4(sp)", 0x4392),
+            ("sd s0, 8(sp)", 0xe422),
+            ("sw a0, 252(sp)", 0xdfaa),
+            ("ld a0, 248(s1)", 0x7ce8),
+            ("sd a1, 0(a2)", 0xe20c),
+            ("lw a3, 124(a4)", 0x5f74),
+            ("sw a5, 8(s0)", 0xc41c),
+            ("jr a0", 0x8502),
+            ("jalr ra, 0(t0)", 0x9282),
+            ("ebreak", 0x9002),
+        ];
+        for (instruction, half) in program {
+            let word = encode(instruction, 0, &|_| None).unwrap()[0];
+            assert_eq!(compress(word), Some(half), "{}", instruction);
+            // c.mv expands to add, not to the addi of mv, so compare the compressed forms.
+            assert_eq!(compress(crate::decoder::expand_compressed(half).unwrap()), Some(half), "{}", instruction);
+        }
+        for instruction in ["addi a0, a1, 32", "lui sp, 1", "ld a0, 4(sp)", "sw a0, 128(s0)", "jalr t0, 0(a0)", "add a0, a1, a2"] {
+            assert_eq!(compress(encode(instruction, 0, &|_| None).unwrap()[0]), None, "{}", instruction);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+
+use crate::line_table::LineTable;
+
+// A branch direction predictor, asked before every conditional branch and told the outcome after.
+pub trait Predictor {
+    fn name(&self) -> String;
+    fn predict(&self, pc: usize, target: usize) -> bool;
+    fn update(&mut self, pc: usize, target: usize, taken: bool);
+}
+
+// Always predicts that the branch falls through.
+pub struct NotTaken;
+
+impl Predictor for NotTaken {
+    fn name(&self) -> String {
+        "not-taken".to_owned()
+    }
+
+    fn predict(&self, _pc: usize, _target: usize) -> bool {
+        false
+    }
+
+    fn update(&mut self, _pc: usize, _target: usize, _taken: bool) {}
+}
+
+// Backward taken, forward not taken: loops are taken, if statements are not.
+pub struct Btfn;
+
+impl Predictor for Btfn {
+    fn name(&self) -> String {
+        "btfn".to_owned()
+    }
+
+    fn predict(&self, pc: usize, target: usize) -> bool {
+        target <= pc
+    }
+
+    fn update(&mut self, _pc: usize, _target: usize, _taken: bool) {}
+}
+
+// Remembers the last outcome of the branches sharing a table entry.
+pub struct OneBit {
+    table: Vec<bool>
+}
+
+impl OneBit {
+    pub fn new(index_bits: u32) -> Self {
+        OneBit { table: vec![false; 1 << index_bits] }
+    }
+}
+
+impl Predictor for OneBit {
+    fn name(&self) -> String {
+        "1-bit".to_owned()
+    }
+
+    fn predict(&self, pc: usize, _target: usize) -> bool {
+        self.table[pc % self.table.len()]
+    }
+
+    fn update(&mut self, pc: usize, _target: usize, taken: bool) {
+        let length = self.table.len();
+        self.table[pc % length] = taken;
+    }
+}
+
+// A table of 2-bit saturating counters, 0 and 1 predict not taken, 2 and 3 taken.
+struct CounterTable {
+    counters: Vec<u8>
+}
+
+impl CounterTable {
+    fn new(index_bits: u32) -> Self {
+        // Start weakly not taken.
+        CounterTable { counters: vec![1; 1 << index_bits] }
+    }
+
+    fn predict(&self, index: usize) -> bool {
+        self.counters[index % self.counters.len()] >= 2
+    }
+
+    fn update(&mut self, index: usize, taken: bool) {
+        let length = self.counters.len();
+        let counter = &mut self.counters[index % length];
+        *counter = if taken { (*counter + 1).min(3) } else { counter.saturating_sub(1) };
+    }
+}
+
+// 2-bit saturating counters indexed by the branch address (bimodal).
+pub struct TwoBit {
+    counters: CounterTable
+}
+
+impl TwoBit {
+    pub fn new(index_bits: u32) -> Self {
+        TwoBit { counters: CounterTable::new(index_bits) }
+    }
+}
+
+impl Predictor for TwoBit {
+    fn name(&self) -> String {
+        "2-bit".to_owned()
+    }
+
+    fn predict(&self, pc: usize, _target: usize) -> bool {
+        self.counters.predict(pc)
+    }
+
+    fn update(&mut self, pc: usize, _target: usize, taken: bool) {
+        self.counters.update(pc, taken);
+    }
+}
+
+// 2-bit counters indexed by the branch address xor the outcomes of the most recent branches.
+pub struct Gshare {
+    counters: CounterTable,
+    history: usize,
+    history_mask: usize
+}
+
+impl Gshare {
+    pub fn new(index_bits: u32) -> Self {
+        Gshare { counters: CounterTable::new(index_bits), history: 0, history_mask: (1 << index_bits) - 1 }
+    }
+
+    fn index(&self, pc: usize) -> usize {
+        pc ^ self.history
+    }
+}
+
+impl Predictor for Gshare {
+    fn name(&self) -> String {
+        "gshare".to_owned()
+    }
+
+    fn predict(&self, pc: usize, _target: usize) -> bool {
+        self.counters.predict(self.index(pc))
+    }
+
+    fn update(&mut self, pc: usize, _target: usize, taken: bool) {
+        self.counters.update(self.index(pc), taken);
+        self.history = ((self.history << 1) | taken as usize) & self.history_mask;
+    }
+}
+
+// Chooses per branch between a bimodal and a gshare predictor, depending on which was right more often.
+pub struct Tournament {
+    bimodal: TwoBit,
+    gshare: Gshare,
+    // 0 and 1 prefer the bimodal predictor, 2 and 3 gshare.
+    chooser: CounterTable
+}
+
+impl Tournament {
+    pub fn new(index_bits: u32) -> Self {
+        Tournament { bimodal: TwoBit::new(index_bits), gshare: Gshare::new(index_bits), chooser: CounterTable::new(index_bits) }
+    }
+}
+
+impl Predictor for Tournament {
+    fn name(&self) -> String {
+        "tournament".to_owned()
+    }
+
+    fn predict(&self, pc: usize, target: usize) -> bool {
+        if self.chooser.predict(pc) {
+            self.gshare.predict(pc, target)
+        } else {
+            self.bimodal.predict(pc, target)
+        }
+    }
+
+    fn update(&mut self, pc: usize, target: usize, taken: bool) {
+        let bimodal_correct = self.bimodal.predict(pc, target) == taken;
+        let gshare_correct = self.gshare.predict(pc, target) == taken;
+        if bimodal_correct != gshare_correct {
+            self.chooser.update(pc, gshare_correct);
+        }
+        self.bimodal.update(pc, target, taken);
+        self.gshare.update(pc, target, taken);
+    }
+}
+
+pub const PREDICTOR_NAMES: [&str; 6] = ["not-taken", "btfn", "1-bit", "2-bit", "gshare", "tournament"];
+
+// Create a predictor by name, the dynamic ones use tables with 2^index_bits entries.
+pub fn create(name: &str, index_bits: u32) -> Option<Box<dyn Predictor>> {
+    match name {
+        "not-taken" | "static" => Some(Box::new(NotTaken)),
+        "btfn" => Some(Box::new(Btfn)),
+        "1-bit" => Some(Box::new(OneBit::new(index_bits))),
+        "2-bit" | "bimodal" => Some(Box::new(TwoBit::new(index_bits))),
+        "gshare" => Some(Box::new(Gshare::new(index_bits))),
+        "tournament" => Some(Box::new(Tournament::new(index_bits))),
+        _ => None
+    }
+}
+
+#[derive(Default)]
+struct BranchRecord {
+    executed: u64,
+    taken: u64,
+    // Mispredictions of every predictor, in the order of the predictors.
+    mispredictions: Vec<u64>
+}
+
+// Runs several predictors side by side on the same branches.
+pub struct BranchSimulator {
+    predictors: Vec<Box<dyn Predictor>>,
+    branches: BTreeMap<usize, BranchRecord>
+}
+
+impl BranchSimulator {
+    pub fn new(predictors: Vec<Box<dyn Predictor>>) -> Self {
+        BranchSimulator { predictors, branches: BTreeMap::new() }
+    }
+
+    // Record a conditional branch at pc that jumps to target when taken.
+    pub fn record(&mut self, pc: usize, target: usize, taken: bool) {
+        let count = self.predictors.len();
+        let record = self.branches.entry(pc).or_insert_with(|| BranchRecord { mispredictions: vec![0; count], ..Default::default() });
+        record.executed += 1;
+        record.taken += taken as u64;
+        for (i, predictor) in self.predictors.iter_mut().enumerate() {
+            if predictor.predict(pc, target) != taken {
+                record.mispredictions[i] += 1;
+            }
+            predictor.update(pc, target, taken);
+        }
+    }
+
+    pub fn print_report(&self, line_table: &LineTable) {
+        let executed: u64 = self.branches.values().map(|record| record.executed).sum();
+        println!("{} branches executed, {} taken", executed, self.branches.values().map(|record| record.taken).sum::<u64>());
+        println!("{:>12} {:>14} {:>10}", "predictor", "mispredictions", "accuracy");
+        for (i, predictor) in self.predictors.iter().enumerate() {
+            let mispredictions: u64 = self.branches.values().map(|record| record.mispredictions[i]).sum();
+            let accuracy = if executed == 0 { 100.0 } else { (executed - mispredictions) as f64 * 100.0 / executed as f64 };
+            println!("{:>12} {:>14} {:>9.2}%", predictor.name(), mispredictions, accuracy);
+        }
+
+        println!();
+        print!("{:>10} {:>10}", "executed", "taken");
+        for predictor in &self.predictors {
+            print!(" {:>10}", predictor.name());
+        }
+        println!("  branch");
+        for (&pc, record) in &self.branches {
+            print!("{:>10} {:>10}", record.executed, record.taken);
+            for mispredictions in &record.mispredictions {
+                print!(" {:>10}", mispredictions);
+            }
+            match line_table.get(pc) {
+                Some(info) => println!("  {}", info),
+                None => println!("  {}", pc)
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+// C library functions the interpreter provides when a program calls them without defining them,
+// enabled with --builtins. Arguments are passed in a0 - a7 and the result is returned in a0 like
+// in the RISC-V calling convention.
+use std::io::Write;
+
+use crate::evaluator::Evaluator;
+
+pub const NAMES: [&str; 13] = ["putchar", "puts", "printf", "strlen", "strcmp", "memcpy", "memset", "malloc", "calloc", "free", "abs", "atoi", "exit"];
+
+const ARGUMENTS: [&str; 8] = ["a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7"];
+
+pub fn is_builtin(name: &str) -> bool {
+    NAMES.contains(&name)
+}
+
+// The bytes of the zero terminated string at address, without the zero.
+fn read_string(evaluator: &Evaluator, address: i64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut address = address as usize;
+    while evaluator.memory[address] != 0 {
+        bytes.push(evaluator.memory[address]);
+        address += 1;
+    }
+    bytes
+}
+
+fn write_output(evaluator: &mut Evaluator, bytes: &[u8]) -> Result<(), String> {
+    evaluator.output.write_all(bytes).and_then(|_| evaluator.output.flush()).map_err(|err| err.to_string())
+}
+
+// Reserve size bytes on the heap by moving the program break, like brk does. Allocations are
+// never freed and start after address 0 so they are not null.
+fn allocate(evaluator: &mut Evaluator, size: i64) -> i64 {
+    let memory = &mut evaluator.memory;
+    let start = (memory.program_break.max(8) + 7) & !7;
+    let end = start + size.max(0) as usize;
+    if end > memory.virtual_memory_size - memory.stack_memory.len() {
+        return 0;
+    }
+    memory.program_break = end;
+    memory.heap_memory.resize(end, 0);
+    start as i64
+}
+
+// printf's format with the arguments after the format string: %d, %i, %u, %x, %X, %o, %c, %s,
+// %p and %% with the -, 0 and + flags, a width and the length modifiers l, ll, h and z.
+fn format(evaluator: &Evaluator, format: &[u8]) -> Result<Vec<u8>, String> {
+    let mut output = Vec::new();
+    let mut arguments = ARGUMENTS[1..].iter().map(|register| evaluator.registers[*register]);
+    let mut bytes = format.iter().copied().peekable();
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            output.push(byte);
+            continue;
+        }
+        let (mut left, mut zeros, mut plus) = (false, false, false);
+        while let Some(flag) = bytes.next_if(|byte| matches!(byte, b'-' | b'0' | b'+')) {
+            match flag {
+                b'-' => left = true,
+                b'0' => zeros = true,
+                _ => plus = true
+            }
+        }
+        let mut width = 0;
+        while let Some(digit) = bytes.next_if(u8::is_ascii_digit) {
+            width = width * 10 + (digit - b'0') as usize;
+        }
+        let mut long = false;
+        while let Some(modifier) = bytes.next_if(|byte| matches!(byte, b'l' | b'h' | b'z')) {
+            long |= modifier != b'h';
+        }
+        let conversion = bytes.next().ok_or("printf: the format ends with %")?;
+        if conversion == b'%' {
+            output.push(b'%');
+            continue;
+        }
+        let argument = arguments.next().ok_or("printf: only 7 arguments after the format are supported")?;
+        // Without l, integers are 32 bits.
+        let (signed, unsigned) = if long { (argument, argument as u64) } else { (argument as i32 as i64, argument as u32 as u64) };
+        let text = match conversion {
+            b'd' | b'i' if plus && signed >= 0 => format!("+{}", signed).into_bytes(),
+            b'd' | b'i' => signed.to_string().into_bytes(),
+            b'u' => unsigned.to_string().into_bytes(),
+            b'x' => format!("{:x}", unsigned).into_bytes(),
+            b'X' => format!("{:X}", unsigned).into_bytes(),
+            b'o' => format!("{:o}", unsigned).into_bytes(),
+            b'p' => format!("{:#x}", argument).into_bytes(),
+            b'c' => vec![argument as u8],
+            b's' => read_string(evaluator, argument),
+            other => return Err(format!("printf: unsupported conversion %{}", other as char))
+        };
+        let padding = width.saturating_sub(text.len());
+        if left {
+            output.extend(&text);
+            output.resize(output.len() + padding, b' ');
+        } else if zeros && !matches!(conversion, b's' | b'c') {
+            // The zeros go after the sign.
+            let sign = text.first().filter(|byte| matches!(byte, b'-' | b'+')).map_or(0, |_| 1);
+            output.extend(&text[..sign]);
+            output.resize(output.len() + padding, b'0');
+            output.extend(&text[sign..]);
+        } else {
+            output.resize(output.len() + padding, b' ');
+            output.extend(&text);
+        }
+    }
+    Ok(output)
+}
+
+// Run the builtin name as if it was called, leaving the result in a0.
+pub fn call(evaluator: &mut Evaluator, name: &str) -> Result<(), String> {
+    let [a0, a1, a2] = [evaluator.registers["a0"], evaluator.registers["a1"], evaluator.registers["a2"]];
+    let result = match name {
+        "putchar" => {
+            write_output(evaluator, &[a0 as u8])?;
+            a0 & 0xff
+        },
+        "puts" => {
+            let mut line = read_string(evaluator, a0);
+            line.push(b'\n');
+            write_output(evaluator, &line)?;
+            line.len() as i64
+        },
+        "printf" => {
+            let text = format(evaluator, &read_string(evaluator, a0))?;
+            write_output(evaluator, &text)?;
+            text.len() as i64
+        },
+        "strlen" => read_string(evaluator, a0).len() as i64,
+        "strcmp" => {
+            let (a, b) = (read_string(evaluator, a0), read_string(evaluator, a1));
+            match a.iter().zip(&b).find(|(a, b)| a != b) {
+                Some((a, b)) => *a as i64 - *b as i64,
+                None => a.len() as i64 - b.len() as i64
+            }
+        },
+        "memcpy" => {
+            for i in 0..a2 {
+                let byte = evaluator.memory[(a1 + i) as usize];
+                evaluator.memory[(a0 + i) as usize] = byte;
+            }
+            a0
+        },
+        "memset" => {
+            for i in 0..a2 {
+                evaluator.memory[(a0 + i) as usize] = a1 as u8;
+            }
+            a0
+        },
+        "malloc" => allocate(evaluator, a0),
+        "calloc" => allocate(evaluator, a0.wrapping_mul(a1)),
+        "free" => a0,
+        "abs" => (a0 as i32).wrapping_abs() as i64,
+        "atoi" => {
+            let text = String::from_utf8_lossy(&read_string(evaluator, a0)).into_owned();
+            let text = text.trim_start();
+            let end = text.char_indices().find(|&(i, char)| !(char.is_ascii_digit() || (i == 0 && (char == '-' || char == '+')))).map_or(text.len(), |(i, _)| i);
+            text[..end].parse::<i32>().unwrap_or(0) as i64
+        },
+        "exit" => {
+            evaluator.exit_code = Some(a0);
+            a0
+        },
+        _ => return Err(format!("\"{}\" is not defined and not a builtin", name))
+    };
+    evaluator.registers["a0"] = result;
+    Ok(())
+}
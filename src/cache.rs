@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+
+use crate::memory::{AccessKind, MemoryAccess};
+
+// When instructions live in the program list and not in memory, fetches are modelled as if every
+// instruction was 4 bytes long, starting at address 0 of a separate instruction address space.
+pub const INSTRUCTION_SIZE: u64 = 4;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Replacement {
+    Lru,
+    Fifo,
+    Random
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WritePolicy {
+    // Stores only update the cache, dirty lines are written to the next level when evicted.
+    WriteBack,
+    // Stores are always passed to the next level, a store miss does not allocate a line.
+    WriteThrough
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CacheConfig {
+    pub size: usize,
+    pub associativity: usize,
+    pub line_size: usize,
+    pub replacement: Replacement,
+    pub write_policy: WritePolicy
+}
+
+impl CacheConfig {
+    pub fn new(size: usize, associativity: usize, line_size: usize) -> Self {
+        CacheConfig { size, associativity, line_size, replacement: Replacement::Lru, write_policy: WritePolicy::WriteBack }
+    }
+
+    // Parse "size:associativity:line size[:replacement[:write policy]]", e.g. "1k:2:16:lru:wb".
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = spec.split(':').collect();
+        if parts.len() < 3 || parts.len() > 5 {
+            return Err(format!("expected \"size:associativity:line size[:replacement[:write policy]]\", got \"{}\"", spec));
+        }
+        let number = |part: &str, what: &str| {
+            let (digits, factor) = match part.to_lowercase().strip_suffix('k') {
+                Some(digits) => (digits.to_owned(), 1024),
+                None => (part.to_owned(), 1)
+            };
+            match digits.parse::<usize>() {
+                Ok(value) if value > 0 => Ok(value * factor),
+                _ => Err(format!("invalid {} \"{}\"", what, part))
+            }
+        };
+        let mut config = CacheConfig::new(number(parts[0], "size")?, number(parts[1], "associativity")?, number(parts[2], "line size")?);
+        if let Some(replacement) = parts.get(3) {
+            config.replacement = match *replacement {
+                "lru" => Replacement::Lru,
+                "fifo" => Replacement::Fifo,
+                "random" => Replacement::Random,
+                _ => return Err(format!("unknown replacement policy \"{}\", expected lru, fifo or random", replacement))
+            };
+        }
+        if let Some(write_policy) = parts.get(4) {
+            config.write_policy = match *write_policy {
+                "wb" | "write-back" => WritePolicy::WriteBack,
+                "wt" | "write-through" => WritePolicy::WriteThrough,
+                _ => return Err(format!("unknown write policy \"{}\", expected wb or wt", write_policy))
+            };
+        }
+        if !config.line_size.is_power_of_two() {
+            return Err(format!("the line size {} is not a power of two", config.line_size));
+        }
+        if config.size % (config.associativity * config.line_size) != 0 {
+            return Err(format!("a size of {} bytes can not be split into sets of {} lines of {} bytes", config.size, config.associativity, config.line_size));
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct CacheStats {
+    pub read_hits: u64,
+    pub read_misses: u64,
+    pub write_hits: u64,
+    pub write_misses: u64,
+    pub writebacks: u64
+}
+
+impl CacheStats {
+    pub fn accesses(&self) -> u64 {
+        self.hits() + self.misses()
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.read_hits + self.write_hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.read_misses + self.write_misses
+    }
+
+    pub fn miss_rate(&self) -> f64 {
+        if self.accesses() == 0 { 0.0 } else { self.misses() as f64 * 100.0 / self.accesses() as f64 }
+    }
+
+    fn count(&mut self, write: bool, hit: bool) {
+        match (write, hit) {
+            (false, true) => self.read_hits += 1,
+            (false, false) => self.read_misses += 1,
+            (true, true) => self.write_hits += 1,
+            (true, false) => self.write_misses += 1
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: u64,
+    last_used: u64,
+    inserted: u64
+}
+
+// Result of one access to a single cache.
+struct Outcome {
+    hit: bool,
+    // Accesses (line address, write) that have to be passed to the next level.
+    next_level: Vec<(u64, bool)>
+}
+
+pub struct Cache {
+    pub name: &'static str,
+    pub config: CacheConfig,
+    pub stats: CacheStats,
+    sets: Vec<Vec<Line>>,
+    time: u64,
+    random_state: u64
+}
+
+impl Cache {
+    pub fn new(name: &'static str, config: CacheConfig) -> Self {
+        let set_count = config.size / (config.associativity * config.line_size);
+        Cache {
+            name,
+            config,
+            stats: CacheStats::default(),
+            sets: vec![vec![Line::default(); config.associativity]; set_count],
+            time: 0,
+            random_state: 0x2545f4914f6cdd1d
+        }
+    }
+
+    fn victim(&mut self, set: usize) -> usize {
+        let lines = &self.sets[set];
+        if let Some(free) = lines.iter().position(|line| !line.valid) {
+            return free;
+        }
+        match self.config.replacement {
+            Replacement::Lru => (0..lines.len()).min_by_key(|&way| lines[way].last_used).unwrap(),
+            Replacement::Fifo => (0..lines.len()).min_by_key(|&way| lines[way].inserted).unwrap(),
+            Replacement::Random => {
+                // xorshift, so runs are reproducible
+                self.random_state ^= self.random_state << 13;
+                self.random_state ^= self.random_state >> 7;
+                self.random_state ^= self.random_state << 17;
+                (self.random_state % lines.len() as u64) as usize
+            }
+        }
+    }
+
+    // Access the line containing address.
+    fn access(&mut self, address: u64, write: bool) -> Outcome {
+        self.time += 1;
+        let line_size = self.config.line_size as u64;
+        let line_number = address / line_size;
+        let set = (line_number % self.sets.len() as u64) as usize;
+        let tag = line_number / self.sets.len() as u64;
+        let write_back = self.config.write_policy == WritePolicy::WriteBack;
+        let mut next_level = Vec::new();
+
+        let hit_way = self.sets[set].iter().position(|line| line.valid && line.tag == tag);
+        let hit = hit_way.is_some();
+        self.stats.count(write, hit);
+
+        let way = match hit_way {
+            Some(way) => Some(way),
+            // Without write allocation a store miss goes straight to the next level.
+            None if write && !write_back => None,
+            None => {
+                let way = self.victim(set);
+                let victim = self.sets[set][way];
+                if victim.valid && victim.dirty {
+                    self.stats.writebacks += 1;
+                    next_level.push(((victim.tag * self.sets.len() as u64 + set as u64) * line_size, true));
+                }
+                next_level.push((line_number * line_size, false));
+                self.sets[set][way] = Line { valid: true, dirty: false, tag, last_used: 0, inserted: self.time };
+                Some(way)
+            }
+        };
+        if let Some(way) = way {
+            let line = &mut self.sets[set][way];
+            line.last_used = self.time;
+            line.dirty |= write && write_back;
+        }
+        if write && !write_back {
+            next_level.push((address, true));
+        }
+        Outcome { hit, next_level }
+    }
+}
+
+// Extra cycles for a line read that misses the first level and is found in L2, or goes to memory.
+// Write-backs and write-through stores are assumed to be absorbed by a write buffer.
+pub const L2_LATENCY: u64 = 10;
+pub const MEMORY_LATENCY: u64 = 100;
+
+const L1I: usize = 0;
+const L1D: usize = 1;
+const L2: usize = 2;
+
+// Split instruction and data caches, optionally backed by a shared second level cache.
+pub struct CacheHierarchy {
+    // L1I, L1D and L2 if configured.
+    caches: Vec<Cache>,
+    pub memory_reads: u64,
+    pub memory_writes: u64,
+    // Hits and misses of every cache caused by the instructions of each function.
+    functions: HashMap<String, Vec<CacheStats>>
+}
+
+impl CacheHierarchy {
+    pub fn new(l1i: CacheConfig, l1d: CacheConfig, l2: Option<CacheConfig>) -> Self {
+        let mut caches = vec![Cache::new("L1I", l1i), Cache::new("L1D", l1d)];
+        if let Some(l2) = l2 {
+            caches.push(Cache::new("L2", l2));
+        }
+        CacheHierarchy { caches, memory_reads: 0, memory_writes: 0, functions: HashMap::new() }
+    }
+
+    // Access a cache and the levels below it, returns the cycles spent waiting for missing lines.
+    fn access(&mut self, level: usize, address: u64, size: u64, write: bool, function: &str) -> u64 {
+        let mut latency = 0;
+        let line_size = self.caches[level].config.line_size as u64;
+        let mut line = address / line_size * line_size;
+        // Unaligned accesses can touch more than one line.
+        while line < address + size.max(1) {
+            let outcome = self.caches[level].access(line.max(address), write);
+            let stats = self.functions.entry(function.to_owned()).or_insert_with(|| vec![CacheStats::default(); 3]);
+            stats[level].count(write, outcome.hit);
+
+            for (next_address, next_write) in outcome.next_level {
+                // Writes of a whole dirty line or a write through store, reads always fetch a whole line.
+                let next_size = if next_write && self.caches[level].config.write_policy == WritePolicy::WriteThrough { size } else { line_size };
+                if level != L2 && self.caches.len() > L2 {
+                    let l2_latency = self.access(L2, next_address, next_size, next_write, function);
+                    if !next_write {
+                        latency += L2_LATENCY + l2_latency;
+                    }
+                } else if next_write {
+                    self.memory_writes += 1;
+                } else {
+                    self.memory_reads += 1;
+                    latency += MEMORY_LATENCY;
+                }
+            }
+            line += line_size;
+        }
+        latency
+    }
+
+    pub fn fetch(&mut self, address: u64, size: u64, function: &str) -> u64 {
+        self.access(L1I, address, size, false, function)
+    }
+
+    pub fn data(&mut self, access: &MemoryAccess, function: &str) -> u64 {
+        self.access(L1D, access.address as u64, access.size as u64, access.kind == AccessKind::Write, function)
+    }
+
+    pub fn print_report(&self) {
+        println!("{:>5} {:>24} {:>12} {:>12} {:>12} {:>9} {:>12}", "cache", "configuration", "accesses", "hits", "misses", "miss %", "writebacks");
+        for cache in &self.caches {
+            let config = &cache.config;
+            let configuration = format!("{}B {}-way {}B {} {}", config.size, config.associativity, config.line_size, format!("{:?}", config.replacement).to_uppercase(),
+                if config.write_policy == WritePolicy::WriteBack { "WB" } else { "WT" });
+            let stats = &cache.stats;
+            println!("{:>5} {:>24} {:>12} {:>12} {:>12} {:>8.2}% {:>12}",
+                cache.name, configuration, stats.accesses(), stats.hits(), stats.misses(), stats.miss_rate(), stats.writebacks);
+        }
+        println!("Memory: {} line reads, {} writes", self.memory_reads, self.memory_writes);
+
+        let mut functions: Vec<(&String, &Vec<CacheStats>)> = self.functions.iter().collect();
+        functions.sort_by(|a, b| b.1[L1D].misses().cmp(&a.1[L1D].misses()).then(a.0.cmp(b.0)));
+        println!();
+        for cache in &self.caches {
+            print!("{:>12} {:>8}", format!("{} misses", cache.name), "miss %");
+        }
+        println!("  function");
+        for (name, stats) in functions {
+            for (level, _) in self.caches.iter().enumerate() {
+                print!("{:>12} {:>7.2}%", stats[level].misses(), stats[level].miss_rate());
+            }
+            println!("  {}", name);
+        }
+    }
+}
@@ -1,133 +1,734 @@
-use std::collections::HashMap;
-use std::fs;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::{fmt, fs};
+use std::ops::Range;
+use std::path::Path;
 
-use crate::memory::Memory;
+use crate::assembler::{AddressOperand, RelocationKind};
+use crate::builtins;
+use crate::data::{self, DataReference};
+use crate::expression;
+use crate::macros;
+use crate::line_table::{LineInfo, LineTable, SourcePosition};
+use crate::memory::{Memory, Segment};
 
-fn compile(content: &str, memory: &mut Memory, verbose: bool, program: &mut Vec<String>, data_segment_size: &mut usize, jump_tag_map: &mut HashMap<String, usize>) -> i64 {
+// The sections data is placed in with their addresses, SECTION_SIZE apart so each can grow to 1 MiB.
+// Other sections are added to .data if they are writable and to .rodata if not.
+pub const DATA_SECTIONS: [(&str, usize); 3] = [(".rodata", 0x100000), (".data", 0x200000), (".bss", 0x300000)];
+pub const SECTION_SIZE: usize = 0x100000;
+const RODATA: usize = 0;
+const DATA: usize = 1;
+const BSS: usize = 2;
+
+// The data of one of DATA_SECTIONS from all files.
+#[derive(Clone, Debug)]
+pub struct DataSection {
+    pub name: &'static str,
+    pub address: usize,
+    // Zeros for .bss, which images and ELF files only store the size of.
+    pub data: Vec<u8>,
+    pub align: usize
+}
+
+impl DataSection {
+    pub fn is_writable(&self) -> bool {
+        self.name != ".rodata"
+    }
+
+    // Whether address is in the section or right after its end, where labels at the end point.
+    pub fn contains(&self, address: usize) -> bool {
+        address >= self.address && address - self.address <= self.data.len()
+    }
+
+    fn align_to(&mut self, align: usize, fill: u8) {
+        self.align = self.align.max(align);
+        self.data.resize(self.data.len().next_multiple_of(align), fill);
+    }
+}
+
+// An error when a section of size bytes would run into the next one.
+pub fn check_section_size(name: &str, size: usize) -> Result<(), String> {
+    if size > SECTION_SIZE {
+        Err(format!("{} has {} bytes, more than the {} a section can hold", name, size, SECTION_SIZE))
+    } else {
+        Ok(())
+    }
+}
+
+// The data section that the contents of a section with these .section flags go into, None for code.
+fn data_section(name: &str, flags: &str) -> Option<usize> {
+    if name.starts_with(".text") {
+        None
+    } else if name.starts_with(".bss") || name.starts_with(".sbss") || name.starts_with(".tbss") {
+        Some(BSS)
+    } else if name.starts_with(".data") || name.starts_with(".sdata") || name.starts_with(".tdata") || flags.contains('w') {
+        Some(DATA)
+    } else {
+        Some(RODATA)
+    }
+}
+
+// Everything that is accumulated while compiling one or more files.
+#[derive(Default)]
+pub struct Assembly {
+    pub program: Vec<String>,
+    pub line_table: LineTable,
+    pub entry_point: i64,
+    // Bytes of data at the top of the stack, only in images from before data had sections.
+    pub data_segment_size: usize,
+    pub sections: Vec<DataSection>,
+    // Labels by key: .globl labels by their name, the others by local_key, so that files can use
+    // the same names for their own labels.
+    pub jump_tag_map: HashMap<String, usize>,
+    // The compiled files, in the order of the indices local_key uses.
+    pub files: Vec<String>,
+    // Labels of data, all other labels are program indices.
+    pub data_labels: HashSet<String>,
+    // Values in the data that refer to labels, code labels have their program index as address.
+    pub data_references: Vec<DataReference>,
+    // Program indices of lla instructions that take the address of code instead of data.
+    pub code_references: HashSet<usize>,
+    // Program indices of instructions with a %hi, %lo, %pcrel_hi or %pcrel_lo operand.
+    pub address_operands: HashMap<usize, AddressOperand>,
+    // Program indices of lla instructions that take the address of data and of calls to labels
+    // that are not defined, with the label and the offset from it. Object files refer to them
+    // with relocations.
+    pub symbol_references: HashMap<usize, (String, i64)>,
+    // Labels made visible to the other files and object files with .globl.
+    pub globals: HashSet<String>,
+    // Calls and data that refer to labels no file defines.
+    pub external_references: Vec<ExternalReference>
+}
+
+// A reference to a symbol that is not defined, which object files leave to the linker and the
+// interpreter can only run as a builtin.
+pub struct ExternalReference {
+    pub file: String,
+    pub line: usize,
+    pub symbol: String,
+    pub call: bool
+}
+
+impl fmt::Display for ExternalReference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = if self.call { "function" } else { "symbol" };
+        write!(f, "{}:{}: undefined {} \"{}\"", self.file, self.line, what, self.symbol)
+    }
+}
+
+// The key in jump_tag_map of a label that is not .globl in the file with index file.
+pub fn local_key(file: usize, name: &str) -> String {
+    format!("{}:{}", file, name)
+}
+
+// The name of a label from its key, a label name can not contain a colon.
+pub fn symbol_name(key: &str) -> &str {
+    key.split_once(':').map_or(key, |split| split.1)
+}
+
+// The labels of one file and the data that refers to labels, before linking makes the .globl
+// labels visible to the other files.
+#[derive(Default)]
+struct FileSymbols {
+    index: usize,
+    file: String,
+    // The program indices of the file's instructions.
+    program: Range<usize>,
+    labels: HashMap<String, usize>,
+    data_labels: HashSet<String>,
+    // The line each label is defined on.
+    lines: HashMap<String, usize>,
+    // Labels that are defined more than once, as error messages for linking to report.
+    errors: Vec<String>,
+    globals: HashSet<String>,
+    // With the file and line they are on, which is another file for .include.
+    data_references: Vec<(String, usize, DataReference)>
+}
+
+impl FileSymbols {
+    // The key of a label as seen from this file, its own label or a .globl label of any file.
+    fn key(&self, name: &str, globals: &HashSet<String>) -> Option<String> {
+        if self.labels.contains_key(name) && !self.globals.contains(name) {
+            Some(local_key(self.index, name))
+        } else if globals.contains(name) {
+            Some(name.to_owned())
+        } else {
+            None
+        }
+    }
+}
+
+impl Assembly {
+    // The values of the data references that can be resolved as bytes by address, with code
+    // labels at code_address.
+    pub fn data_reference_values(&self, code_address: &dyn Fn(usize) -> i64) -> Vec<(usize, Vec<u8>)> {
+        let address_of = |label: &str| self.jump_tag_map.get(label).map(|&value| if self.data_labels.contains(label) { value as i64 } else { code_address(value) });
+        self.data_references.iter()
+            .filter_map(|reference| Some((reference.address, reference.bytes(reference.value(&address_of)?))))
+            .collect()
+    }
+
+    // The value of a label by name, the .globl one or else the first file's local one.
+    pub fn find_label(&self, name: &str) -> Option<usize> {
+        self.jump_tag_map.get(name).or_else(|| (0..self.files.len()).find_map(|file| self.jump_tag_map.get(&local_key(file, name)))).copied()
+    }
+
+    // The section and offset that a data address is at.
+    pub fn data_location(&self, address: usize) -> Option<(usize, usize)> {
+        self.sections.iter().position(|section| section.contains(address)).map(|i| (i, address - self.sections[i].address))
+    }
+}
+
+// Where the # comment on a line starts, a # in a string or character literal does not start one.
+pub fn comment_start(line: &str) -> usize {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, char) in line.char_indices() {
+        match (char, quote) {
+            (_, Some(_)) if escaped => escaped = false,
+            ('\\', Some(_)) => escaped = true,
+            (_, Some(open)) if char == open => quote = None,
+            ('"', None) | ('\'', None) => quote = Some(char),
+            ('#', None) => return i,
+            _ => {}
+        }
+    }
+    line.len()
+}
+
+// The first relocation operator, %hi(symbol), %lo(symbol), %pcrel_hi(symbol) or %pcrel_lo(label)
+// with the label on the auipc with the %pcrel_hi, as its start, its end, the operator and the
+// operand.
+fn find_operator(line: &str) -> Option<(usize, usize, &str, &str)> {
+    let start = line.find('%')?;
+    let open = start + line[start..].find('(')?;
+    let close = open + line[open..].find(')')?;
+    Some((start, close + 1, &line[start + 1..open], &line[open + 1..close]))
+}
+
+// The address operand of a relocation operator in the instruction at index, key_of gives the
+// key of a label.
+fn address_operand(assembly: &Assembly, index: usize, operator: &str, operand: &str, key_of: &dyn Fn(&str) -> Option<String>) -> Result<AddressOperand, String> {
+    let store = matches!(assembly.program[index].split(' ').next(), Some("sb" | "sh" | "sw" | "sd"));
+    let kind = match operator {
+        "hi" => RelocationKind::Hi20,
+        "lo" if store => RelocationKind::Lo12S,
+        "lo" => RelocationKind::Lo12I,
+        "pcrel_hi" => RelocationKind::PcrelHi20,
+        "pcrel_lo" => {
+            // Relative to the auipc, for the symbol of its %pcrel_hi.
+            let auipc = key_of(operand.trim()).and_then(|key| assembly.address_operands.get(&assembly.jump_tag_map[&key]))
+                .filter(|hi| hi.kind == RelocationKind::PcrelHi20)
+                .ok_or_else(|| format!("\"{}\" is not the label of an auipc with %pcrel_hi", operand.trim()))?;
+            let kind = if store { RelocationKind::PcrelLo12S } else { RelocationKind::PcrelLo12 };
+            return Ok(AddressOperand { kind, ..auipc.clone() });
+        },
+        _ => return Err(format!("unknown relocation operator %{}", operator))
+    };
+    let reference = data::parse_reference(operand.trim(), 0, 0)?;
+    if reference.minus.is_some() {
+        return Err(format!("%{} can not take the difference of labels", operator));
+    }
+    let key = key_of(&reference.symbol).ok_or_else(|| format!("undefined symbol \"{}\"", reference.symbol))?;
+    Ok(AddressOperand {
+        kind,
+        code: !assembly.data_labels.contains(&key),
+        value: assembly.jump_tag_map[&key] as i64,
+        symbol: key,
+        addend: reference.addend,
+        auipc: index
+    })
+}
+
+// Replace the relocation operators in the instructions in range by their values in the
+// interpreter, where the pc of an auipc is its program index.
+fn resolve_operators(assembly: &mut Assembly, range: Range<usize>, key_of: &dyn Fn(&str) -> Option<String>, errors: &mut Vec<(usize, String)>) {
+    for index in range {
+        while let Some((start, end, operator, operand)) = find_operator(&assembly.program[index]) {
+            let operand = match address_operand(assembly, index, operator, operand, key_of) {
+                Ok(operand) => operand,
+                Err(err) => {
+                    errors.push((index, err));
+                    break;
+                }
+            };
+            let target = operand.value + operand.addend;
+            let immediate = operand.kind.immediate(if operand.kind.is_pcrel() { target - operand.auipc as i64 } else { target });
+            assembly.program[index].replace_range(start..end, &immediate.to_string());
+            assembly.address_operands.insert(index, operand);
+        }
+    }
+}
+
+// All quoted strings on a line, used for the file names in .file directives.
+fn quoted_strings(line: &str) -> Vec<String> {
+    line.split('"').skip(1).step_by(2).map(|str| unescape::unescape(str).unwrap_or_else(|| str.to_owned())).collect()
+}
+
+// The labels at the start of a line like "loop: addi a0, a0, 1" and the rest of the line.
+pub fn split_labels(line: &str) -> (Vec<&str>, &str) {
+    let mut labels = Vec::new();
+    let mut rest = line;
+    while let Some((label, after)) = rest.split_once(':') {
+        if label.is_empty() || !label.chars().all(|char| char.is_ascii_alphanumeric() || matches!(char, '_' | '.' | '$')) {
+            break;
+        }
+        labels.push(label);
+        rest = after.trim_start();
+    }
+    (labels, rest)
+}
+
+// GNU numeric local labels like `1:`, which can be defined any number of times. `1b` refers to the
+// closest definition of 1 before it and `1f` to the closest one after it. Every definition becomes
+// a label .L1$N, where N counts the definitions of 1 in the file.
+#[derive(Default)]
+struct NumericLabels {
+    // How often each number has been defined so far.
+    counts: HashMap<String, usize>
+}
+
+impl NumericLabels {
+    fn is_numeric(label: &str) -> bool {
+        !label.is_empty() && label.chars().all(|char| char.is_ascii_digit())
+    }
+
+    // The label that the definition of number on this line becomes.
+    fn define(&mut self, number: &str) -> String {
+        let count = self.counts.entry(number.to_owned()).or_insert(0);
+        *count += 1;
+        format!(".L{}${}", number, *count - 1)
+    }
+
+    // The line with its 1b and 1f references replaced by the labels they refer to. A 1b without a
+    // definition before it stays as it is, to be reported as undefined.
+    fn replace(&self, line: &str) -> String {
+        expression::replace_words(line, |word| {
+            let (number, direction) = word.split_at(word.len() - 1);
+            let count = self.counts.get(number).copied().unwrap_or(0);
+            match direction {
+                _ if !Self::is_numeric(number) => None,
+                "b" if count > 0 => Some(format!(".L{}${}", number, count - 1)),
+                "f" => Some(format!(".L{}${}", number, count)),
+                _ => None
+            }
+        })
+    }
+
+    // Once the file is read, turn the labels of 1f references that have no definition after them
+    // back into 1f for the error message.
+    fn restore_undefined(&self, text: &str) -> String {
+        expression::replace_words(text, |word| {
+            let (number, count) = word.strip_prefix(".L")?.split_once('$')?;
+            let count: usize = count.parse().ok()?;
+            if count >= self.counts.get(number).copied().unwrap_or(0) { Some(format!("{}f", number)) } else { None }
+        })
+    }
+}
+
+// The name and the expression of `.equ name, value`, `.set name, value` or `name = value`.
+pub fn parse_assignment(line: &str) -> Option<(&str, &str)> {
+    let (name, value) = match line.strip_prefix(".equ ").or_else(|| line.strip_prefix(".set ")) {
+        Some(operands) => operands.split_once(',')?,
+        None => line.split_once('=')?
+    };
+    let name = name.trim();
+    let is_name = !name.is_empty() && !name.starts_with(|char: char| char.is_ascii_digit())
+        && name.chars().all(|char| char.is_ascii_alphanumeric() || matches!(char, '_' | '.' | '$'));
+    if is_name { Some((name, value.trim())) } else { None }
+}
+
+// The instruction with its operands that are expressions like `BUF_SIZE*4` or `-0x80` replaced by
+// their values, also as the offset of `offset(register)`. Constants in operands that refer to
+// labels, like `buffer+HEADER`, are replaced by their values too.
+fn evaluate_operands(line: &str, constants: &HashMap<String, i64>) -> String {
+    let Some((instruction_name, params)) = line.split_once(' ') else {
+        return line.to_owned();
+    };
+    let constant = |name: &str| constants.get(name).copied();
+    let params: Vec<String> = expression::split_operands(params).into_iter().map(|param| {
+        let indent = &param[..param.len() - param.trim_start().len()];
+        let trimmed = param.trim();
+        if let Ok(value) = expression::evaluate(trimmed, &constant) {
+            return format!("{}{}", indent, value);
+        }
+        if let Some((offset, register)) = trimmed.strip_suffix(')').and_then(|trimmed| trimmed.rsplit_once('(')) {
+            if let Ok(value) = expression::evaluate(offset, &constant) {
+                return format!("{}{}({})", indent, value, register);
+            }
+        }
+        expression::replace_words(param, |word| constant(word).map(|value| value.to_string()))
+    }).collect();
+    format!("{} {}", instruction_name, params.join(","))
+}
+
+// Compile one file into the program and the data sections, the labels stay in its FileSymbols
+// until linking.
+fn compile(index: usize, file: &str, content: &str, verbose: bool, assembly: &mut Assembly) -> Result<FileSymbols, String> {
+    let Assembly { program, line_table, sections, .. } = assembly;
     let start_program_length = program.len();
-    let mut last_jump_label: String = Default::default();
-    let lines: Vec<&str> = content.split("\n").collect();
+    let mut symbols = FileSymbols { index, file: file.to_owned(), ..FileSymbols::default() };
+    let mut last_function_label: Option<String> = None;
+    // Debug information emitted by "gcc -g", mapping back to the C source.
+    let mut source_files: HashMap<usize, String> = HashMap::new();
+    let mut compilation_directory: Option<String> = None;
+    let mut source_position: Option<SourcePosition> = None;
+    let mut in_debug_section = false;
+    // The data section the current section goes into, None in code.
+    let mut section = None;
+    // Labels in code since the last instruction, data that follows them is what they refer to.
+    let mut pending_labels = Vec::new();
+    let mut numeric_labels = NumericLabels::default();
+    // The values of .equ and .set.
+    let mut constants: HashMap<String, i64> = HashMap::new();
+    let lines = macros::expand(file, content)?;
     if verbose {
         println!("Total amount of lines: {}", lines.len());
     }
-    let digit_count = (lines.len() -1).to_string().len();
+    let digit_count = lines.iter().map(|source| source.line).max().unwrap_or(0).to_string().len();
     let mut offset = 0;
-    for (i, line) in lines.iter().enumerate() {
+    for (i, source) in lines.iter().enumerate() {
+        let line = &source.text;
         if verbose {
-            println!("{:width$}│{}", i, line, width=digit_count);
+            println!("{:width$}│{}", source.line, line, width=digit_count);
         }
         
+        let column = line.chars().take_while(|c| c.is_whitespace()).count() + 1;
         let line = line.trim().replace('\t', " ");
-        let line = String::from(line[..line.find('#').unwrap_or_else(|| line.len())].trim());
+        let line = String::from(line[..comment_start(&line)].trim());
 
-        if line.ends_with(":") {
-            last_jump_label = line[..line.len()-1].to_string();
-            jump_tag_map.insert(last_jump_label[..].to_string(), i - offset + start_program_length);
-            offset += 1; // We are removing the line with the jump tag.
+        if in_debug_section && !line.starts_with('.') {
+            offset += 1; // The contents of .debug_* sections are not loaded.
+            continue;
         }
-        else if line.starts_with('.') {
-            offset += 1;
-            if line.starts_with(".string") {
-                let str = line.split_once(' ').unwrap().1.trim();
-                let str = unescape::unescape(&str[1..str.len()-1]).unwrap();
-                
-                *data_segment_size += str.len() + 1;
-                let size = memory.stack_memory.len();
-                for (i, c) in str.char_indices() {
-                    memory.stack_memory[size - *data_segment_size + i] = c as u8;
+        let (labels, line) = split_labels(&line);
+        for label in labels {
+            let label = if NumericLabels::is_numeric(label) { numeric_labels.define(label) } else { label.to_owned() };
+            if let Some(&defined) = symbols.lines.get(&label) {
+                symbols.errors.push(format!("{}:{}: the label \"{}\" is already defined on line {}", source.file, source.line, label, defined));
+            }
+            symbols.lines.insert(label.clone(), source.line);
+            if let Some(section) = section {
+                let data_section: &DataSection = &sections[section];
+                symbols.labels.insert(label.clone(), data_section.address + data_section.data.len());
+                symbols.data_labels.insert(label);
+            } else {
+                if !label.starts_with('.') {
+                    last_function_label = Some(label.clone());
                 }
-                memory.stack_memory[size - *data_segment_size + str.len()] = '\0' as u8;
-
-                jump_tag_map.insert(last_jump_label[..].to_string(), memory.virtual_memory_size - *data_segment_size);
-            } else if line.starts_with(".zero") {
-                let size = line.split_once(' ').unwrap().1.trim().parse::<usize>().unwrap();
+                symbols.labels.insert(label.clone(), i - offset + start_program_length);
+                pending_labels.push(label);
+            }
+        }
+        // Strings are left alone, "1b" in them is not a label.
+        let line = if line.contains('"') { line.to_owned() } else { numeric_labels.replace(line) };
 
-                *data_segment_size += size;
-                jump_tag_map.insert(last_jump_label[..].to_string(), memory.virtual_memory_size - *data_segment_size);
+        if line.is_empty() || line.starts_with('#') {
+            offset += 1; // Empty lines and lines with only labels are removed.
+        }
+        else if let Some((name, value)) = parse_assignment(&line) {
+            // With the location counter "." or a label, name becomes a label, like GCC's
+            // `.set .LANCHOR0,. + 0`. Otherwise it is a constant.
+            let location = match section {
+                Some(section) => {
+                    let data_section: &DataSection = &sections[section];
+                    data_section.address + data_section.data.len()
+                },
+                None => i - offset + start_program_length
+            };
+            offset += 1;
+            let label = Cell::new(None);
+            let value = expression::evaluate(value, &|symbol| {
+                if let Some(&value) = constants.get(symbol) {
+                    return Some(value);
+                }
+                let value = if symbol == "." { location } else { *symbols.labels.get(symbol)? };
+                label.set(Some(if symbol == "." { section.is_some() } else { symbols.data_labels.contains(symbol) }));
+                Some(value as i64)
+            }).map_err(|err| format!("{}:{}: {}", source.file, source.line, err))?;
+            match label.get() {
+                Some(data) => {
+                    symbols.labels.insert(name.to_owned(), value as usize);
+                    symbols.lines.insert(name.to_owned(), source.line);
+                    if data {
+                        symbols.data_labels.insert(name.to_owned());
+                    }
+                },
+                None => {
+                    constants.insert(name.to_owned(), value);
+                }
             }
         }
-        else if line == "" || line.starts_with('#') {
+        else if line.starts_with('.') {
             offset += 1;
+            let directive = line.split_whitespace().next().unwrap_or("");
+            if directive == ".section" {
+                let mut operands = line[directive.len()..].split(',');
+                let name = operands.next().unwrap_or("").trim();
+                let flags = operands.next().unwrap_or("").trim();
+                in_debug_section = name.starts_with(".debug");
+                section = data_section(name, flags);
+            } else if directive == ".text" || directive == ".data" || directive == ".bss" || directive == ".rodata" {
+                section = data_section(directive, "");
+                in_debug_section = false;
+            } else if in_debug_section {
+                // The contents of .debug_* sections are not loaded.
+            } else if directive == ".globl" || directive == ".global" {
+                symbols.globals.extend(line[directive.len()..].split(',').map(|name| name.trim().to_owned()));
+            } else if directive == ".file" {
+                // Either `.file "name"`, `.file N "name"` or `.file N "directory" "name"`.
+                let number = line[directive.len()..].split_whitespace().next().and_then(|n| n.parse::<usize>().ok());
+                let strings = quoted_strings(&line);
+                if let (Some(number), Some(name)) = (number, strings.last()) {
+                    let directory = if strings.len() > 1 { Some(strings[0].clone()) } else { compilation_directory.clone() };
+                    if number == 0 && directory.is_some() {
+                        compilation_directory = directory.clone();
+                    }
+                    let path = match directory {
+                        _ if Path::new(name).is_absolute() => Path::new(name).to_path_buf(),
+                        Some(directory) => Path::new(&directory).join(name),
+                        None => Path::new(file).parent().unwrap_or_else(|| Path::new("")).join(name)
+                    };
+                    source_files.insert(number, path.to_string_lossy().into_owned());
+                }
+            } else if directive == ".loc" {
+                // `.loc file line [column] [options]`, a line of 0 means there is no source line.
+                let operands: Vec<usize> = line[directive.len()..].split_whitespace().map_while(|n| n.parse::<usize>().ok()).collect();
+                source_position = match operands[..] {
+                    [number, line, ..] if line != 0 => Some(SourcePosition {
+                        file: source_files.get(&number).cloned().unwrap_or_else(|| format!("<file {}>", number)),
+                        line,
+                        column: operands.get(2).copied().unwrap_or(0)
+                    }),
+                    _ => None
+                };
+            } else if matches!(directive, ".align" | ".p2align" | ".balign") {
+                // Code is not aligned, its labels are program indices.
+                if let Some(section) = section {
+                    let (align, fill) = data::parse_alignment(directive, &line[directive.len()..], &|name| constants.get(name).copied()).map_err(|err| format!("{}:{}: {}", source.file, source.line, err))?;
+                    sections[section].align_to(align, fill);
+                }
+            } else if data::is_data_directive(directive) {
+                let directive_data = data::parse_data_directive(directive, &line[directive.len()..], &|name| constants.get(name).copied()).map_err(|err| format!("{}:{}: {}", source.file, source.line, err))?;
+                // Data in code goes to .rodata, labels right before it point to it.
+                let data_section = &mut sections[section.unwrap_or(RODATA)];
+                // References to labels are zeros until they are filled in when linking.
+                if data_section.name == ".bss" && (directive_data.bytes.iter().any(|&byte| byte != 0) || !directive_data.references.is_empty()) {
+                    return Err(format!("{}:{}: .bss can only contain zeros", source.file, source.line));
+                }
+                let address = data_section.address + data_section.data.len();
+                for label in pending_labels.drain(..) {
+                    symbols.labels.insert(label.clone(), address);
+                    symbols.data_labels.insert(label);
+                }
+                symbols.data_references.extend(directive_data.references.into_iter().map(|reference| (source.file.clone(), source.line, DataReference { address: address + reference.address, ..reference })));
+                data_section.data.extend(directive_data.bytes);
+            }
         }
         else {
-            program.push(line);
+            pending_labels.clear();
+            program.push(evaluate_operands(&line, &constants));
+            line_table.push(LineInfo { file: source.file.clone(), line: source.line, column, label: last_function_label.clone(), source: source_position.clone() });
         }
     }
 
-    for i in 0..program.len() {
-        let line = &program[i];
+    symbols.program = start_program_length..program.len();
+    for line in &mut program[start_program_length..] {
+        *line = numeric_labels.restore_undefined(line);
+    }
+    for (_, _, reference) in &mut symbols.data_references {
+        reference.symbol = numeric_labels.restore_undefined(&reference.symbol);
+        reference.minus = reference.minus.as_ref().map(|minus| numeric_labels.restore_undefined(minus));
+    }
+    Ok(symbols)
+}
+
+// Replace the labels that jumps, branches, calls and lla refer to in the instructions in range
+// by their values, key_of gives the key of a label. Calls to labels that are not defined are
+// left to be linked with other object files.
+fn resolve_labels(assembly: &mut Assembly, range: Range<usize>, key_of: &dyn Fn(&str) -> Option<String>, errors: &mut Vec<(usize, String)>) {
+    let Assembly { program, jump_tag_map, data_labels, code_references, symbol_references, .. } = assembly;
+    for index in range {
+        let line = &mut program[index];
         let (instruction_name, params) = line.split_once(" ").unwrap_or_else(|| (line, ""));
         let (mut instruction_name, mut params) = (instruction_name, String::from(params));
-        if line.starts_with("j") || line.starts_with("call") || line.starts_with('b') || line.starts_with("lla") {
-            if instruction_name == "call" {
-                instruction_name = "jal";
-                params = format!("ra, {}", params);
-            }
-            let params: Vec<&str> = params.split(',').collect();
-            let label = params[params.len()-1].trim();
-            let label = label.strip_suffix("@plt").unwrap_or(label);
-            if jump_tag_map.contains_key(label) {
+        let refers_to_label = line.starts_with("j") || line.starts_with("call") || line.starts_with("tail") || line.starts_with('b') || line.starts_with("lla") || line.starts_with("la ");
+        if !refers_to_label || instruction_name == "jr" || instruction_name == "jalr" {
+            continue;
+        }
+        // Without position independent code, la is lla.
+        if instruction_name == "la" {
+            instruction_name = "lla";
+        }
+        if instruction_name == "call" {
+            instruction_name = "jal";
+            params = format!("ra, {}", params);
+        }
+        let params: Vec<&str> = params.split(',').collect();
+        let label = params[params.len()-1].trim();
+        let label = label.strip_suffix("@plt").unwrap_or(label);
+        // The address of data can have an offset, like "lla a0, buffer+8".
+        let (label, addend) = match data::parse_reference(label, 0, 0) {
+            Ok(reference) if reference.minus.is_none() && key_of(label).is_none() && key_of(&reference.symbol).is_some() => (reference.symbol, reference.addend),
+            _ => (label.to_owned(), 0)
+        };
+        let label = &label[..];
+        match key_of(label) {
+            Some(key) if addend != 0 && !(instruction_name == "lla" && data_labels.contains(&key)) => {
+                errors.push((index, format!("only the address of data can have an offset, \"{}\" is code", label)));
+            },
+            Some(key) => {
                 if !label.starts_with('.') {
-                    print!("\x1b[32m");
-                    print!("Mapping label \"{}\" to {}", label, jump_tag_map[label]);
-                    println!("\x1b[0m");
+                    eprint!("\x1b[32m");
+                    eprint!("Mapping label \"{}\" to {}", label, jump_tag_map[&key]);
+                    eprintln!("\x1b[0m");
+                }
+                if instruction_name == "lla" && !data_labels.contains(&key) {
+                    code_references.insert(index);
+                } else if instruction_name == "lla" {
+                    symbol_references.insert(index, (key.clone(), addend));
+                } else {
+                    symbol_references.remove(&index);
+                }
+
+                let mut ins = instruction_name.to_owned() + " ";
+                for param in &params[..params.len()-1] {
+                    ins.push_str(param);
+                    ins.push(',');
                 }
-            
+                ins.push_str(&(jump_tag_map[&key] as i64 + addend).to_string());
+                *line = ins;
+            },
+            // Already a program index.
+            None if label.parse::<i64>().is_ok() => {},
+            // Defined in another object file or a library, like "call printf".
+            None if instruction_name == "jal" || instruction_name == "tail" => {
+                symbol_references.insert(index, (label.to_owned(), 0));
                 let mut ins = instruction_name.to_owned() + " ";
-                for i in 0..params.len()-1 {
-                    ins.push_str(params[i]);
-                    ins.push_str(",");
+                for param in &params[..params.len()-1] {
+                    ins.push_str(param);
+                    ins.push(',');
+                }
+                ins.push_str(label);
+                *line = ins;
+            },
+            None => errors.push((index, format!("undefined symbol \"{}\"", label)))
+        }
+    }
+}
+
+// Make the .globl labels of every file visible to the others and resolve all references to
+// labels. Returns every label that is defined twice and every reference to a label that is not
+// defined as an error, except for calls and data, which become external references.
+fn link(assembly: &mut Assembly, files: Vec<FileSymbols>) -> Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+    let mut defined_in: HashMap<&str, &FileSymbols> = HashMap::new();
+    for symbols in &files {
+        errors.extend(symbols.errors.iter().cloned());
+        let mut globals: Vec<&String> = symbols.globals.iter().filter(|name| symbols.labels.contains_key(*name)).collect();
+        globals.sort_by_key(|name| symbols.lines[*name]);
+        for name in globals {
+            match defined_in.get(&name[..]) {
+                Some(other) => errors.push(format!("{}:{}: the symbol \"{}\" is also defined in {}:{}", symbols.file, symbols.lines[name], name, other.file, other.lines[name])),
+                None => {
+                    defined_in.insert(name, symbols);
                 }
-                ins.push_str(&jump_tag_map[label].to_string()[..]);
-                program[i] = ins;
             }
-            else if instruction_name != "jr" {
-                //panic!("Jump label \"{}\" not found!", label);
-                //println!("WARNING: Jump label \"{}\" not found!", label);
+        }
+    }
+    let globals: HashSet<String> = defined_in.keys().map(|name| name.to_string()).collect();
+    for symbols in &files {
+        for (name, &value) in &symbols.labels {
+            let key = symbols.key(name, &globals).unwrap();
+            if symbols.data_labels.contains(name) {
+                assembly.data_labels.insert(key.clone());
             }
-            /*else if variables.contains_key(param) {
-                program[i] = format!("{} {}", instruction_name, jump_tag_map[param]).to_string();
+            assembly.jump_tag_map.insert(key, value);
+        }
+    }
+    // Undefined references are only reported when linking fails anyway, builtins are left out as
+    // they may be provided by the interpreter.
+    let mut failed = !errors.is_empty();
+    for symbols in files {
+        let key_of = |name: &str| symbols.key(name, &globals);
+        let mut file_errors = Vec::new();
+        resolve_labels(assembly, symbols.program.clone(), &key_of, &mut file_errors);
+        resolve_operators(assembly, symbols.program.clone(), &key_of, &mut file_errors);
+        failed |= !file_errors.is_empty();
+        let mut messages: Vec<(usize, String)> = file_errors.into_iter().map(|(index, err)| {
+            let info = &assembly.line_table[index];
+            (info.line, format!("{}:{}: {}", info.file, info.line, err))
+        }).collect();
+
+        let first_external = assembly.external_references.len();
+        for index in symbols.program.clone() {
+            if let Some((symbol, _)) = assembly.symbol_references.get(&index).filter(|(symbol, _)| !assembly.jump_tag_map.contains_key(symbol)) {
+                let info = &assembly.line_table[index];
+                assembly.external_references.push(ExternalReference { file: info.file.clone(), line: info.line, symbol: symbol.clone(), call: true });
             }
-            else {
-                let jump_location = param.parse::<i32>();
-                match jump_location {
-                    Ok(val) => {
-                        if val < 0 || val >= program.len() as i32 {
-                            panic!("Parameter \"{}\" is not a valid jump destination!", param)
-                        }
-                    },
-                    Err(_) => panic!("Label \"{}\" is not a valid jump destination!", param)
-                }
-            }*/
-        }
-    }
-    if jump_tag_map.contains_key("main") {
-        return jump_tag_map["main"] as i64
-    }
-    0
-}
-
-pub fn compile_files(files: &[String], memory: &mut Memory, verbose: bool) -> (Vec<String>, i64, usize) {
-    let mut program = Vec::new();
-    let mut data_segment_size = 0;
-    let mut entry_point = 0;
-    
-    let mut jump_tag_map: HashMap<String, usize> = HashMap::new();
-    for file in files {
-        print!("\x1b[92m");
-        print!("\x1b[1m");
-        print!("Compiling \"{}\"", file);
-        println!("\x1b[0m");
-        let content = &fs::read_to_string(file)
-            .expect("Could not read file!")[..];
-        let ep = compile(content, memory, verbose, &mut program, &mut data_segment_size, &mut jump_tag_map);
-        if ep != 0 {
-            entry_point = ep;
-        }
-    }
-    print!("\x1b[92m");
-    print!("\x1b[1m");
-    print!("Compilation finished, entry_point = {}, data_segment_size = {} bytes", entry_point, data_segment_size);
-    println!("\x1b[0m");
-    return (program, entry_point, data_segment_size);
+        }
+        for (file, line, reference) in &symbols.data_references {
+            let mut key = |name: &String| key_of(name).unwrap_or_else(|| {
+                assembly.external_references.push(ExternalReference { file: file.clone(), line: *line, symbol: name.clone(), call: false });
+                name.clone()
+            });
+            let reference = DataReference { symbol: key(&reference.symbol), minus: reference.minus.as_ref().map(&mut key), ..reference.clone() };
+            assembly.data_references.push(reference);
+        }
+        let undefined = assembly.external_references[first_external..].iter().filter(|reference| !builtins::is_builtin(&reference.symbol));
+        messages.extend(undefined.map(|reference| (reference.line, reference.to_string())));
+        messages.sort_by_key(|(line, _)| *line);
+        errors.extend(messages.into_iter().map(|(_, message)| message));
+    }
+    if failed {
+        return Err(errors);
+    }
+    assembly.globals = globals;
+    assembly.entry_point = assembly.find_label("main").map_or(0, |main| main as i64);
+    Ok(())
+}
+
+// Map the data sections into memory, .rodata is read only.
+pub fn map_sections(sections: &[DataSection], memory: &mut Memory) {
+    for section in sections.iter().filter(|section| !section.data.is_empty()) {
+        memory.map_segment(Segment { start: section.address, data: section.data.clone(), writable: section.is_writable(), executable: false });
+    }
+}
+
+// Compile and link the files into memory, or the errors that stopped it.
+pub fn compile_files(files: &[String], memory: &mut Memory, verbose: bool) -> Result<Assembly, Vec<String>> {
+    let sections = DATA_SECTIONS.iter().map(|&(name, address)| DataSection { name, address, data: Vec::new(), align: 1 }).collect();
+    let mut assembly = Assembly { sections, ..Assembly::default() };
+
+    let mut file_symbols = Vec::new();
+    for (index, file) in files.iter().enumerate() {
+        eprint!("\x1b[92m");
+        eprint!("\x1b[1m");
+        eprint!("Compiling \"{}\"", file);
+        eprintln!("\x1b[0m");
+        let content = fs::read_to_string(file).map_err(|err| vec![format!("Could not read file \"{}\": {}", file, err)])?;
+        let symbols = compile(index, file, &content, verbose, &mut assembly).map_err(|err| vec![err])?;
+        file_symbols.push(symbols);
+        assembly.files.push(file.clone());
+    }
+    link(&mut assembly, file_symbols).map_err(|mut errors| {
+        errors.push(format!("Linking failed with {} error{}", errors.len(), if errors.len() == 1 { "" } else { "s" }));
+        errors
+    })?;
+    let too_large: Vec<String> = assembly.sections.iter().filter_map(|section| check_section_size(section.name, section.data.len()).err()).collect();
+    if !too_large.is_empty() {
+        return Err(too_large);
+    }
+    // Labels of other files can be used in data, so the references are filled in at the end.
+    for (address, bytes) in assembly.data_reference_values(&|index| index as i64) {
+        let (section, offset) = assembly.data_location(address).unwrap();
+        assembly.sections[section].data[offset..offset + bytes.len()].copy_from_slice(&bytes);
+    }
+    map_sections(&assembly.sections, memory);
+    let sizes: Vec<String> = assembly.sections.iter().map(|section| format!("{} = {} bytes", section.name, section.data.len())).collect();
+    eprint!("\x1b[92m");
+    eprint!("\x1b[1m");
+    eprint!("Compilation finished, entry_point = {}, {}", assembly.entry_point, sizes.join(", "));
+    eprintln!("\x1b[0m");
+    Ok(assembly)
 }
@@ -0,0 +1,196 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+
+use crate::evaluator::{CallKind, Evaluator};
+use crate::line_table::LineTable;
+
+#[derive(Clone, Copy, Default)]
+struct BranchCounts {
+    taken: u64,
+    not_taken: u64
+}
+
+// What is known about one line of a file: how often its code ran and its branches.
+#[derive(Default)]
+struct LineCoverage {
+    hits: u64,
+    // Program index of every branch on the line, with how often it was (not) taken.
+    branches: Vec<(usize, Option<BranchCounts>)>
+}
+
+// A function label with the line it starts at and how often it was entered.
+struct FunctionCoverage {
+    name: String,
+    line: usize,
+    entries: u64
+}
+
+#[derive(Default)]
+struct FileCoverage {
+    lines: BTreeMap<usize, LineCoverage>,
+    functions: Vec<FunctionCoverage>
+}
+
+// Collects how often every instruction ran and which way every conditional branch went.
+pub struct Coverage {
+    hits: Vec<u64>,
+    // How often execution entered a function at each instruction.
+    entries: Vec<u64>,
+    // The function of the last instruction and whether it was a call or a return.
+    function: Option<String>,
+    call_kind: CallKind,
+    branches: HashMap<usize, BranchCounts>
+}
+
+impl Coverage {
+    pub fn new(program_length: usize) -> Self {
+        Coverage {
+            hits: vec![0; program_length],
+            entries: vec![0; program_length],
+            function: None,
+            call_kind: CallKind::Call,
+            branches: HashMap::new()
+        }
+    }
+
+    // Record the instruction at pc, next_pc is where execution continued afterwards.
+    pub fn record(&mut self, pc: usize, instruction: &str, next_pc: usize, line_table: &LineTable) {
+        // Instructions fetched from memory can add to the program while it runs.
+        if pc >= self.hits.len() {
+            self.hits.resize(pc + 1, 0);
+            self.entries.resize(pc + 1, 0);
+        }
+        self.hits[pc] += 1;
+        // Calls, tail calls and falling through enter a function, returns and loops back to its
+        // first instruction do not.
+        let function = line_table.get(pc).and_then(|info| info.label.as_deref());
+        let changed = function != self.function.as_deref();
+        if self.call_kind == CallKind::Call || (changed && self.call_kind != CallKind::Return) {
+            self.entries[pc] += 1;
+        }
+        if changed {
+            self.function = function.map(str::to_owned);
+        }
+        self.call_kind = Evaluator::call_kind(instruction);
+        if Evaluator::is_branch(instruction) {
+            let counts = self.branches.entry(pc).or_default();
+            if next_pc == pc + 1 {
+                counts.not_taken += 1;
+            } else {
+                counts.taken += 1;
+            }
+        }
+    }
+
+    // Group the coverage by file and line, both for the assembly and the C sources it came from.
+    fn files(&self, program: &[String], line_table: &LineTable) -> BTreeMap<String, FileCoverage> {
+        let mut files: BTreeMap<String, FileCoverage> = BTreeMap::new();
+        for (i, instruction) in program.iter().enumerate() {
+            let info = &line_table[i];
+            let mut positions = vec![(info.file.clone(), info.line)];
+            if let Some(source) = &info.source {
+                positions.push((source.file.clone(), source.line));
+            }
+            let is_function_start = info.label.is_some() && (i == 0 || line_table[i - 1].label != info.label);
+
+            for (file, line) in positions {
+                let file = files.entry(file).or_default();
+                let line_coverage = file.lines.entry(line).or_default();
+                // A C line is as covered as the most executed instruction generated for it.
+                line_coverage.hits = line_coverage.hits.max(self.hits[i]);
+                if Evaluator::is_branch(instruction) {
+                    line_coverage.branches.push((i, self.branches.get(&i).copied()));
+                }
+                if is_function_start {
+                    file.functions.push(FunctionCoverage { name: info.label.clone().unwrap(), line, entries: self.entries[i] });
+                }
+            }
+        }
+        files
+    }
+
+    // Write the coverage in the lcov tracefile format, readable by genhtml and most CI tools.
+    pub fn write_lcov(&self, path: &str, program: &[String], line_table: &LineTable) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "TN:")?;
+        for (name, file) in self.files(program, line_table) {
+            writeln!(writer, "SF:{}", name)?;
+            for function in &file.functions {
+                writeln!(writer, "FN:{},{}", function.line, function.name)?;
+            }
+            for function in &file.functions {
+                writeln!(writer, "FNDA:{},{}", function.entries, function.name)?;
+            }
+            writeln!(writer, "FNF:{}", file.functions.len())?;
+            writeln!(writer, "FNH:{}", file.functions.iter().filter(|function| function.entries > 0).count())?;
+
+            let (mut branches_found, mut branches_hit) = (0, 0);
+            for (line, coverage) in &file.lines {
+                for (block, counts) in &coverage.branches {
+                    for (branch, count) in [(0, counts.map(|counts| counts.taken)), (1, counts.map(|counts| counts.not_taken))] {
+                        branches_found += 1;
+                        match count {
+                            Some(count) => {
+                                if count > 0 {
+                                    branches_hit += 1;
+                                }
+                                writeln!(writer, "BRDA:{},{},{},{}", line, block, branch, count)?
+                            },
+                            None => writeln!(writer, "BRDA:{},{},{},-", line, block, branch)?
+                        }
+                    }
+                }
+            }
+            writeln!(writer, "BRF:{}", branches_found)?;
+            writeln!(writer, "BRH:{}", branches_hit)?;
+
+            for (line, coverage) in &file.lines {
+                writeln!(writer, "DA:{},{}", line, coverage.hits)?;
+            }
+            writeln!(writer, "LF:{}", file.lines.len())?;
+            writeln!(writer, "LH:{}", file.lines.values().filter(|coverage| coverage.hits > 0).count())?;
+            writeln!(writer, "end_of_record")?;
+        }
+        writer.flush()
+    }
+
+    // Write every file with the execution count in front of each line that has code, lines with
+    // code that never ran are marked with #####.
+    pub fn write_report(&self, path: &str, program: &[String], line_table: &LineTable) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        for (name, file) in self.files(program, line_table) {
+            let content = fs::read_to_string(&name).unwrap_or_default();
+            let hit = file.lines.values().filter(|coverage| coverage.hits > 0).count();
+            writeln!(writer, "{}: {}/{} lines executed", name, hit, file.lines.len())?;
+            for function in &file.functions {
+                writeln!(writer, "  {} entered {} times", function.name, function.entries)?;
+            }
+            for (i, text) in content.lines().enumerate() {
+                match file.lines.get(&(i + 1)) {
+                    Some(coverage) if coverage.hits == 0 => write!(writer, "{:>9}", "#####")?,
+                    Some(coverage) => write!(writer, "{:>9}", coverage.hits)?,
+                    None => write!(writer, "{:>9}", "-")?
+                }
+                write!(writer, " │{:>5}│{}", i + 1, text)?;
+                if let Some(coverage) = file.lines.get(&(i + 1)) {
+                    for (_, counts) in &coverage.branches {
+                        let counts = counts.unwrap_or_default();
+                        write!(writer, "  [taken {}, not taken {}]", counts.taken, counts.not_taken)?;
+                    }
+                }
+                writeln!(writer)?;
+            }
+            writeln!(writer)?;
+        }
+        writer.flush()
+    }
+
+    // One line summary of the instruction and branch direction coverage.
+    pub fn summary(&self) -> String {
+        let executed = self.hits.iter().filter(|&&hits| hits > 0).count();
+        let directions = self.branches.values().map(|counts| (counts.taken > 0) as usize + (counts.not_taken > 0) as usize).sum::<usize>();
+        format!("Coverage: {}/{} instructions executed, {} branch directions taken of {} in executed branches",
+            executed, self.hits.len(), directions, self.branches.len() * 2)
+    }
+}
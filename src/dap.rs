@@ -0,0 +1,587 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::{fs, thread};
+
+use crate::compile::{compile_files, Assembly};
+use crate::evaluator::{CallKind, Evaluator};
+use crate::json::Json;
+use crate::line_table::LineTable;
+use crate::registers::Registers;
+
+const REGISTERS_REFERENCE: i64 = 1;
+const STACK_REFERENCE: i64 = 2;
+
+// How often (in instructions) a running program checks for incoming requests such as pause.
+const POLL_INTERVAL: usize = 1024;
+
+// Collects everything the program writes so it can be forwarded as output events.
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum RunMode {
+    Continue,
+    StepIn,
+    Next,
+    StepOut
+}
+
+enum Stop {
+    Reason(&'static str, Option<String>),
+    // The program ran past its last instruction or called exit, with the exit code.
+    Exited(i64),
+    Terminated
+}
+
+struct Session {
+    messages: Receiver<Json>,
+    seq: i64,
+    evaluator: Option<Evaluator>,
+    program: Vec<String>,
+    line_table: LineTable,
+    // Canonical path of every assembly and C source file, keyed by the file name used in the line table.
+    canonical_paths: HashMap<String, String>,
+    step_by_line: bool,
+    breakpoints: HashMap<String, HashSet<usize>>,
+    // Program index of every call instruction that has not returned yet.
+    call_stack: Vec<usize>,
+    stop_on_entry: bool,
+    output: SharedBuffer,
+    terminated: bool
+}
+
+// Run a debug adapter protocol server on stdin/stdout.
+pub fn run() {
+    let (sender, messages) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut reader = stdin.lock();
+        while let Some(message) = read_message(&mut reader) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut session = Session {
+        messages,
+        seq: 1,
+        evaluator: None,
+        program: Vec::new(),
+        line_table: LineTable::default(),
+        canonical_paths: HashMap::new(),
+        step_by_line: true,
+        breakpoints: HashMap::new(),
+        call_stack: Vec::new(),
+        stop_on_entry: false,
+        output: SharedBuffer::default(),
+        terminated: false
+    };
+
+    while !session.terminated {
+        let request = match session.messages.recv() {
+            Ok(request) => request,
+            Err(_) => break
+        };
+        if let Some(mode) = session.handle_request(&request) {
+            session.run(mode);
+        }
+    }
+}
+
+fn read_message(reader: &mut impl BufRead) -> Option<Json> {
+    loop {
+        let mut content_length = None;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header).ok()? == 0 {
+                return None;
+            }
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("Content-Length") {
+                    content_length = value.trim().parse::<usize>().ok();
+                }
+            }
+        }
+        let mut body = vec![0; content_length?];
+        reader.read_exact(&mut body).ok()?;
+        match Json::parse(&String::from_utf8_lossy(&body)) {
+            Ok(message) => return Some(message),
+            Err(err) => eprintln!("Ignoring malformed message: {}", err)
+        }
+    }
+}
+
+fn canonicalize(path: &str) -> String {
+    fs::canonicalize(path).map(|p| p.to_string_lossy().into_owned()).unwrap_or_else(|_| path.to_owned())
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        return message.to_string();
+    }
+    if let Some(message) = payload.downcast_ref::<String>() {
+        return message.clone();
+    }
+    "Evaluator panicked".to_owned()
+}
+
+impl Session {
+    fn send(&mut self, mut message: Vec<(&str, Json)>) {
+        message.insert(0, ("seq", Json::from(self.seq)));
+        self.seq += 1;
+        let body = Json::object(message).to_string();
+        let mut stdout = io::stdout();
+        write!(stdout, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        stdout.flush().unwrap();
+    }
+
+    fn respond(&mut self, request: &Json, body: Result<Json, String>) {
+        let request_seq = request.get("seq").cloned().unwrap_or(Json::Null);
+        let command = request.get("command").cloned().unwrap_or(Json::Null);
+        let mut message = vec![
+            ("type", Json::from("response")),
+            ("request_seq", request_seq),
+            ("command", command)
+        ];
+        match body {
+            Ok(body) => {
+                message.push(("success", Json::from(true)));
+                message.push(("body", body));
+            },
+            Err(err) => {
+                message.push(("success", Json::from(false)));
+                message.push(("message", Json::from(err)));
+            }
+        }
+        self.send(message);
+    }
+
+    fn event(&mut self, event: &str, body: Json) {
+        self.send(vec![
+            ("type", Json::from("event")),
+            ("event", Json::from(event)),
+            ("body", body)
+        ]);
+    }
+
+    fn flush_output(&mut self) {
+        let bytes: Vec<u8> = self.output.0.borrow_mut().drain(..).collect();
+        if !bytes.is_empty() {
+            let text = String::from_utf8_lossy(&bytes).into_owned();
+            self.event("output", Json::object(vec![
+                ("category", Json::from("stdout")),
+                ("output", Json::from(text))
+            ]));
+        }
+    }
+
+    fn eip(&self) -> usize {
+        self.evaluator.as_ref().map_or(0, |evaluator| evaluator.registers["eip"] as usize)
+    }
+
+    // Handle a single request, returns how to resume execution if the request asked for it.
+    fn handle_request(&mut self, request: &Json) -> Option<RunMode> {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("").to_owned();
+        let arguments = request.get("arguments").cloned().unwrap_or(Json::Object(Vec::new()));
+        let mode = match &command[..] {
+            "continue" => Some(RunMode::Continue),
+            "next" => Some(RunMode::Next),
+            "stepIn" => Some(RunMode::StepIn),
+            "stepOut" => Some(RunMode::StepOut),
+            _ => None
+        };
+        if mode.is_some() && self.evaluator.is_none() {
+            self.respond(request, Err("No program has been launched".to_owned()));
+            return None;
+        }
+        self.step_by_line = arguments.get("granularity").and_then(Json::as_str) != Some("instruction");
+
+        let body = match &command[..] {
+            "initialize" => Ok(Json::object(vec![
+                ("supportsConfigurationDoneRequest", Json::from(true)),
+                ("supportsDisassembleRequest", Json::from(true)),
+                ("supportsSteppingGranularity", Json::from(true)),
+                ("supportsTerminateRequest", Json::from(true))
+            ])),
+            "launch" => self.launch(&arguments),
+            "setBreakpoints" => Ok(self.set_breakpoints(&arguments)),
+            "setExceptionBreakpoints" => Ok(Json::object(vec![])),
+            "configurationDone" => Ok(Json::object(vec![])),
+            "threads" => Ok(Json::object(vec![
+                ("threads", Json::from(vec![Json::object(vec![
+                    ("id", Json::from(1)),
+                    ("name", Json::from("main"))
+                ])]))
+            ])),
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(Json::object(vec![
+                ("scopes", Json::from(vec![
+                    Json::object(vec![
+                        ("name", Json::from("Registers")),
+                        ("variablesReference", Json::from(REGISTERS_REFERENCE)),
+                        ("expensive", Json::from(false))
+                    ]),
+                    Json::object(vec![
+                        ("name", Json::from("Stack")),
+                        ("variablesReference", Json::from(STACK_REFERENCE)),
+                        ("expensive", Json::from(false))
+                    ])
+                ]))
+            ])),
+            "variables" => Ok(self.variables(&arguments)),
+            "disassemble" => Ok(self.disassemble(&arguments)),
+            "evaluate" => self.evaluate(&arguments),
+            "continue" => Ok(Json::object(vec![("allThreadsContinued", Json::from(true))])),
+            "next" | "stepIn" | "stepOut" | "pause" => Ok(Json::object(vec![])),
+            "disconnect" | "terminate" => {
+                self.terminated = true;
+                Ok(Json::object(vec![]))
+            },
+            _ => Err(format!("Unsupported request \"{}\"", command))
+        };
+        self.respond(request, body);
+
+        match &command[..] {
+            "launch" if self.evaluator.is_some() => self.event("initialized", Json::object(vec![])),
+            "configurationDone" if self.evaluator.is_some() => {
+                if self.stop_on_entry {
+                    self.stopped("entry", None);
+                } else {
+                    return Some(RunMode::Continue);
+                }
+            },
+            "pause" => self.stopped("pause", None),
+            _ => {}
+        }
+        mode
+    }
+
+    fn launch(&mut self, arguments: &Json) -> Result<Json, String> {
+        let files: Vec<String> = match arguments.get("program") {
+            Some(Json::String(file)) => vec![file.clone()],
+            Some(Json::Array(files)) => files.iter().filter_map(Json::as_str).map(str::to_owned).collect(),
+            _ => return Err("Expected \"program\" to be a file or a list of files".to_owned())
+        };
+        if let Some(file) = files.iter().find(|file| !Path::new(file).is_file()) {
+            return Err(format!("Could not read file \"{}\"", file));
+        }
+        self.stop_on_entry = arguments.get("stopOnEntry").and_then(Json::as_bool).unwrap_or(false);
+
+        let mut evaluator = Evaluator::new(false);
+        evaluator.output = Box::new(self.output.clone());
+        evaluator.input = match arguments.get("stdin").and_then(Json::as_str) {
+            Some(path) => Box::new(io::BufReader::new(fs::File::open(path).map_err(|err| format!("Could not open \"{}\": {}", path, err))?)),
+            None => Box::new(io::BufReader::new(io::empty()))
+        };
+        let Assembly { program, line_table, entry_point, data_segment_size, .. } = compile_files(&files, &mut evaluator.memory, false)
+            .map_err(|errors| errors.join("\n"))?;
+        evaluator.registers["sp"] = (evaluator.memory.virtual_memory_size - data_segment_size) as i64;
+        evaluator.registers["eip"] = entry_point;
+
+        self.canonical_paths = files.iter().map(|file| (file.clone(), canonicalize(file))).collect();
+        for source in line_table.iter().filter_map(|info| info.source.as_ref()) {
+            if !self.canonical_paths.contains_key(&source.file) {
+                self.canonical_paths.insert(source.file.clone(), canonicalize(&source.file));
+            }
+        }
+        self.program = program;
+        self.line_table = line_table;
+        self.evaluator = Some(evaluator);
+        Ok(Json::object(vec![]))
+    }
+
+    fn source(&self, file: &str) -> Json {
+        let name = Path::new(file).file_name().map_or(file.to_owned(), |name| name.to_string_lossy().into_owned());
+        Json::object(vec![
+            ("name", Json::from(name)),
+            ("path", Json::from(self.canonical_paths.get(file).cloned().unwrap_or_else(|| file.to_owned())))
+        ])
+    }
+
+    // The assembly line of an instruction and the C source line it was generated from, if known.
+    fn positions(&self, index: usize) -> Vec<(&str, usize)> {
+        let info = &self.line_table[index];
+        let mut positions = vec![(&info.file[..], info.line)];
+        if let Some(source) = &info.source {
+            positions.push((&source.file[..], source.line));
+        }
+        positions
+    }
+
+    fn source_line(&self, index: usize) -> Option<(String, usize)> {
+        self.line_table.get(index).and_then(|info| info.source.as_ref()).map(|source| (source.file.clone(), source.line))
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Json) -> Json {
+        let path = arguments.get("source").and_then(|source| source.get("path")).and_then(Json::as_str).map(canonicalize).unwrap_or_default();
+        let lines: Vec<i64> = arguments.get("breakpoints").and_then(Json::as_array).map_or(Vec::new(), |breakpoints| {
+            breakpoints.iter().filter_map(|breakpoint| breakpoint.get("line").and_then(Json::as_i64)).collect()
+        });
+
+        let mut indices = HashSet::new();
+        let mut breakpoints = Vec::new();
+        for line in lines {
+            // Breakpoints on lines without code move to the next line in the same file that has an instruction.
+            let found = (0..self.line_table.len())
+                .flat_map(|i| self.positions(i).into_iter().map(move |position| (i, position)))
+                .filter(|(_, (file, position_line))| self.canonical_paths.get(*file) == Some(&path) && *position_line as i64 >= line)
+                .map(|(i, (_, position_line))| (position_line, i))
+                .min();
+            match found {
+                Some((found_line, index)) => {
+                    indices.insert(index);
+                    breakpoints.push(Json::object(vec![
+                        ("verified", Json::from(true)),
+                        ("line", Json::from(found_line))
+                    ]));
+                },
+                None => breakpoints.push(Json::object(vec![
+                    ("verified", Json::from(false)),
+                    ("line", Json::from(line)),
+                    ("message", Json::from("No instruction at or after this line"))
+                ]))
+            }
+        }
+        self.breakpoints.insert(path, indices);
+        Json::object(vec![("breakpoints", Json::from(breakpoints))])
+    }
+
+    fn frame(&self, id: usize, index: usize) -> Json {
+        let mut frame = vec![
+            ("id", Json::from(id)),
+            ("instructionPointerReference", Json::from(format!("{:#x}", index))),
+            ("name", Json::from(self.line_table.function_name(index)))
+        ];
+        match self.line_table.get(index) {
+            Some(info) => match &info.source {
+                Some(source) => {
+                    frame.push(("source", self.source(&source.file)));
+                    frame.push(("line", Json::from(source.line)));
+                    frame.push(("column", Json::from(source.column.max(1))));
+                },
+                None => {
+                    frame.push(("source", self.source(&info.file)));
+                    frame.push(("line", Json::from(info.line)));
+                    frame.push(("column", Json::from(info.column)));
+                }
+            },
+            None => {
+                frame.push(("line", Json::from(0)));
+                frame.push(("column", Json::from(0)));
+            }
+        }
+        Json::object(frame)
+    }
+
+    fn stack_trace(&self) -> Json {
+        let mut frames = vec![self.frame(0, self.eip())];
+        for (id, call_site) in self.call_stack.iter().rev().enumerate() {
+            frames.push(self.frame(id + 1, *call_site));
+        }
+        Json::object(vec![
+            ("totalFrames", Json::from(frames.len())),
+            ("stackFrames", Json::from(frames))
+        ])
+    }
+
+    fn variables(&self, arguments: &Json) -> Json {
+        let evaluator = match &self.evaluator {
+            Some(evaluator) => evaluator,
+            None => return Json::object(vec![("variables", Json::from(vec![]))])
+        };
+        let variable = |name: String, value: i64| Json::object(vec![
+            ("name", Json::from(name)),
+            ("value", Json::from(format!("{} ({:#x})", value, value))),
+            ("variablesReference", Json::from(0))
+        ]);
+
+        let mut variables = Vec::new();
+        match arguments.get("variablesReference").and_then(Json::as_i64) {
+            Some(REGISTERS_REFERENCE) => {
+                variables.push(variable("eip".to_owned(), evaluator.registers["eip"]));
+                for name in Registers::NAMES {
+                    variables.push(variable(name.to_owned(), evaluator.registers[name]));
+                }
+            },
+            Some(STACK_REFERENCE) => {
+                let memory = &evaluator.memory;
+                let stack_start = (memory.virtual_memory_size - memory.stack_memory.len()) as i64;
+                let sp = evaluator.registers["sp"];
+                if sp >= stack_start && sp <= memory.virtual_memory_size as i64 {
+                    let mut address = sp;
+                    while address + 8 <= memory.virtual_memory_size as i64 {
+                        variables.push(variable(format!("sp+{} ({:#x})", address - sp, address), memory.load_from(address)));
+                        address += 8;
+                    }
+                }
+            },
+            _ => {}
+        }
+        Json::object(vec![("variables", Json::from(variables))])
+    }
+
+    fn disassemble(&self, arguments: &Json) -> Json {
+        let base = arguments.get("memoryReference").and_then(Json::as_str)
+            .and_then(|reference| i64::from_str_radix(reference.trim_start_matches("0x"), 16).ok())
+            .unwrap_or(0);
+        let offset = arguments.get("instructionOffset").and_then(Json::as_i64).unwrap_or(0);
+        let count = arguments.get("instructionCount").and_then(Json::as_i64).unwrap_or(0);
+
+        let mut instructions = Vec::new();
+        for index in base + offset..base + offset + count {
+            let mut instruction = vec![("address", Json::from(format!("{:#x}", index)))];
+            if index >= 0 && (index as usize) < self.program.len() {
+                let location = &self.line_table[index as usize];
+                instruction.push(("instruction", Json::from(&self.program[index as usize][..])));
+                instruction.push(("location", self.source(&location.file)));
+                instruction.push(("line", Json::from(location.line)));
+            } else {
+                instruction.push(("instruction", Json::from("")));
+                instruction.push(("presentationHint", Json::from("invalid")));
+            }
+            instructions.push(Json::object(instruction));
+        }
+        Json::object(vec![("instructions", Json::from(instructions))])
+    }
+
+    fn evaluate(&mut self, arguments: &Json) -> Result<Json, String> {
+        let expression = arguments.get("expression").and_then(Json::as_str).unwrap_or("").trim().to_owned();
+        let context = arguments.get("context").and_then(Json::as_str).unwrap_or("");
+        let evaluator = self.evaluator.as_mut().ok_or("No program has been launched")?;
+
+        let result = if evaluator.registers.has_register(&expression) {
+            evaluator.registers[&expression[..]].to_string()
+        } else if context == "repl" {
+            // Run the expression as an instruction without moving the program counter.
+            let eip = evaluator.registers["eip"];
+            let result = panic::catch_unwind(AssertUnwindSafe(|| evaluator.evaluate(&expression)));
+            evaluator.registers["eip"] = eip;
+            match result {
+                Ok(Ok(())) => String::new(),
+                Ok(Err(err)) => return Err(err),
+                Err(payload) => return Err(panic_message(payload))
+            }
+        } else {
+            return Err(format!("\"{}\" is not a register", expression));
+        };
+        self.flush_output();
+        Ok(Json::object(vec![
+            ("result", Json::from(result)),
+            ("variablesReference", Json::from(0))
+        ]))
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) {
+        self.flush_output();
+        let mut body = vec![
+            ("reason", Json::from(reason)),
+            ("threadId", Json::from(1)),
+            ("allThreadsStopped", Json::from(true))
+        ];
+        if let Some(text) = text {
+            body.push(("text", Json::from(text)));
+        }
+        self.event("stopped", Json::object(body));
+    }
+
+    // Execute a single instruction, keeping track of calls and returns for the stack trace.
+    fn step(&mut self) -> Result<(), String> {
+        let evaluator = self.evaluator.as_mut().unwrap();
+        let eip = evaluator.registers["eip"] as usize;
+        let ins = &self.program[eip][..];
+        let call_kind = Evaluator::call_kind(ins);
+
+        match panic::catch_unwind(AssertUnwindSafe(|| evaluator.evaluate(ins))) {
+            Ok(result) => result?,
+            Err(payload) => return Err(panic_message(payload))
+        }
+        match call_kind {
+            CallKind::Call => self.call_stack.push(eip),
+            CallKind::Return => {
+                self.call_stack.pop();
+            },
+            CallKind::Other => {}
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, mode: RunMode) {
+        let depth = self.call_stack.len();
+        // When stepping through C code keep going until the source line changes.
+        let start_line = if self.step_by_line { self.source_line(self.eip()) } else { None };
+        let mut executed = 0;
+        let stop = loop {
+            if self.eip() >= self.program.len() {
+                break Stop::Exited(0);
+            }
+            if let Err(err) = self.step() {
+                break Stop::Reason("exception", Some(err));
+            }
+            executed += 1;
+            if let Some(code) = self.evaluator.as_ref().unwrap().exit_code {
+                break Stop::Exited(code);
+            }
+
+            if self.eip() < self.program.len() && self.breakpoints.values().any(|indices| indices.contains(&self.eip())) {
+                break Stop::Reason("breakpoint", None);
+            }
+            let same_line = start_line.is_some() && self.source_line(self.eip()) == start_line;
+            match mode {
+                RunMode::StepIn if !same_line => break Stop::Reason("step", None),
+                RunMode::Next if self.call_stack.len() <= depth && !same_line => break Stop::Reason("step", None),
+                RunMode::StepOut if self.call_stack.len() < depth => break Stop::Reason("step", None),
+                _ => {}
+            }
+
+            if executed % POLL_INTERVAL == 0 {
+                self.flush_output();
+                match self.messages.try_recv() {
+                    Ok(request) => {
+                        let command = request.get("command").and_then(Json::as_str).unwrap_or("").to_owned();
+                        if command == "pause" {
+                            self.respond(&request, Ok(Json::object(vec![])));
+                            break Stop::Reason("pause", None);
+                        }
+                        self.handle_request(&request);
+                        if self.terminated {
+                            break Stop::Terminated;
+                        }
+                    },
+                    Err(TryRecvError::Empty) => {},
+                    Err(TryRecvError::Disconnected) => break Stop::Terminated
+                }
+            }
+        };
+
+        match stop {
+            Stop::Reason(reason, text) => self.stopped(reason, text),
+            Stop::Exited(code) => {
+                self.flush_output();
+                self.event("exited", Json::object(vec![("exitCode", Json::from(code))]));
+                self.event("terminated", Json::object(vec![]));
+            },
+            Stop::Terminated => self.terminated = true
+        }
+    }
+}
@@ -0,0 +1,263 @@
+// The data directives of the GNU assembler: .byte, .half, .word, .dword, .ascii, .asciz, .string,
+// .zero, .space, .fill and their aliases. Numbers can be expressions with the file's constants,
+// which constants looks up.
+use crate::expression;
+
+// A value in .word or .dword that depends on labels: symbol [- minus] [+ addend].
+#[derive(Clone, Debug)]
+pub struct DataReference {
+    // Where the value is stored, an offset into the directive's bytes until the data is placed.
+    pub address: usize,
+    pub size: usize,
+    pub symbol: String,
+    pub minus: Option<String>,
+    pub addend: i64
+}
+
+impl DataReference {
+    // The value with the labels' addresses, None if one of them is not defined.
+    pub fn value(&self, address_of: &dyn Fn(&str) -> Option<i64>) -> Option<i64> {
+        let minus = match &self.minus {
+            Some(label) => address_of(label)?,
+            None => 0
+        };
+        Some(address_of(&self.symbol)?.wrapping_sub(minus).wrapping_add(self.addend))
+    }
+
+    // The value as little endian bytes of the reference's size.
+    pub fn bytes(&self, value: i64) -> Vec<u8> {
+        value.to_le_bytes()[..self.size].to_vec()
+    }
+}
+
+// The bytes of a data directive, with the values that refer to labels left as zeros.
+pub struct Data {
+    pub bytes: Vec<u8>,
+    pub references: Vec<DataReference>
+}
+
+// The size of the values of an integer directive.
+fn value_size(directive: &str) -> Option<usize> {
+    match directive {
+        ".byte" => Some(1),
+        ".half" | ".short" | ".2byte" => Some(2),
+        ".word" | ".long" | ".int" | ".4byte" => Some(4),
+        ".dword" | ".quad" | ".8byte" => Some(8),
+        _ => None
+    }
+}
+
+pub fn is_data_directive(directive: &str) -> bool {
+    value_size(directive).is_some() || matches!(directive, ".ascii" | ".asciz" | ".string" | ".zero" | ".space" | ".skip" | ".fill")
+}
+
+// A decimal, hexadecimal (0x), binary (0b) or octal (leading 0) integer, or a character like 'a'.
+pub fn parse_integer(text: &str) -> Option<i64> {
+    let text = text.trim();
+    if let Some(char) = text.strip_prefix('\'').and_then(|rest| rest.strip_suffix('\'')) {
+        let bytes = parse_escapes(char).ok()?;
+        return if bytes.len() == 1 { Some(bytes[0] as i64) } else { None };
+    }
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.strip_prefix('+').unwrap_or(text))
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        u64::from_str_radix(binary, 2).ok()?
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        u64::from_str_radix(&digits[1..], 8).ok()?
+    } else {
+        digits.parse::<u64>().ok()?
+    };
+    Some(if negative { (value as i64).wrapping_neg() } else { value as i64 })
+}
+
+// The bytes of the contents of a string literal with its escape sequences replaced.
+fn parse_escapes(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(char) = chars.next() {
+        if char != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend(char.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+        let escaped = chars.next().ok_or("the string ends with a backslash")?;
+        bytes.push(match escaped {
+            'n' => b'\n',
+            't' => b'\t',
+            'r' => b'\r',
+            'a' => 0x07,
+            'b' => 0x08,
+            'f' => 0x0c,
+            'v' => 0x0b,
+            'x' => {
+                let mut value = 0u32;
+                while let Some(digit) = chars.peek().and_then(|char| char.to_digit(16)) {
+                    value = value * 16 + digit;
+                    chars.next();
+                }
+                value as u8
+            },
+            '0'..='7' => {
+                let mut value = escaped.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match chars.peek().and_then(|char| char.to_digit(8)) {
+                        Some(digit) => {
+                            value = value * 8 + digit;
+                            chars.next();
+                        },
+                        None => break
+                    }
+                }
+                value as u8
+            },
+            other if other.is_ascii() => other as u8,
+            other => return Err(format!("unknown escape sequence \"\\{}\"", other))
+        });
+    }
+    Ok(bytes)
+}
+
+// The string literals of a comma separated list like `"a", "b\n"`.
+fn parse_strings(operands: &str) -> Result<Vec<Vec<u8>>, String> {
+    let mut strings = Vec::new();
+    let mut rest = operands.trim();
+    while !rest.is_empty() {
+        let content = rest.strip_prefix('"').ok_or_else(|| format!("expected a string, found \"{}\"", rest))?;
+        let mut end = None;
+        let mut escaped = false;
+        for (i, char) in content.char_indices() {
+            match char {
+                '\\' if !escaped => escaped = true,
+                '"' if !escaped => {
+                    end = Some(i);
+                    break;
+                },
+                _ => escaped = false
+            }
+        }
+        let end = end.ok_or("the string is not terminated")?;
+        strings.push(parse_escapes(&content[..end])?);
+        rest = content[end + 1..].trim_start();
+        if let Some(after) = rest.strip_prefix(',') {
+            rest = after.trim_start();
+        } else if !rest.is_empty() {
+            return Err(format!("expected a comma, found \"{}\"", rest));
+        }
+    }
+    Ok(strings)
+}
+
+fn is_label(text: &str) -> bool {
+    text.chars().next().is_some_and(|char| char.is_ascii_alphabetic() || char == '_' || char == '.' || char == '$')
+        && text.chars().all(|char| char.is_ascii_alphanumeric() || matches!(char, '_' | '.' | '$'))
+}
+
+// A value that refers to labels: label, label+N, label-N or label-label, with an optional +N or -N.
+pub fn parse_reference(operand: &str, address: usize, size: usize) -> Result<DataReference, String> {
+    let mut terms = Vec::new();
+    let mut start = 0;
+    for (i, char) in operand.char_indices().skip(1) {
+        if char == '+' || char == '-' {
+            terms.push(&operand[start..i]);
+            start = i;
+        }
+    }
+    terms.push(&operand[start..]);
+    let invalid = || format!("\"{}\" is not a number or a label with an offset", operand);
+    let symbol = terms[0].trim();
+    if !is_label(symbol) {
+        return Err(invalid());
+    }
+    let mut reference = DataReference { address, size, symbol: symbol.to_owned(), minus: None, addend: 0 };
+    for term in &terms[1..] {
+        let (sign, text) = term.split_at(1);
+        let text = text.trim();
+        match parse_integer(text) {
+            Some(value) if sign == "+" => reference.addend += value,
+            Some(value) => reference.addend -= value,
+            None if sign == "-" && reference.minus.is_none() && is_label(text) => reference.minus = Some(text.to_owned()),
+            None => return Err(invalid())
+        }
+    }
+    Ok(reference)
+}
+
+fn parse_count(text: Option<&str>, default: i64, what: &str, constants: &dyn Fn(&str) -> Option<i64>) -> Result<i64, String> {
+    match text.map(str::trim) {
+        None | Some("") => Ok(default),
+        Some(text) => expression::evaluate(text, constants).map_err(|err| format!("expected a number for the {}, {}", what, err))
+    }
+}
+
+// The alignment in bytes and the fill byte of .align and .p2align (a power of two) or .balign.
+pub fn parse_alignment(directive: &str, operands: &str, constants: &dyn Fn(&str) -> Option<i64>) -> Result<(usize, u8), String> {
+    let mut operands = operands.split(',');
+    let amount = parse_count(operands.next(), -1, "alignment", constants)?;
+    let fill = parse_count(operands.next(), 0, "fill value", constants)?;
+    let align = match directive {
+        ".balign" if amount > 0 && (amount as u64).is_power_of_two() => amount as usize,
+        ".align" | ".p2align" if (0..16).contains(&amount) => 1 << amount,
+        _ => return Err(format!("{} is not a valid alignment for {}", amount, directive))
+    };
+    Ok((align, fill as u8))
+}
+
+// Whether value fits in size bytes as a signed or an unsigned number.
+fn fits(value: i64, size: usize) -> bool {
+    size == 8 || (-(1i64 << (size * 8 - 1))..(1i64 << (size * 8))).contains(&value)
+}
+
+// The bytes of a data directive with its operands.
+pub fn parse_data_directive(directive: &str, operands: &str, constants: &dyn Fn(&str) -> Option<i64>) -> Result<Data, String> {
+    let mut data = Data { bytes: Vec::new(), references: Vec::new() };
+    if let Some(size) = value_size(directive) {
+        for operand in expression::split_operands(operands).into_iter().map(str::trim) {
+            match expression::evaluate(operand, constants) {
+                Ok(value) if fits(value, size) => data.bytes.extend(&value.to_le_bytes()[..size]),
+                Ok(value) => return Err(format!("{} does not fit in {} bytes", value, size)),
+                // Anything else refers to labels, with constants in the offset.
+                Err(_) => {
+                    let operand = expression::replace_words(operand, |word| constants(word).map(|value| value.to_string()));
+                    data.references.push(parse_reference(&operand, data.bytes.len(), size)?);
+                    data.bytes.resize(data.bytes.len() + size, 0);
+                }
+            }
+        }
+        return Ok(data);
+    }
+    match directive {
+        ".ascii" | ".asciz" | ".string" => for string in parse_strings(operands)? {
+            data.bytes.extend(string);
+            if directive != ".ascii" {
+                data.bytes.push(0);
+            }
+        },
+        ".zero" | ".space" | ".skip" => {
+            let mut operands = operands.splitn(2, ',');
+            let size = parse_count(operands.next(), -1, "size", constants)?;
+            let fill = parse_count(operands.next(), 0, "fill value", constants)?;
+            if size < 0 {
+                return Err(format!("expected a size after {}", directive));
+            }
+            data.bytes = vec![fill as u8; size as usize];
+        },
+        ".fill" => {
+            let mut operands = operands.splitn(3, ',');
+            let repeat = parse_count(operands.next(), -1, "repeat count", constants)?;
+            let size = parse_count(operands.next(), 1, "size", constants)?;
+            let value = parse_count(operands.next(), 0, "value", constants)?;
+            if repeat < 0 || !(0..=8).contains(&size) {
+                return Err(format!("expected a repeat count and a size of at most 8 after {}", directive));
+            }
+            for _ in 0..repeat {
+                data.bytes.extend(&value.to_le_bytes()[..size as usize]);
+            }
+        },
+        _ => return Err(format!("{} is not a data directive", directive))
+    }
+    Ok(data)
+}
@@ -0,0 +1,387 @@
+use crate::registers::Registers;
+
+// A decoded instruction in the text syntax the evaluator runs, jump and branch targets are
+// absolute byte addresses.
+pub struct Decoded {
+    pub text: String,
+    // 2 for compressed instructions, 4 otherwise.
+    pub size: u64
+}
+
+// ABI names of the floating point registers, FLOAT_NAMES[n] is register fn.
+pub const FLOAT_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1",
+    "fa0", "fa1", "fa2", "fa3", "fa4", "fa5", "fa6", "fa7",
+    "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9", "fs10", "fs11",
+    "ft8", "ft9", "ft10", "ft11"
+];
+
+// Names of the control and status registers user programs commonly access.
+pub const CSR_NAMES: [(u32, &str); 6] = [
+    (0x001, "fflags"), (0x002, "frm"), (0x003, "fcsr"), (0xc00, "cycle"), (0xc01, "time"), (0xc02, "instret")
+];
+
+fn x(register: u32) -> &'static str {
+    Registers::NAMES[register as usize & 0x1f]
+}
+
+fn f(register: u32) -> &'static str {
+    FLOAT_NAMES[register as usize & 0x1f]
+}
+
+// The rounding mode operand of a floating point instruction, left out when it is dynamic like
+// objdump does. 5 and 6 are reserved.
+fn rounding_mode(funct3: u32) -> Option<&'static str> {
+    match funct3 {
+        7 => Some(""),
+        _ => [",rne", ",rtz", ",rdn", ",rup", ",rmm"].get(funct3 as usize).copied()
+    }
+}
+
+fn csr_name(csr: u32) -> String {
+    match CSR_NAMES.iter().find(|(number, _)| *number == csr) {
+        Some((_, name)) => name.to_string(),
+        None => format!("{:#x}", csr)
+    }
+}
+
+// Bits from..=to of value, moved down to bit 0.
+fn field(value: u32, to: u32, from: u32) -> u32 {
+    value >> from & ((1 << (to - from + 1)) - 1)
+}
+
+// Sign extend the lowest bits of value.
+fn sign_extend(value: u32, bits: u32) -> i64 {
+    ((value << (32 - bits)) as i32 >> (32 - bits)) as i64
+}
+
+// Whether the instruction starting with these low 16 bits is a 32 bit instruction.
+pub fn is_full_size(low: u16) -> bool {
+    low & 0b11 == 0b11
+}
+
+// Decode the instruction in word (the upper half is ignored for compressed instructions) at pc.
+pub fn decode(word: u32, pc: u64) -> Result<Decoded, String> {
+    if is_full_size(word as u16) {
+        Ok(Decoded { text: decode_full(word, pc)?, size: 4 })
+    } else {
+        let expanded = expand_compressed(word as u16)?;
+        Ok(Decoded { text: decode_full(expanded, pc)?, size: 2 })
+    }
+}
+
+fn decode_full(word: u32, pc: u64) -> Result<String, String> {
+    let opcode = word & 0x7f;
+    let rd = field(word, 11, 7);
+    let funct3 = field(word, 14, 12);
+    let rs1 = field(word, 19, 15);
+    let rs2 = field(word, 24, 20);
+    let funct7 = field(word, 31, 25);
+    let i_immediate = sign_extend(word >> 20, 12);
+    let s_immediate = sign_extend(field(word, 31, 25) << 5 | field(word, 11, 7), 12);
+    let b_offset = sign_extend(field(word, 31, 31) << 12 | field(word, 7, 7) << 11 | field(word, 30, 25) << 5 | field(word, 11, 8) << 1, 13);
+    let j_offset = sign_extend(field(word, 31, 31) << 20 | field(word, 19, 12) << 12 | field(word, 20, 20) << 11 | field(word, 30, 21) << 1, 21);
+    let target = |offset: i64| (pc as i64).wrapping_add(offset);
+    let unknown = || Err(format!("unknown instruction {:#010x}", word));
+
+    let text = match opcode {
+        0x37 => format!("lui {},{:#x}", x(rd), word >> 12),
+        0x17 => format!("auipc {},{:#x}", x(rd), word >> 12),
+        0x6f => match rd {
+            0 => format!("j {}", target(j_offset)),
+            _ => format!("jal {},{}", x(rd), target(j_offset))
+        },
+        0x67 if funct3 == 0 => match (rd, rs1, i_immediate) {
+            (0, 1, 0) => "ret".to_owned(),
+            (0, _, 0) => format!("jr {}", x(rs1)),
+            (1, _, 0) => format!("jalr {}", x(rs1)),
+            _ => format!("jalr {},{}({})", x(rd), i_immediate, x(rs1))
+        },
+        0x63 => {
+            let name = match funct3 {
+                0 => "beq", 1 => "bne", 4 => "blt", 5 => "bge", 6 => "bltu", 7 => "bgeu",
+                _ => return unknown()
+            };
+            match (name, rs1, rs2) {
+                ("beq", _, 0) => format!("beqz {},{}", x(rs1), target(b_offset)),
+                ("bne", _, 0) => format!("bnez {},{}", x(rs1), target(b_offset)),
+                ("blt", _, 0) => format!("bltz {},{}", x(rs1), target(b_offset)),
+                ("bge", _, 0) => format!("bgez {},{}", x(rs1), target(b_offset)),
+                ("blt", 0, _) => format!("bgtz {},{}", x(rs2), target(b_offset)),
+                ("bge", 0, _) => format!("blez {},{}", x(rs2), target(b_offset)),
+                _ => format!("{} {},{},{}", name, x(rs1), x(rs2), target(b_offset))
+            }
+        },
+        0x03 => {
+            let name = match funct3 {
+                0 => "lb", 1 => "lh", 2 => "lw", 3 => "ld", 4 => "lbu", 5 => "lhu", 6 => "lwu",
+                _ => return unknown()
+            };
+            format!("{} {},{}({})", name, x(rd), i_immediate, x(rs1))
+        },
+        0x23 => {
+            let name = match funct3 {
+                0 => "sb", 1 => "sh", 2 => "sw", 3 => "sd",
+                _ => return unknown()
+            };
+            format!("{} {},{}({})", name, x(rs2), s_immediate, x(rs1))
+        },
+        0x13 => match funct3 {
+            0 if rd == 0 && rs1 == 0 && i_immediate == 0 => "nop".to_owned(),
+            0 if rs1 == 0 => format!("li {},{}", x(rd), i_immediate),
+            0 if i_immediate == 0 => format!("mv {},{}", x(rd), x(rs1)),
+            0 => format!("addi {},{},{}", x(rd), x(rs1), i_immediate),
+            2 => format!("slti {},{},{}", x(rd), x(rs1), i_immediate),
+            3 if i_immediate == 1 => format!("seqz {},{}", x(rd), x(rs1)),
+            3 => format!("sltiu {},{},{}", x(rd), x(rs1), i_immediate),
+            4 if i_immediate == -1 => format!("not {},{}", x(rd), x(rs1)),
+            4 => format!("xori {},{},{}", x(rd), x(rs1), i_immediate),
+            6 => format!("ori {},{},{}", x(rd), x(rs1), i_immediate),
+            7 => format!("andi {},{},{}", x(rd), x(rs1), i_immediate),
+            1 if field(word, 31, 26) == 0 => format!("slli {},{},{}", x(rd), x(rs1), field(word, 25, 20)),
+            5 if field(word, 31, 26) == 0x00 => format!("srli {},{},{}", x(rd), x(rs1), field(word, 25, 20)),
+            5 if field(word, 31, 26) == 0x10 => format!("srai {},{},{}", x(rd), x(rs1), field(word, 25, 20)),
+            _ => return unknown()
+        },
+        0x1b => match (funct3, funct7) {
+            (0, _) if i_immediate == 0 => format!("sext.w {},{}", x(rd), x(rs1)),
+            (0, _) => format!("addiw {},{},{}", x(rd), x(rs1), i_immediate),
+            (1, 0x00) => format!("slliw {},{},{}", x(rd), x(rs1), rs2),
+            (5, 0x00) => format!("srliw {},{},{}", x(rd), x(rs1), rs2),
+            (5, 0x20) => format!("sraiw {},{},{}", x(rd), x(rs1), rs2),
+            _ => return unknown()
+        },
+        0x33 | 0x3b => {
+            let word_sized = opcode == 0x3b;
+            let name = match (funct7, funct3, word_sized) {
+                (0x00, 0, false) => "add", (0x20, 0, false) => "sub", (0x00, 1, false) => "sll",
+                (0x00, 2, false) => "slt", (0x00, 3, false) => "sltu", (0x00, 4, false) => "xor",
+                (0x00, 5, false) => "srl", (0x20, 5, false) => "sra", (0x00, 6, false) => "or",
+                (0x00, 7, false) => "and",
+                (0x01, 0, false) => "mul", (0x01, 1, false) => "mulh", (0x01, 2, false) => "mulhsu",
+                (0x01, 3, false) => "mulhu", (0x01, 4, false) => "div", (0x01, 5, false) => "divu",
+                (0x01, 6, false) => "rem", (0x01, 7, false) => "remu",
+                (0x00, 0, true) => "addw", (0x20, 0, true) => "subw", (0x00, 1, true) => "sllw",
+                (0x00, 5, true) => "srlw", (0x20, 5, true) => "sraw", (0x01, 0, true) => "mulw",
+                (0x01, 4, true) => "divw", (0x01, 5, true) => "divuw", (0x01, 6, true) => "remw",
+                (0x01, 7, true) => "remuw",
+                _ => return unknown()
+            };
+            match name {
+                "add" if rs1 == 0 => format!("mv {},{}", x(rd), x(rs2)),
+                "sub" if rs1 == 0 => format!("neg {},{}", x(rd), x(rs2)),
+                "subw" if rs1 == 0 => format!("negw {},{}", x(rd), x(rs2)),
+                "sltu" if rs1 == 0 => format!("snez {},{}", x(rd), x(rs2)),
+                "slt" if rs2 == 0 => format!("sltz {},{}", x(rd), x(rs1)),
+                "slt" if rs1 == 0 => format!("sgtz {},{}", x(rd), x(rs2)),
+                _ => format!("{} {},{},{}", name, x(rd), x(rs1), x(rs2))
+            }
+        },
+        0x0f => match (funct3, word >> 20) {
+            (0, 0xff) => "fence".to_owned(),
+            (0, 0x833) => "fence.tso".to_owned(),
+            (0, sets) => {
+                let set = |bits: u32| "iorw".chars().enumerate().filter(|(i, _)| bits & 8 >> i != 0).map(|(_, char)| char).collect::<String>();
+                format!("fence {},{}", set(sets >> 4 & 0xf), set(sets & 0xf))
+            },
+            (1, _) => "fence.i".to_owned(),
+            _ => return unknown()
+        },
+        0x73 => match (funct3, word >> 20) {
+            (0, 0) if rd == 0 && rs1 == 0 => "ecall".to_owned(),
+            (0, 1) if rd == 0 && rs1 == 0 => "ebreak".to_owned(),
+            (1..=3, csr) => {
+                let name = ["", "csrrw", "csrrs", "csrrc"][funct3 as usize];
+                format!("{} {},{},{}", name, x(rd), csr_name(csr), x(rs1))
+            },
+            (5..=7, csr) => {
+                let name = ["", "", "", "", "", "csrrwi", "csrrsi", "csrrci"][funct3 as usize];
+                format!("{} {},{},{}", name, x(rd), csr_name(csr), rs1)
+            },
+            _ => return unknown()
+        },
+        0x2f => {
+            let size = match funct3 {
+                2 => "w", 3 => "d",
+                _ => return unknown()
+            };
+            let ordering = match field(word, 26, 25) {
+                0 => "", 1 => ".rl", 2 => ".aq", _ => ".aqrl"
+            };
+            let name = match field(word, 31, 27) {
+                0x02 if rs2 == 0 => return Ok(format!("lr.{}{} {},({})", size, ordering, x(rd), x(rs1))),
+                0x03 => "sc", 0x01 => "amoswap", 0x00 => "amoadd", 0x04 => "amoxor", 0x0c => "amoand",
+                0x08 => "amoor", 0x10 => "amomin", 0x14 => "amomax", 0x18 => "amominu", 0x1c => "amomaxu",
+                _ => return unknown()
+            };
+            format!("{}.{}{} {},{},({})", name, size, ordering, x(rd), x(rs2), x(rs1))
+        },
+        0x07 | 0x27 => {
+            let name = match (opcode, funct3) {
+                (0x07, 2) => "flw", (0x07, 3) => "fld", (0x27, 2) => "fsw", (0x27, 3) => "fsd",
+                _ => return unknown()
+            };
+            if opcode == 0x07 {
+                format!("{} {},{}({})", name, f(rd), i_immediate, x(rs1))
+            } else {
+                format!("{} {},{}({})", name, f(rs2), s_immediate, x(rs1))
+            }
+        },
+        0x43 | 0x47 | 0x4b | 0x4f => {
+            let name = ["fmadd", "fmsub", "fnmsub", "fnmadd"][(opcode as usize - 0x43) / 4];
+            let format = match field(word, 26, 25) {
+                0 => "s", 1 => "d",
+                _ => return unknown()
+            };
+            let rounding = rounding_mode(funct3).ok_or_else(|| format!("unknown instruction {:#010x}", word))?;
+            format!("{}.{} {},{},{},{}{}", name, format, f(rd), f(rs1), f(rs2), f(field(word, 31, 27)), rounding)
+        },
+        0x53 => return decode_float(word, rd, funct3, rs1, rs2, funct7),
+        _ => return unknown()
+    };
+    Ok(text)
+}
+
+// The OP-FP instructions of the F and D extensions.
+fn decode_float(word: u32, rd: u32, funct3: u32, rs1: u32, rs2: u32, funct7: u32) -> Result<String, String> {
+    let format = match funct7 & 0b11 {
+        0 => "s", 1 => "d",
+        _ => return Err(format!("unknown instruction {:#010x}", word))
+    };
+    let integer_format = |number: u32| ["w", "wu", "l", "lu"].get(number as usize).copied();
+    // Only the instructions that round have a rounding mode, checked when it is used.
+    let rounding = || rounding_mode(funct3).ok_or_else(|| format!("unknown instruction {:#010x}", word));
+    let text = match funct7 >> 2 {
+        0x00 => format!("fadd.{} {},{},{}{}", format, f(rd), f(rs1), f(rs2), rounding()?),
+        0x01 => format!("fsub.{} {},{},{}{}", format, f(rd), f(rs1), f(rs2), rounding()?),
+        0x02 => format!("fmul.{} {},{},{}{}", format, f(rd), f(rs1), f(rs2), rounding()?),
+        0x03 => format!("fdiv.{} {},{},{}{}", format, f(rd), f(rs1), f(rs2), rounding()?),
+        0x0b if rs2 == 0 => format!("fsqrt.{} {},{}{}", format, f(rd), f(rs1), rounding()?),
+        0x04 => match funct3 {
+            0 if rs1 == rs2 => format!("fmv.{} {},{}", format, f(rd), f(rs1)),
+            1 if rs1 == rs2 => format!("fneg.{} {},{}", format, f(rd), f(rs1)),
+            2 if rs1 == rs2 => format!("fabs.{} {},{}", format, f(rd), f(rs1)),
+            0..=2 => format!("{}.{} {},{},{}", ["fsgnj", "fsgnjn", "fsgnjx"][funct3 as usize], format, f(rd), f(rs1), f(rs2)),
+            _ => return Err(format!("unknown instruction {:#010x}", word))
+        },
+        0x05 if funct3 <= 1 => format!("{}.{} {},{},{}", ["fmin", "fmax"][funct3 as usize], format, f(rd), f(rs1), f(rs2)),
+        0x14 if funct3 <= 2 => format!("{}.{} {},{},{}", ["fle", "flt", "feq"][funct3 as usize], format, x(rd), f(rs1), f(rs2)),
+        // Widening conversions are exact and have no rounding mode.
+        0x08 if format == "d" => format!("fcvt.d.s {},{}", f(rd), f(rs1)),
+        0x08 => format!("fcvt.s.d {},{}{}", f(rd), f(rs1), rounding()?),
+        0x18 => match integer_format(rs2) {
+            Some(integer) => format!("fcvt.{}.{} {},{}{}", integer, format, x(rd), f(rs1), rounding()?),
+            None => return Err(format!("unknown instruction {:#010x}", word))
+        },
+        0x1a => match integer_format(rs2) {
+            Some(integer) if format == "d" && integer.starts_with('w') => format!("fcvt.d.{} {},{}", integer, f(rd), x(rs1)),
+            Some(integer) => format!("fcvt.{}.{} {},{}{}", format, integer, f(rd), x(rs1), rounding()?),
+            None => return Err(format!("unknown instruction {:#010x}", word))
+        },
+        0x1c if funct3 == 0 => format!("fmv.x.{} {},{}", if format == "s" { "w" } else { "d" }, x(rd), f(rs1)),
+        0x1c if funct3 == 1 => format!("fclass.{} {},{}", format, x(rd), f(rs1)),
+        0x1e if funct3 == 0 => format!("fmv.{}.x {},{}", if format == "s" { "w" } else { "d" }, f(rd), x(rs1)),
+        _ => return Err(format!("unknown instruction {:#010x}", word))
+    };
+    Ok(text)
+}
+
+// The 32 bit instruction a 16 bit compressed instruction stands for.
+pub fn expand_compressed(half: u16) -> Result<u32, String> {
+    let half = half as u32;
+    let unknown = || Err(format!("unknown compressed instruction {:#06x}", half));
+    let funct3 = field(half, 15, 13);
+    let rd = field(half, 11, 7);
+    let rs2 = field(half, 6, 2);
+    // The 3 bit register fields of the compressed formats address x8 - x15.
+    let rd_short = field(half, 4, 2) + 8;
+    let rs1_short = field(half, 9, 7) + 8;
+    let imm6 = sign_extend(field(half, 12, 12) << 5 | field(half, 6, 2), 6);
+
+    let r_type = |opcode: u32, funct3: u32, funct7: u32, rd: u32, rs1: u32, rs2: u32| funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode;
+    let i_type = |opcode: u32, funct3: u32, rd: u32, rs1: u32, immediate: i64| ((immediate as u32) & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode;
+    let s_type = |opcode: u32, funct3: u32, rs1: u32, rs2: u32, immediate: i64| {
+        let immediate = immediate as u32;
+        field(immediate, 11, 5) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | field(immediate, 4, 0) << 7 | opcode
+    };
+    let b_type = |funct3: u32, rs1: u32, offset: i64| {
+        let offset = offset as u32;
+        field(offset, 12, 12) << 31 | field(offset, 10, 5) << 25 | rs1 << 15 | funct3 << 12 | field(offset, 4, 1) << 8 | field(offset, 11, 11) << 7 | 0x63
+    };
+    let j_offset = || sign_extend(field(half, 12, 12) << 11 | field(half, 8, 8) << 10 | field(half, 10, 9) << 8 | field(half, 6, 6) << 7
+        | field(half, 7, 7) << 6 | field(half, 2, 2) << 5 | field(half, 11, 11) << 4 | field(half, 5, 3) << 1, 12);
+    let jal = |rd: u32, offset: i64| {
+        let offset = offset as u32;
+        field(offset, 20, 20) << 31 | field(offset, 10, 1) << 21 | field(offset, 11, 11) << 20 | field(offset, 19, 12) << 12 | rd << 7 | 0x6f
+    };
+
+    let word = match (half & 0b11, funct3) {
+        (0b00, 0b000) => {
+            let immediate = field(half, 10, 7) << 6 | field(half, 12, 11) << 4 | field(half, 5, 5) << 3 | field(half, 6, 6) << 2;
+            if immediate == 0 {
+                return unknown();
+            }
+            i_type(0x13, 0, rd_short, 2, immediate as i64) // c.addi4spn
+        },
+        (0b00, 0b001) => i_type(0x07, 3, rd_short, rs1_short, (field(half, 6, 5) << 6 | field(half, 12, 10) << 3) as i64), // c.fld
+        (0b00, 0b010) => i_type(0x03, 2, rd_short, rs1_short, (field(half, 5, 5) << 6 | field(half, 12, 10) << 3 | field(half, 6, 6) << 2) as i64), // c.lw
+        (0b00, 0b011) => i_type(0x03, 3, rd_short, rs1_short, (field(half, 6, 5) << 6 | field(half, 12, 10) << 3) as i64), // c.ld
+        (0b00, 0b101) => s_type(0x27, 3, rs1_short, rd_short, (field(half, 6, 5) << 6 | field(half, 12, 10) << 3) as i64), // c.fsd
+        (0b00, 0b110) => s_type(0x23, 2, rs1_short, rd_short, (field(half, 5, 5) << 6 | field(half, 12, 10) << 3 | field(half, 6, 6) << 2) as i64), // c.sw
+        (0b00, 0b111) => s_type(0x23, 3, rs1_short, rd_short, (field(half, 6, 5) << 6 | field(half, 12, 10) << 3) as i64), // c.sd
+        (0b01, 0b000) => i_type(0x13, 0, rd, rd, imm6), // c.addi, c.nop
+        (0b01, 0b001) if rd != 0 => i_type(0x1b, 0, rd, rd, imm6), // c.addiw
+        (0b01, 0b010) => i_type(0x13, 0, rd, 0, imm6), // c.li
+        (0b01, 0b011) if rd == 2 => {
+            let immediate = sign_extend(field(half, 12, 12) << 9 | field(half, 4, 3) << 7 | field(half, 5, 5) << 6 | field(half, 2, 2) << 5 | field(half, 6, 6) << 4, 10);
+            if immediate == 0 {
+                return unknown();
+            }
+            i_type(0x13, 0, 2, 2, immediate) // c.addi16sp
+        },
+        (0b01, 0b011) if imm6 != 0 => ((imm6 as u32) & 0xfffff) << 12 | rd << 7 | 0x37, // c.lui
+        (0b01, 0b100) => {
+            let rd = rs1_short;
+            let shift = field(half, 12, 12) << 5 | field(half, 6, 2);
+            match field(half, 11, 10) {
+                0b00 => i_type(0x13, 5, rd, rd, shift as i64), // c.srli
+                0b01 => i_type(0x13, 5, rd, rd, (0x400 | shift) as i64), // c.srai
+                0b10 => i_type(0x13, 7, rd, rd, imm6), // c.andi
+                _ => {
+                    let rs2 = rd_short;
+                    match (field(half, 12, 12), field(half, 6, 5)) {
+                        (0, 0b00) => r_type(0x33, 0, 0x20, rd, rd, rs2), // c.sub
+                        (0, 0b01) => r_type(0x33, 4, 0x00, rd, rd, rs2), // c.xor
+                        (0, 0b10) => r_type(0x33, 6, 0x00, rd, rd, rs2), // c.or
+                        (0, 0b11) => r_type(0x33, 7, 0x00, rd, rd, rs2), // c.and
+                        (1, 0b00) => r_type(0x3b, 0, 0x20, rd, rd, rs2), // c.subw
+                        (1, 0b01) => r_type(0x3b, 0, 0x00, rd, rd, rs2), // c.addw
+                        _ => return unknown()
+                    }
+                }
+            }
+        },
+        (0b01, 0b101) => jal(0, j_offset()), // c.j
+        (0b01, 0b110) | (0b01, 0b111) => {
+            let offset = sign_extend(field(half, 12, 12) << 8 | field(half, 6, 5) << 6 | field(half, 2, 2) << 5 | field(half, 11, 10) << 3 | field(half, 4, 3) << 1, 9);
+            b_type(funct3 & 1, rs1_short, offset) // c.beqz, c.bnez
+        },
+        (0b10, 0b000) => i_type(0x13, 1, rd, rd, (field(half, 12, 12) << 5 | field(half, 6, 2)) as i64), // c.slli
+        (0b10, 0b001) => i_type(0x07, 3, rd, 2, (field(half, 4, 2) << 6 | field(half, 12, 12) << 5 | field(half, 6, 5) << 3) as i64), // c.fldsp
+        (0b10, 0b010) if rd != 0 => i_type(0x03, 2, rd, 2, (field(half, 3, 2) << 6 | field(half, 12, 12) << 5 | field(half, 6, 4) << 2) as i64), // c.lwsp
+        (0b10, 0b011) if rd != 0 => i_type(0x03, 3, rd, 2, (field(half, 4, 2) << 6 | field(half, 12, 12) << 5 | field(half, 6, 5) << 3) as i64), // c.ldsp
+        (0b10, 0b100) => match (field(half, 12, 12), rd, rs2) {
+            (0, 0, _) => return unknown(),
+            (0, _, 0) => i_type(0x67, 0, 0, rd, 0), // c.jr
+            (0, _, _) => r_type(0x33, 0, 0, rd, 0, rs2), // c.mv
+            (1, 0, 0) => 0x00100073, // c.ebreak
+            (1, _, 0) => i_type(0x67, 0, 1, rd, 0), // c.jalr
+            _ => r_type(0x33, 0, 0, rd, rd, rs2) // c.add
+        },
+        (0b10, 0b101) => s_type(0x27, 3, 2, rs2, (field(half, 9, 7) << 6 | field(half, 12, 10) << 3) as i64), // c.fsdsp
+        (0b10, 0b110) => s_type(0x23, 2, 2, rs2, (field(half, 8, 7) << 6 | field(half, 12, 9) << 2) as i64), // c.swsp
+        (0b10, 0b111) => s_type(0x23, 3, 2, rs2, (field(half, 9, 7) << 6 | field(half, 12, 10) << 3) as i64), // c.sdsp
+        _ => return unknown()
+    };
+    Ok(word)
+}
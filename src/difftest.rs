@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::ops::Range;
+
+use crate::evaluator::Step;
+use crate::memory::{AccessKind, Memory};
+use crate::registers::Registers;
+
+// One retired instruction from a reference simulator commit log, e.g. Spike's `--log-commits`:
+//   core   0: 3 0x0000000080000004 (0x02028593) x11 0x0000000080000020
+//   core   0: 3 0x000000008000000c (0x00b53023) mem 0x0000000080001000 0x0000000000000000
+pub struct CommitRecord {
+    pub line: usize,
+    pub pc: u64,
+    pub encoding: u32,
+    // Written integer registers by number, x0 is never logged.
+    pub registers: Vec<(usize, i64)>,
+    // Stores as (address, value, size in bytes), the value width in the log gives the size.
+    pub stores: Vec<(u64, u64, usize)>
+}
+
+fn parse_hex(str: &str) -> Option<u64> {
+    u64::from_str_radix(str.strip_prefix("0x")?, 16).ok()
+}
+
+fn parse_record(line_number: usize, line: &str) -> Option<CommitRecord> {
+    let (core, rest) = line.split_once(':')?;
+    if !core.trim_start().starts_with("core") {
+        return None;
+    }
+    let mut tokens = rest.split_whitespace().peekable();
+    // Commit lines start with the privilege level, plain instruction logs do not.
+    tokens.next()?.parse::<u8>().ok()?;
+    let pc = parse_hex(tokens.next()?)?;
+    let encoding = tokens.next()?.trim_start_matches('(').trim_end_matches(')');
+    let encoding = parse_hex(encoding)? as u32;
+
+    let mut record = CommitRecord { line: line_number, pc, encoding, registers: Vec::new(), stores: Vec::new() };
+    while let Some(token) = tokens.next() {
+        if token == "mem" {
+            let address = parse_hex(tokens.next()?)?;
+            // A load only logs the address, a store is followed by the value.
+            if let Some(value) = tokens.peek().filter(|value| value.starts_with("0x")) {
+                let size = (value.len() - 2) / 2;
+                record.stores.push((address, parse_hex(value)?, size));
+                tokens.next();
+            }
+        } else if let Some(number) = token.strip_prefix('x').and_then(|number| number.parse::<usize>().ok()) {
+            let value = parse_hex(tokens.next()?)?;
+            record.registers.push((number, value as i64));
+        } else if tokens.peek().is_some_and(|value| value.starts_with("0x")) {
+            // Floating point and control and status registers are not compared.
+            tokens.next();
+        }
+    }
+    Some(record)
+}
+
+// The register a jump writes its return address to, jal and jalr without one link ra.
+fn link_register(instruction: &str) -> Option<usize> {
+    let (instruction_name, params) = instruction.split_once(' ').unwrap_or((instruction, ""));
+    let params: Vec<&str> = params.split(',').map(str::trim).collect();
+    let link = match instruction_name {
+        "call" => "ra",
+        "jal" | "jalr" if params.len() == 1 => "ra",
+        "jal" | "jalr" => params[0],
+        _ => return None
+    };
+    Registers::NAMES.iter().position(|register| *register == link).filter(|&number| number != 0)
+}
+
+// A range of iasm's addresses, the stack or a section, and how far the same memory is from it in
+// the reference. The distance is learned from the first address in the range that differs.
+struct Region {
+    range: Range<u64>,
+    offset: Option<i64>
+}
+
+// Steps along a reference commit log and reports the first instruction that behaves differently.
+pub struct DiffTester {
+    records: Vec<CommitRecord>,
+    position: usize,
+    checked: usize,
+    // Program indices are not byte addresses, so the mapping between the two is learned while
+    // running and has to stay consistent for the control flow to be the same.
+    index_to_pc: HashMap<usize, u64>,
+    pc_to_index: HashMap<u64, usize>,
+    regions: Vec<Region>,
+    // The registers written by the skipped part of the log, the state the comparison starts in.
+    initial: Vec<(usize, i64)>,
+    // iasm's starting values of the registers the log does not give, with the reference's value
+    // once one of them is compared.
+    unknown: HashMap<i64, Option<i64>>
+}
+
+impl DiffTester {
+    // Read a commit log, skipping everything before the first instruction at start if given.
+    pub fn open(path: &str, start: Option<u64>, memory: &Memory) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let mut records: Vec<CommitRecord> = content.lines().enumerate()
+            .filter_map(|(i, line)| parse_record(i + 1, line))
+            .collect();
+        let mut initial: Vec<(usize, i64)> = Vec::new();
+        if let Some(start) = start {
+            let first = records.iter().position(|record| record.pc == start).unwrap_or(records.len());
+            for (number, value) in records.drain(..first).flat_map(|record| record.registers) {
+                initial.retain(|&(n, _)| n != number);
+                initial.push((number, value));
+            }
+        }
+        let mut regions: Vec<Region> = memory.segments.iter()
+            .map(|segment| Region { range: segment.start as u64..(segment.start + segment.data.len()) as u64, offset: None })
+            .collect();
+        let stack_end = memory.virtual_memory_size as u64;
+        regions.push(Region { range: stack_end - memory.stack_memory.len() as u64..stack_end, offset: None });
+        Ok(DiffTester { records, position: 0, checked: 0, index_to_pc: HashMap::new(), pc_to_index: HashMap::new(), regions, initial, unknown: HashMap::new() })
+    }
+
+    // Start from the reference's registers at the first checked instruction. sp and ra tie iasm's
+    // stack and return address to the reference's, the other registers are copied. Registers the
+    // log does not give are tied to the reference by the first comparison of their value.
+    pub fn seed(&mut self, registers: &mut Registers) {
+        for (number, name) in Registers::NAMES.iter().enumerate().skip(1) {
+            if *name != "sp" && !self.initial.iter().any(|&(n, _)| n == number) {
+                self.unknown.insert(registers[name], None);
+            }
+        }
+        for (number, value) in std::mem::take(&mut self.initial) {
+            let name = Registers::NAMES[number];
+            let actual = registers[name];
+            match name {
+                "sp" => {
+                    self.same_value(actual, value);
+                },
+                "ra" => {
+                    self.index_to_pc.insert(actual as usize, value as u64);
+                    self.pc_to_index.insert(value as u64, actual as usize);
+                },
+                _ => registers[name] = value
+            }
+        }
+    }
+
+    fn describe(record: &CommitRecord) -> String {
+        format!("reference line {}: pc {:#x} ({:#010x})", record.line, record.pc, record.encoding)
+    }
+
+    fn map_index(&mut self, index: usize, pc: u64) -> Result<(), String> {
+        match (self.index_to_pc.get(&index), self.pc_to_index.get(&pc)) {
+            (Some(&known), _) if known != pc => Err(format!("control flow differs: instruction {} was at pc {:#x} before, now at {:#x}", index, known, pc)),
+            (_, Some(&known)) if known != index => Err(format!("control flow differs: pc {:#x} was instruction {} before, now {}", pc, known, index)),
+            _ => {
+                self.index_to_pc.insert(index, pc);
+                self.pc_to_index.insert(pc, index);
+                Ok(())
+            }
+        }
+    }
+
+    fn region_index(&self, value: i64) -> Option<usize> {
+        let address = value as u64;
+        self.regions.iter().position(|region| region.range.start <= address && address <= region.range.end)
+    }
+
+    // Whether an iasm value is the reference's value, code positions are compared through the
+    // learned mapping and addresses in the stack and the sections moved to the reference's.
+    fn known_value(&self, actual: i64, expected: i64) -> bool {
+        actual == expected
+            || (actual >= 0 && self.index_to_pc.get(&(actual as usize)) == Some(&(expected as u64)))
+            || self.region_index(actual).and_then(|i| self.regions[i].offset).is_some_and(|offset| actual.wrapping_add(offset) == expected)
+    }
+
+    // Like known_value, but the first address that differs in a region learns where the region is.
+    fn same_value(&mut self, actual: i64, expected: i64) -> bool {
+        if self.known_value(actual, expected) {
+            return true;
+        }
+        match self.unknown.get_mut(&actual) {
+            Some(Some(value)) => return *value == expected,
+            Some(value) => {
+                *value = Some(expected);
+                return true;
+            },
+            None => {}
+        }
+        match self.region_index(actual) {
+            Some(i) if self.regions[i].offset.is_none() => {
+                self.regions[i].offset = Some(expected.wrapping_sub(actual));
+                true
+            },
+            _ => false
+        }
+    }
+
+    // Pseudo instructions like li, la and call expand to several real instructions that all write
+    // the same register, the number of records that belong to a step with these writes.
+    fn group_size(&self, writes: &[(usize, i64)], link: Option<usize>) -> usize {
+        let (number, value) = match writes {
+            [write] => *write,
+            _ => return 1
+        };
+        let matches = |record: &CommitRecord| record.registers.iter().any(|&(n, v)| {
+            n == number && if link == Some(number) { v as u64 == record.pc + 4 || v as u64 == record.pc + 2 } else { self.known_value(value, v) }
+        });
+        let first = &self.records[self.position];
+        if matches(first) || !first.registers.iter().any(|&(n, _)| n == number) {
+            return 1;
+        }
+        let mut end = self.position;
+        while let Some(next) = self.records.get(end + 1) {
+            let previous = &self.records[end];
+            if !next.registers.iter().any(|&(n, _)| n == number) || !(next.pc == previous.pc + 4 || next.pc == previous.pc + 2) {
+                break;
+            }
+            end += 1;
+            if matches(next) {
+                return end - self.position + 1;
+            }
+        }
+        // An address in a region that is not mapped yet, la and lla are auipc followed by addi or
+        // ld and absolute addresses lui followed by addi.
+        let opcode = first.encoding & 0x7f;
+        if (opcode == 0x17 || opcode == 0x37) && end > self.position { 2 } else { 1 }
+    }
+
+    // Compare a step at pc (the program index, or the address of fetched code) against the next
+    // reference record(s), returns a description of the first difference.
+    pub fn check(&mut self, step: &Step, instruction: &str, pc: usize) -> Result<(), String> {
+        if self.position >= self.records.len() {
+            return Err(format!("the reference log ended after {} instructions", self.checked));
+        }
+        let writes: Vec<(usize, i64)> = step.writes.iter()
+            .filter_map(|(name, value)| Registers::NAMES.iter().position(|register| register == name).map(|number| (number, *value)))
+            .filter(|(number, _)| *number != 0)
+            .collect();
+        let link = link_register(instruction);
+
+        let count = self.group_size(&writes, link);
+        let group = &self.records[self.position..self.position + count];
+        let context = format!("{} ({})", Self::describe(&group[0]), instruction);
+        let first_pc = group[0].pc;
+        let mut expected: Vec<(usize, i64)> = group[count - 1].registers.clone();
+        let stores: Vec<(u64, u64, usize)> = group.iter().flat_map(|record| record.stores.clone()).collect();
+
+        self.map_index(pc, first_pc).map_err(|err| format!("{}: {}", context, err))?;
+
+        // The return address of a jump is a code position, compared through the learned mapping.
+        if let Some(number) = link {
+            let written = writes.iter().find(|&&(n, _)| n == number);
+            if let (Some(&(_, value)), Some(position)) = (written, expected.iter().position(|&(n, _)| n == number)) {
+                self.map_index(value as usize, expected[position].1 as u64).map_err(|err| format!("{}: {}", context, err))?;
+                expected[position].1 = value;
+            }
+        }
+
+        for &(number, value) in &expected {
+            match writes.iter().find(|&&(n, _)| n == number) {
+                Some(&(_, actual)) if self.same_value(actual, value) => {},
+                Some(&(_, actual)) => return Err(format!("{}: {} should be {:#x}, but is {:#x}", context, Registers::NAMES[number], value, actual)),
+                None => return Err(format!("{}: {} should be written with {:#x}", context, Registers::NAMES[number], value))
+            }
+        }
+        if let Some(&(number, actual)) = writes.iter().find(|&&(n, _)| !expected.iter().any(|&(e, _)| e == n)) {
+            return Err(format!("{}: {} was written with {:#x}, but the reference does not write it", context, Registers::NAMES[number], actual));
+        }
+
+        let actual_stores: Vec<(u64, u64, usize)> = step.accesses.iter()
+            .filter(|access| access.kind == AccessKind::Write)
+            .map(|access| (access.address as u64, access.value as u64, access.size))
+            .collect();
+        // Only full registers can hold an address.
+        let same_stores = actual_stores.len() == stores.len() && actual_stores.iter().zip(&stores).all(|(&(address, value, size), &(expected_address, expected_value, expected_size))| {
+            size == expected_size
+                && self.same_value(address as i64, expected_address as i64)
+                && (value == expected_value || (size == 8 && self.same_value(value as i64, expected_value as i64)))
+        });
+        if !same_stores {
+            let show = |stores: &[(u64, u64, usize)]| stores.iter().map(|(address, value, size)| format!("[{:#x}] = {:#x} ({} bytes)", address, value, size)).collect::<Vec<String>>().join(", ");
+            return Err(format!("{}: memory writes should be {{{}}}, but are {{{}}}", context, show(&stores), show(&actual_stores)));
+        }
+
+        self.position += count;
+        self.checked += 1;
+        Ok(())
+    }
+
+    // Number of instructions that matched, or an error if the reference log continues.
+    pub fn finish(&self) -> Result<usize, String> {
+        match self.records.get(self.position) {
+            Some(record) => Err(format!("the program ended after {} instructions, but the reference continues at {}", self.checked, Self::describe(record))),
+            None => Ok(self.checked)
+        }
+    }
+}
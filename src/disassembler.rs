@@ -0,0 +1,139 @@
+use std::fs;
+
+use crate::decoder;
+use crate::elf::{self, Elf};
+use crate::evaluator::Evaluator;
+
+// Symbol names by address, to annotate addresses like objdump does.
+#[derive(Default)]
+pub struct Symbols(Vec<(u64, String)>);
+
+impl Symbols {
+    pub fn new(mut symbols: Vec<(u64, String)>) -> Self {
+        symbols.sort();
+        symbols.dedup_by_key(|(address, _)| *address);
+        Symbols(symbols)
+    }
+
+    // The symbol that starts at address.
+    pub fn at(&self, address: u64) -> Option<&str> {
+        self.0.binary_search_by_key(&address, |(start, _)| *start).ok().map(|i| &self.0[i].1[..])
+    }
+
+    // " <name>" or " <name+0x10>" for the closest symbol before address.
+    pub fn annotate(&self, address: u64) -> String {
+        match self.0.partition_point(|(start, _)| *start <= address) {
+            0 => String::new(),
+            i if self.0[i - 1].0 == address => format!(" <{}>", self.0[i - 1].1),
+            i => format!(" <{}+{:#x}>", self.0[i - 1].1, address - self.0[i - 1].0)
+        }
+    }
+}
+
+pub struct Line {
+    pub address: u64,
+    // The encoding, only the low 16 bits for compressed instructions.
+    pub raw: u32,
+    pub size: u64,
+    // In the decoder's syntax, None when the bytes are not an instruction.
+    pub text: Option<String>
+}
+
+// Where the absolute jump or branch target starts in a decoded instruction and the target.
+pub fn jump_target(text: &str) -> Option<(usize, u64)> {
+    let instruction_name = text.split_once(' ').map_or(text, |split| split.0);
+    if !Evaluator::is_branch(text) && !matches!(instruction_name, "j" | "jal") {
+        return None;
+    }
+    let (operands, target) = text.rsplit_once([' ', ','])?;
+    Some((operands.len() + 1, target.parse().ok()?))
+}
+
+// Decode the machine code in bytes, loaded at base. Bytes that are not an instruction are
+// shown two at a time.
+pub fn disassemble(bytes: &[u8], base: u64) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset + 2 <= bytes.len() {
+        let mut word = [0; 4];
+        let available = (bytes.len() - offset).min(4);
+        word[..available].copy_from_slice(&bytes[offset..offset + available]);
+        let word = u32::from_le_bytes(word);
+        let address = base + offset as u64;
+        let line = match decoder::decode(word, address) {
+            Ok(decoded) if offset + decoded.size as usize <= bytes.len() => {
+                let raw = if decoded.size == 2 { word & 0xffff } else { word };
+                Line { address, raw, size: decoded.size, text: Some(decoded.text) }
+            },
+            _ => Line { address, raw: word & 0xffff, size: 2, text: None }
+        };
+        offset += line.size as usize;
+        lines.push(line);
+    }
+    lines
+}
+
+// The instruction in objdump's syntax: the name padded to a column, hexadecimal jump targets
+// with the symbol they are in.
+pub fn format_instruction(text: &str, symbols: &Symbols) -> String {
+    let text = match jump_target(text) {
+        Some((at, target)) => format!("{}{:x}{}", &text[..at], target, symbols.annotate(target)),
+        None => text.to_owned()
+    };
+    match text.split_once(' ') {
+        Some((instruction_name, operands)) => format!("{:7} {}", instruction_name, operands),
+        None => text
+    }
+}
+
+// One line of the listing: address, encoding and instruction.
+pub fn format_line(line: &Line, symbols: &Symbols) -> String {
+    let raw = if line.size == 2 { format!("{:04x}", line.raw) } else { format!("{:08x}", line.raw) };
+    let text = match &line.text {
+        Some(text) => format_instruction(text, symbols),
+        None => format!("{:7} {:#06x}", ".2byte", line.raw)
+    };
+    format!("{:8x}:\t{:8}\t{}", line.address, raw, text)
+}
+
+pub fn print_listing(lines: &[Line], symbols: &Symbols) {
+    for line in lines {
+        if let Some(name) = symbols.at(line.address) {
+            println!();
+            println!("{:016x} <{}>:", line.address, name);
+        }
+        println!("{}", format_line(line, symbols));
+    }
+}
+
+// `iasm disasm <file> [--base address]`, the code sections of an ELF file or a raw binary loaded
+// at the base address.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut path = None;
+    let mut base = 0;
+    let mut options = args.iter();
+    while let Some(arg) = options.next() {
+        match &arg[..] {
+            "--base" => {
+                let address = options.next().ok_or("expected an address after \"--base\"")?;
+                base = u64::from_str_radix(address.trim_start_matches("0x"), 16).map_err(|_| format!("expected a hexadecimal address after \"--base\", got \"{}\"", address))?;
+            },
+            _ => path = Some(arg)
+        }
+    }
+    let path = path.ok_or("expected a file to disassemble")?;
+    if !elf::is_elf(path) {
+        let bytes = fs::read(path).map_err(|err| err.to_string())?;
+        print_listing(&disassemble(&bytes, base), &Symbols::default());
+        return Ok(());
+    }
+    let file = Elf::read(path)?;
+    println!("{}:\tfile format elf64-littleriscv", path);
+    for (index, header, bytes) in file.code_sections() {
+        let symbols = Symbols::new(file.symbols.iter().filter(|symbol| symbol.section == index).map(|symbol| (symbol.address, symbol.name.clone())).collect());
+        println!();
+        println!("Disassembly of section {}:", header.name);
+        print_listing(&disassemble(bytes, header.address), &symbols);
+    }
+    Ok(())
+}
@@ -0,0 +1,293 @@
+use std::fs::{self, File};
+use std::convert::TryInto;
+use std::io::Read;
+
+use crate::line_table::LineInfo;
+use crate::memory::{Memory, Segment};
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const SHT_SYMTAB: u32 = 2;
+const SHF_EXECINSTR: u64 = 0x4;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+
+// Auxiliary vector entries passed to the program on the initial stack.
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+pub const PAGE_SIZE: u64 = 4096;
+// Executables get a larger address space than compiled programs, with the stack at the top.
+pub const STACK_TOP: usize = 0x4000_0000;
+pub const STACK_SIZE: usize = 8 << 20;
+
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub address: u64,
+    pub file_size: u64,
+    pub memory_size: u64
+}
+
+pub struct SectionHeader {
+    pub name: String,
+    pub flags: u64,
+    pub address: u64,
+    pub offset: u64,
+    pub size: u64
+}
+
+pub struct Symbol {
+    pub name: String,
+    pub address: u64,
+    pub is_function: bool,
+    // The index of the section header the symbol is defined in.
+    pub section: u16
+}
+
+// A RV64 executable or object file, only statically linked executables can be loaded.
+pub struct Elf {
+    pub kind: u16,
+    pub entry: u64,
+    pub program_headers: Vec<ProgramHeader>,
+    pub section_headers: Vec<SectionHeader>,
+    pub symbols: Vec<Symbol>,
+    program_header_offset: u64,
+    program_header_size: u64,
+    bytes: Vec<u8>
+}
+
+// Whether the file starts with the ELF magic number.
+pub fn is_elf(path: &str) -> bool {
+    let mut magic = [0; 4];
+    File::open(path).and_then(|mut file| file.read_exact(&mut magic)).is_ok() && magic == ELF_MAGIC
+}
+
+fn read<const N: usize>(bytes: &[u8], offset: u64) -> Result<[u8; N], String> {
+    bytes.get(offset as usize..offset as usize + N)
+        .map(|slice| slice.try_into().unwrap())
+        .ok_or_else(|| format!("the file ends before offset {:#x}", offset as usize + N))
+}
+
+fn u16_at(bytes: &[u8], offset: u64) -> Result<u16, String> {
+    Ok(u16::from_le_bytes(read(bytes, offset)?))
+}
+
+fn u32_at(bytes: &[u8], offset: u64) -> Result<u32, String> {
+    Ok(u32::from_le_bytes(read(bytes, offset)?))
+}
+
+fn u64_at(bytes: &[u8], offset: u64) -> Result<u64, String> {
+    Ok(u64::from_le_bytes(read(bytes, offset)?))
+}
+
+fn string_at(bytes: &[u8], offset: u64) -> String {
+    let start = (offset as usize).min(bytes.len());
+    let end = bytes[start..].iter().position(|&byte| byte == 0).map_or(bytes.len(), |length| start + length);
+    String::from_utf8_lossy(&bytes[start..end]).into_owned()
+}
+
+impl Elf {
+    pub fn read(path: &str) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|err| err.to_string())?;
+        Self::parse(bytes)
+    }
+
+    pub fn parse(bytes: Vec<u8>) -> Result<Self, String> {
+        if bytes.get(..4) != Some(&ELF_MAGIC[..]) {
+            return Err("not an ELF file".to_owned());
+        }
+        if bytes.get(4) != Some(&ELFCLASS64) || bytes.get(5) != Some(&ELFDATA2LSB) {
+            return Err("only 64 bit little endian ELF files are supported".to_owned());
+        }
+        if u16_at(&bytes, 18)? != EM_RISCV {
+            return Err("not a RISC-V executable".to_owned());
+        }
+        let kind = u16_at(&bytes, 16)?;
+        let entry = u64_at(&bytes, 24)?;
+        let program_header_offset = u64_at(&bytes, 32)?;
+        let section_header_offset = u64_at(&bytes, 40)?;
+        let program_header_size = u16_at(&bytes, 54)? as u64;
+        let program_header_count = u16_at(&bytes, 56)? as u64;
+        let section_header_size = u16_at(&bytes, 58)? as u64;
+        let section_header_count = u16_at(&bytes, 60)? as u64;
+        let section_names = u16_at(&bytes, 62)? as u64;
+
+        let mut program_headers = Vec::new();
+        for i in 0..program_header_count {
+            let offset = program_header_offset + i * program_header_size;
+            program_headers.push(ProgramHeader {
+                kind: u32_at(&bytes, offset)?,
+                flags: u32_at(&bytes, offset + 4)?,
+                offset: u64_at(&bytes, offset + 8)?,
+                address: u64_at(&bytes, offset + 16)?,
+                file_size: u64_at(&bytes, offset + 32)?,
+                memory_size: u64_at(&bytes, offset + 40)?
+            });
+        }
+
+        let names = if section_header_count > 0 { u64_at(&bytes, section_header_offset + section_names * section_header_size + 24)? } else { 0 };
+        let mut section_headers = Vec::new();
+        for i in 0..section_header_count {
+            let header = section_header_offset + i * section_header_size;
+            section_headers.push(SectionHeader {
+                name: string_at(&bytes, names + u32_at(&bytes, header)? as u64),
+                flags: u64_at(&bytes, header + 8)?,
+                address: u64_at(&bytes, header + 16)?,
+                offset: u64_at(&bytes, header + 24)?,
+                size: u64_at(&bytes, header + 32)?
+            });
+        }
+
+        // Symbols from .symtab, its sh_link is the string table with the names.
+        let mut symbols = Vec::new();
+        for i in 0..section_header_count {
+            let header = section_header_offset + i * section_header_size;
+            if u32_at(&bytes, header + 4)? != SHT_SYMTAB {
+                continue;
+            }
+            let offset = u64_at(&bytes, header + 24)?;
+            let size = u64_at(&bytes, header + 32)?;
+            let entry_size = u64_at(&bytes, header + 56)?.max(24);
+            let strings_header = section_header_offset + u32_at(&bytes, header + 40)? as u64 * section_header_size;
+            let strings = u64_at(&bytes, strings_header + 24)?;
+            for symbol in (offset..offset + size).step_by(entry_size as usize).skip(1) {
+                let name = string_at(&bytes, strings + u32_at(&bytes, symbol)? as u64);
+                let kind = read::<1>(&bytes, symbol + 4)?[0] & 0xf;
+                let section = u16_at(&bytes, symbol + 6)?;
+                let address = u64_at(&bytes, symbol + 8)?;
+                // Mapping symbols ($x, $d) and local labels (.L) are not useful as labels.
+                if section == 0 || name.is_empty() || name.starts_with('$') || name.starts_with(".L") || (kind != STT_FUNC && kind != STT_NOTYPE) {
+                    continue;
+                }
+                symbols.push(Symbol { name, address, is_function: kind == STT_FUNC, section });
+            }
+        }
+        symbols.sort_by_key(|symbol| (symbol.section, symbol.address, !symbol.is_function));
+        symbols.dedup_by_key(|symbol| (symbol.section, symbol.address));
+
+        Ok(Elf { kind, entry, program_headers, section_headers, symbols, program_header_offset, program_header_size, bytes })
+    }
+
+    fn loadable(&self) -> impl Iterator<Item = &ProgramHeader> {
+        self.program_headers.iter().filter(|header| header.kind == PT_LOAD)
+    }
+
+    // Map every PT_LOAD segment with its permissions and move the program break after the highest
+    // one. The stack is moved up to STACK_TOP to make room for the segments.
+    pub fn load(&self, memory: &mut Memory) -> Result<(), String> {
+        if self.kind != ET_EXEC {
+            return Err("only statically linked executables can be loaded".to_owned());
+        }
+        let mut end = 0;
+        for header in self.loadable() {
+            let start = header.offset as usize;
+            let mut data = self.bytes.get(start..start + header.file_size as usize)
+                .ok_or_else(|| format!("the segment at {:#x} is outside the file", header.address))?
+                .to_vec();
+            data.resize(header.memory_size as usize, 0);
+            memory.map_segment(Segment {
+                start: header.address as usize,
+                data,
+                writable: header.flags & PF_W != 0,
+                executable: header.flags & PF_X != 0
+            });
+            end = end.max(header.address + header.memory_size);
+        }
+        if end as usize > STACK_TOP - STACK_SIZE {
+            return Err(format!("the segments end at {:#x}, above the stack", end));
+        }
+        memory.virtual_memory_size = STACK_TOP;
+        memory.stack_memory = vec![0; STACK_SIZE];
+        memory.program_break = end.next_multiple_of(PAGE_SIZE) as usize;
+        memory.heap_memory.resize(memory.program_break, 0);
+        Ok(())
+    }
+
+    // The address ranges of the executable segments.
+    pub fn code_ranges(&self) -> Vec<(u64, u64)> {
+        self.loadable().filter(|header| header.flags & PF_X != 0).map(|header| (header.address, header.address + header.memory_size)).collect()
+    }
+
+    // The sections with code, with their index and contents.
+    pub fn code_sections(&self) -> Vec<(u16, &SectionHeader, &[u8])> {
+        self.section_headers.iter().enumerate()
+            .filter(|(_, header)| header.flags & SHF_EXECINSTR != 0)
+            .map(|(i, header)| (i as u16, header, self.bytes.get(header.offset as usize..(header.offset + header.size) as usize).unwrap_or(&[])))
+            .collect()
+    }
+
+    // Line information for the code after every symbol, labelled with the symbol name.
+    pub fn symbol_lines(&self, path: &str) -> Vec<(u64, LineInfo)> {
+        self.symbols.iter().map(|symbol| (symbol.address, LineInfo {
+            file: path.to_owned(),
+            line: 0,
+            column: 0,
+            label: Some(symbol.name.clone()),
+            source: None
+        })).collect()
+    }
+
+    // Where the program headers are in memory, for AT_PHDR.
+    fn program_header_address(&self) -> Option<u64> {
+        if let Some(header) = self.program_headers.iter().find(|header| header.kind == PT_PHDR) {
+            return Some(header.address);
+        }
+        self.loadable().find(|header| header.offset <= self.program_header_offset && self.program_header_offset < header.offset + header.file_size)
+            .map(|header| header.address + self.program_header_offset - header.offset)
+    }
+
+    // Build the initial stack the Linux kernel hands to a new process and return the stack
+    // pointer: argc, the argv pointers, an empty environment and the auxiliary vector, with the
+    // strings they point to above them.
+    pub fn build_stack(&self, memory: &mut Memory, arguments: &[String]) -> u64 {
+        let mut top = STACK_TOP as u64;
+        let mut push_bytes = |memory: &mut Memory, bytes: &[u8]| {
+            top -= bytes.len() as u64;
+            memory.write_bytes(top as usize, bytes);
+            top
+        };
+        // Not random, so runs are reproducible.
+        let random = push_bytes(memory, &[0x5a; 16]);
+        let mut argv = Vec::new();
+        for argument in arguments.iter().rev() {
+            let mut bytes = argument.as_bytes().to_vec();
+            bytes.push(0);
+            argv.push(push_bytes(memory, &bytes));
+        }
+        argv.reverse();
+
+        let mut auxiliary = vec![(AT_PAGESZ, PAGE_SIZE), (AT_ENTRY, self.entry), (AT_RANDOM, random)];
+        if let Some(address) = self.program_header_address() {
+            auxiliary.extend([(AT_PHDR, address), (AT_PHENT, self.program_header_size), (AT_PHNUM, self.program_headers.len() as u64)]);
+        }
+        auxiliary.push((AT_NULL, 0));
+
+        let mut words = vec![arguments.len() as u64];
+        words.extend(&argv);
+        words.push(0);
+        words.push(0); // The environment is empty.
+        for (key, value) in auxiliary {
+            words.extend([key, value]);
+        }
+        let sp = (top - words.len() as u64 * 8) & !15;
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        memory.write_bytes(sp as usize, &bytes);
+        sp
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs;
+
+use crate::assembler::{self, MachineCode, Relocation, RelocationKind};
+use crate::compile::{self, Assembly};
+use crate::fetch::TEXT_BASE;
+
+const ELF_HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+const SECTION_HEADER_SIZE: u64 = 64;
+const SYMBOL_SIZE: u64 = 24;
+const RELA_SIZE: u64 = 24;
+const PAGE_SIZE: u64 = 4096;
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+const EF_RISCV_RVC: u32 = 0x1;
+const EF_RISCV_FLOAT_ABI_DOUBLE: u32 = 0x4;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+const R_RISCV_32: u64 = 1;
+const R_RISCV_64: u64 = 2;
+const R_RISCV_CALL_PLT: u64 = 19;
+const R_RISCV_PCREL_HI20: u64 = 23;
+const R_RISCV_PCREL_LO12_I: u64 = 24;
+const R_RISCV_PCREL_LO12_S: u64 = 25;
+const R_RISCV_HI20: u64 = 26;
+const R_RISCV_LO12_I: u64 = 27;
+const R_RISCV_LO12_S: u64 = 28;
+// ADD8 to ADD64 are 33 to 36, SUB8 to SUB64 37 to 40, SET8 and SET16 are 54 and 55.
+const R_RISCV_ADD8: u64 = 33;
+const R_RISCV_SUB8: u64 = 37;
+const R_RISCV_SET8: u64 = 54;
+
+// Section header indices of the sections every file starts with.
+const TEXT: u16 = 1;
+const RODATA: u16 = 2;
+const DATA: u16 = 3;
+const BSS: u16 = 4;
+
+struct Section {
+    name: &'static str,
+    kind: u32,
+    flags: u64,
+    address: u64,
+    offset: u64,
+    data: Vec<u8>,
+    // Only differs from data.len() for .bss, which takes no space in the file.
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entry_size: u64
+}
+
+impl Section {
+    fn new(name: &'static str, kind: u32, flags: u64, data: Vec<u8>, align: u64) -> Self {
+        let size = data.len() as u64;
+        Section { name, kind, flags, address: 0, offset: 0, data, size, link: 0, info: 0, align, entry_size: 0 }
+    }
+}
+
+struct Symbol {
+    // The label's key in jump_tag_map, local labels of different files can have the same name.
+    name: String,
+    value: u64,
+    kind: u8,
+    bind: u8,
+    section: u16
+}
+
+// Little endian output.
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u16(&mut self, value: u16) { self.0.extend(value.to_le_bytes()); }
+    fn u32(&mut self, value: u32) { self.0.extend(value.to_le_bytes()); }
+    fn u64(&mut self, value: u64) { self.0.extend(value.to_le_bytes()); }
+}
+
+// The .text, .rodata, .data and .bss sections.
+fn allocated_sections(assembly: &Assembly, machine_code: &MachineCode) -> Vec<Section> {
+    let mut sections = vec![
+        Section::new("", 0, 0, Vec::new(), 0),
+        Section::new(".text", SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR, machine_code.code.clone(), 4)
+    ];
+    for section in &assembly.sections {
+        let flags = if section.is_writable() { SHF_ALLOC | SHF_WRITE } else { SHF_ALLOC };
+        if section.name == ".bss" {
+            let mut bss = Section::new(section.name, SHT_NOBITS, flags, Vec::new(), section.align as u64);
+            bss.size = section.data.len() as u64;
+            sections.push(bss);
+        } else {
+            sections.push(Section::new(section.name, SHT_PROGBITS, flags, section.data.clone(), section.align as u64));
+        }
+    }
+    sections
+}
+
+// The ELF section and offset of a data address, the data sections follow .text in the same order.
+fn data_location(assembly: &Assembly, address: usize) -> (u16, u64) {
+    let (section, offset) = assembly.data_location(address).unwrap();
+    (RODATA + section as u16, offset as u64)
+}
+
+// Symbols for the labels, local ones first as ELF requires. Local labels (.L...) are only kept
+// when a relocation refers to them, symbols that are referred to but not defined are undefined.
+fn symbols(assembly: &Assembly, machine_code: &MachineCode) -> Vec<Symbol> {
+    let mut referenced: HashSet<&str> = machine_code.relocations.iter().map(|relocation| &relocation.symbol[..]).collect();
+    for reference in &assembly.data_references {
+        referenced.insert(&reference.symbol);
+        referenced.extend(reference.minus.as_deref());
+    }
+    let mut symbols = Vec::new();
+    for (label, &value) in &assembly.jump_tag_map {
+        let name = compile::symbol_name(label);
+        if name.is_empty() || (name.starts_with(".L") && !referenced.contains(&label[..])) {
+            continue;
+        }
+        let (section, value, kind) = if assembly.data_labels.contains(label) {
+            let (section, offset) = data_location(assembly, value);
+            (section, offset, STT_OBJECT)
+        } else if name.starts_with('.') {
+            (TEXT, machine_code.addresses[value], STT_NOTYPE)
+        } else {
+            (TEXT, machine_code.addresses[value], STT_FUNC)
+        };
+        let bind = if assembly.globals.contains(label) { STB_GLOBAL } else { STB_LOCAL };
+        symbols.push(Symbol { name: label.clone(), value, kind, bind, section });
+    }
+    let mut undefined: Vec<&str> = referenced.into_iter().filter(|name| !assembly.jump_tag_map.contains_key(*name)).collect();
+    undefined.sort();
+    symbols.extend(undefined.into_iter().map(|name| Symbol { name: name.to_owned(), value: 0, kind: STT_NOTYPE, bind: STB_GLOBAL, section: 0 }));
+    symbols.sort_by(|a, b| (a.bind, a.section, a.value, &a.name).cmp(&(b.bind, b.section, b.value, &b.name)));
+    symbols
+}
+
+// The string table for names, with the offset of every name in it.
+fn string_table<'a>(names: impl Iterator<Item = &'a str>) -> (Vec<u8>, Vec<u32>) {
+    let mut table = vec![0];
+    let mut offsets = Vec::new();
+    for name in names {
+        if name.is_empty() {
+            offsets.push(0);
+            continue;
+        }
+        offsets.push(table.len() as u32);
+        table.extend(name.as_bytes());
+        table.push(0);
+    }
+    (table, offsets)
+}
+
+// Add .symtab, .strtab and .shstrtab, with the symbols' sh_info pointing after the last local one.
+fn add_symbol_table(sections: &mut Vec<Section>, symbols: &[Symbol], base: &dyn Fn(u16) -> u64) {
+    let (strings, name_offsets) = string_table(symbols.iter().map(|symbol| compile::symbol_name(&symbol.name)));
+    let mut table = Writer(vec![0; SYMBOL_SIZE as usize]);
+    for (symbol, name) in symbols.iter().zip(name_offsets) {
+        table.u32(name);
+        table.0.push(symbol.bind << 4 | symbol.kind);
+        table.0.push(0);
+        table.u16(symbol.section);
+        table.u64(if symbol.section == 0 { 0 } else { base(symbol.section) + symbol.value });
+        table.u64(0);
+    }
+    let symtab_index = sections.len() as u32;
+    let mut symtab = Section::new(".symtab", SHT_SYMTAB, 0, table.0, 8);
+    symtab.link = symtab_index + 1;
+    symtab.info = 1 + symbols.iter().filter(|symbol| symbol.bind == STB_LOCAL).count() as u32;
+    symtab.entry_size = SYMBOL_SIZE;
+    sections.push(symtab);
+    sections.push(Section::new(".strtab", SHT_STRTAB, 0, strings, 1));
+    sections.push(Section::new(".shstrtab", SHT_STRTAB, 0, Vec::new(), 1));
+}
+
+// Give every section after the headers its file offset.
+fn layout(sections: &mut [Section], start: u64) -> u64 {
+    let mut offset = start;
+    for section in sections.iter_mut().skip(1) {
+        offset = offset.next_multiple_of(section.align.max(1));
+        section.offset = offset;
+        if section.kind != SHT_NOBITS {
+            offset += section.size;
+        }
+    }
+    offset
+}
+
+// The complete file: ELF header, program headers, section contents and section headers.
+fn write_file(path: &str, kind: u16, entry: u64, flags: u32, program_headers: &[[u64; 6]], mut sections: Vec<Section>) -> Result<usize, String> {
+    let (names, name_offsets) = string_table(sections.iter().map(|section| section.name));
+    let shstrtab = sections.len() - 1;
+    sections[shstrtab].size = names.len() as u64;
+    sections[shstrtab].data = names;
+    // The section names go after everything else, followed by the section headers.
+    sections[shstrtab].offset = sections.iter().filter(|section| section.kind != SHT_NOBITS).map(|section| section.offset + section.size).max().unwrap_or(0);
+    let section_header_offset = (sections[shstrtab].offset + sections[shstrtab].size).next_multiple_of(8);
+
+    let mut out = Writer::default();
+    out.0.extend(b"\x7fELF");
+    out.0.extend([2, 1, 1, 0]); // 64 bit, little endian, version 1, System V ABI.
+    out.0.resize(16, 0);
+    out.u16(kind);
+    out.u16(EM_RISCV);
+    out.u32(1);
+    out.u64(entry);
+    out.u64(if program_headers.is_empty() { 0 } else { ELF_HEADER_SIZE });
+    out.u64(section_header_offset);
+    out.u32(flags);
+    out.u16(ELF_HEADER_SIZE as u16);
+    out.u16(PROGRAM_HEADER_SIZE as u16);
+    out.u16(program_headers.len() as u16);
+    out.u16(SECTION_HEADER_SIZE as u16);
+    out.u16(sections.len() as u16);
+    out.u16(shstrtab as u16);
+    for &[flags, offset, address, file_size, memory_size, align] in program_headers {
+        out.u32(PT_LOAD);
+        out.u32(flags as u32);
+        out.u64(offset);
+        out.u64(address);
+        out.u64(address);
+        out.u64(file_size);
+        out.u64(memory_size);
+        out.u64(align);
+    }
+    for section in &sections {
+        if section.kind != SHT_NOBITS && !section.data.is_empty() {
+            out.0.resize(section.offset as usize, 0);
+            out.0.extend(&section.data);
+        }
+    }
+    out.0.resize(section_header_offset as usize, 0);
+    for (section, name) in sections.iter().zip(name_offsets) {
+        out.u32(name);
+        out.u32(section.kind);
+        out.u64(section.flags);
+        out.u64(section.address);
+        out.u64(section.offset);
+        out.u64(section.size);
+        out.u32(section.link);
+        out.u32(section.info);
+        out.u64(section.align);
+        out.u64(section.entry_size);
+    }
+    fs::write(path, &out.0).map_err(|err| err.to_string())?;
+    Ok(out.0.len())
+}
+
+fn assemble(program: &[String], assembly: &Assembly, compressed: bool) -> Result<MachineCode, String> {
+    assembler::assemble(program, &assembly.code_references, &assembly.address_operands, Some(&assembly.symbol_references), 0, compressed)
+        .map_err(|(i, err)| format!("could not encode {}: {}", program[i], err))
+}
+
+fn elf_flags(compressed: bool) -> u32 {
+    EF_RISCV_FLOAT_ABI_DOUBLE | if compressed { EF_RISCV_RVC } else { 0 }
+}
+
+// Write an ELF relocatable object file. References to data and to labels of other files become
+// relocations, .globl labels are global symbols.
+pub fn write_object(path: &str, assembly: &Assembly, compressed: bool) -> Result<usize, String> {
+    let mut machine_code = assemble(&assembly.program, assembly, compressed)?;
+    let mut sections = allocated_sections(assembly, &machine_code);
+
+    let mut symbols = symbols(assembly, &machine_code);
+    // A PCREL_LO12 relocation refers to a label on the auipc with the HI20 relocation.
+    let mut pcrel_labels: Vec<Symbol> = Vec::new();
+    let pcrel_lo = [RelocationKind::PcrelLo12, RelocationKind::PcrelLo12S];
+    for relocation in machine_code.relocations.iter_mut().filter(|relocation| pcrel_lo.contains(&relocation.kind)) {
+        relocation.symbol = match pcrel_labels.iter().find(|label| label.value == relocation.auipc) {
+            Some(label) => label.name.clone(),
+            None => {
+                let name = format!(".Lpcrel_hi{}", pcrel_labels.len());
+                pcrel_labels.push(Symbol { name: name.clone(), value: relocation.auipc, kind: STT_NOTYPE, bind: STB_LOCAL, section: TEXT });
+                name
+            }
+        };
+    }
+    let locals = symbols.iter().filter(|symbol| symbol.bind == STB_LOCAL).count();
+    symbols.splice(locals..locals, pcrel_labels);
+    let symbol_index: HashMap<&str, u64> = symbols.iter().enumerate().map(|(i, symbol)| (&symbol.name[..], i as u64 + 1)).collect();
+
+    let mut rela = Writer::default();
+    for relocation in &machine_code.relocations {
+        let kind = match relocation.kind {
+            RelocationKind::Call => R_RISCV_CALL_PLT,
+            RelocationKind::PcrelHi20 => R_RISCV_PCREL_HI20,
+            RelocationKind::PcrelLo12 => R_RISCV_PCREL_LO12_I,
+            RelocationKind::PcrelLo12S => R_RISCV_PCREL_LO12_S,
+            RelocationKind::Hi20 => R_RISCV_HI20,
+            RelocationKind::Lo12I => R_RISCV_LO12_I,
+            RelocationKind::Lo12S => R_RISCV_LO12_S
+        };
+        // The addend of a PCREL_LO12 relocation is on the HI20 one it refers to.
+        let addend = if pcrel_lo.contains(&relocation.kind) { 0 } else { relocation.addend };
+        rela.u64(relocation.offset);
+        rela.u64(symbol_index[&relocation.symbol[..]] << 32 | kind);
+        rela.u64(addend as u64);
+    }
+    let mut relocations = vec![(".rela.text", TEXT, rela.0)];
+
+    // Labels in data are absolute relocations, differences an ADD and a SUB relocation on zeros.
+    let mut data_rela = [Writer::default(), Writer::default()];
+    for reference in &assembly.data_references {
+        let (section, offset) = data_location(assembly, reference.address);
+        if section == BSS {
+            return Err(format!("{} can not be stored in .bss", reference.symbol));
+        }
+        let size_index = reference.size.trailing_zeros() as u64;
+        let kind = match (reference.size, &reference.minus) {
+            (_, Some(_)) => R_RISCV_ADD8 + size_index,
+            (8, None) => R_RISCV_64,
+            (4, None) => R_RISCV_32,
+            (_, None) => R_RISCV_SET8 + size_index
+        };
+        let rela = &mut data_rela[(section - RODATA) as usize];
+        rela.u64(offset);
+        rela.u64(symbol_index[&reference.symbol[..]] << 32 | kind);
+        rela.u64(reference.addend as u64);
+        if let Some(minus) = &reference.minus {
+            rela.u64(offset);
+            rela.u64(symbol_index[&minus[..]] << 32 | (R_RISCV_SUB8 + size_index));
+            rela.u64(0);
+        }
+        let data = &mut sections[section as usize].data;
+        data[offset as usize..offset as usize + reference.size].fill(0);
+    }
+    let [rodata_rela, data_rela] = data_rela;
+    relocations.push((".rela.rodata", RODATA, rodata_rela.0));
+    relocations.push((".rela.data", DATA, data_rela.0));
+
+    // .rela.text is always there, the others only when there are relocations.
+    relocations.retain(|(_, section, rela)| *section == TEXT || !rela.is_empty());
+    let symbol_table = (sections.len() + relocations.len()) as u32;
+    for (name, section, rela) in relocations {
+        let mut rela_section = Section::new(name, SHT_RELA, SHF_INFO_LINK, rela, 8);
+        rela_section.link = symbol_table;
+        rela_section.info = section as u32;
+        rela_section.entry_size = RELA_SIZE;
+        sections.push(rela_section);
+    }
+    add_symbol_table(&mut sections, &symbols, &|_| 0);
+    layout(&mut sections, ELF_HEADER_SIZE);
+    write_file(path, ET_REL, 0, elf_flags(compressed), &[], sections)
+}
+
+// Patch the immediate of the instruction a relocation is on for the symbol at target, for a
+// call both the auipc and the jalr after it. text is the address of the code.
+fn relocate(code: &mut [u8], relocation: &Relocation, target: i64, text: i64) {
+    let value = target + relocation.addend;
+    let value = if relocation.kind.is_pcrel() { value - (text + relocation.auipc as i64) } else { value };
+    let mut patch = |at: usize, kind: RelocationKind| {
+        let word = u32::from_le_bytes(code[at..at + 4].try_into().unwrap());
+        code[at..at + 4].copy_from_slice(&kind.patch(word, kind.immediate(value)).to_le_bytes());
+    };
+    let offset = relocation.offset as usize;
+    patch(offset, relocation.kind);
+    if relocation.kind == RelocationKind::Call {
+        patch(offset + 4, RelocationKind::PcrelLo12);
+    }
+}
+
+// Write a statically linked executable loaded at TEXT_BASE, with the code and read only data in
+// one segment and .data and .bss in a writable one. Without a _start label, a _start is added
+// that calls main and exits with its return value. A program with neither has no entry point.
+pub fn write_executable(path: &str, assembly: &Assembly, compressed: bool) -> Result<usize, String> {
+    let mut program = assembly.program.clone();
+    let start = match assembly.find_label("_start") {
+        Some(start) => start,
+        None => {
+            let main = assembly.find_label("main").ok_or("there is no _start or main label to start the executable at")?;
+            program.extend([format!("jal ra,{}", main), "li a7,93".to_owned(), "ecall".to_owned()]);
+            assembly.program.len()
+        }
+    };
+    let mut machine_code = assemble(&program, assembly, compressed)?;
+    let mut sections = allocated_sections(assembly, &machine_code);
+    let mut symbols = symbols(assembly, &machine_code);
+    if assembly.find_label("_start").is_none() {
+        symbols.push(Symbol { name: "_start".to_owned(), value: machine_code.addresses[start], kind: STT_FUNC, bind: STB_GLOBAL, section: TEXT });
+    }
+
+    // Text and read only data follow the headers in the first segment, the writable segment
+    // starts on the next page with the same offset into the page as in the file.
+    let headers_size = ELF_HEADER_SIZE + 2 * PROGRAM_HEADER_SIZE;
+    let end = layout(&mut sections[..=RODATA as usize], headers_size);
+    for section in &mut sections[1..=RODATA as usize] {
+        section.address = TEXT_BASE + section.offset;
+    }
+    let data_address = (TEXT_BASE + end).next_multiple_of(PAGE_SIZE);
+    let data_offset = end.next_multiple_of(8);
+    sections[DATA as usize].offset = data_offset;
+    sections[DATA as usize].address = data_address + data_offset % PAGE_SIZE;
+    let bss_address = (sections[DATA as usize].address + sections[DATA as usize].size).next_multiple_of(8);
+    sections[BSS as usize].offset = data_offset + sections[DATA as usize].size;
+    sections[BSS as usize].address = bss_address;
+
+    let addresses: Vec<u64> = sections.iter().map(|section| section.address).collect();
+    let base = |section: u16| addresses[section as usize];
+    for relocation in &machine_code.relocations {
+        let symbol = symbols.iter().find(|symbol| symbol.name == relocation.symbol && symbol.section != 0)
+            .ok_or_else(|| format!("undefined symbol \"{}\"", relocation.symbol))?;
+        let target = base(symbol.section) + symbol.value;
+        relocate(&mut machine_code.code, relocation, target as i64, base(TEXT) as i64);
+    }
+    let address_of = |label: &str| symbols.iter().find(|symbol| symbol.name == label && symbol.section != 0).map(|symbol| (base(symbol.section) + symbol.value) as i64);
+    for reference in &assembly.data_references {
+        let value = reference.value(&address_of).ok_or_else(|| format!("undefined symbol in \"{}\"", reference.symbol))?;
+        let (section, offset) = data_location(assembly, reference.address);
+        if section == BSS {
+            return Err(format!("{} can not be stored in .bss", reference.symbol));
+        }
+        sections[section as usize].data[offset as usize..offset as usize + reference.size].copy_from_slice(&reference.bytes(value));
+    }
+    sections[TEXT as usize].data = machine_code.code;
+
+    let text_end = sections[RODATA as usize].offset + sections[RODATA as usize].size;
+    let data = &sections[DATA as usize];
+    let program_headers = [
+        [(PF_R | PF_X) as u64, 0, TEXT_BASE, text_end, text_end, PAGE_SIZE],
+        [(PF_R | PF_W) as u64, data.offset, data.address, data.size, bss_address + sections[BSS as usize].size - data.address, PAGE_SIZE]
+    ];
+    add_symbol_table(&mut sections, &symbols, &base);
+    let bss_offset = sections[BSS as usize].offset;
+    layout(&mut sections[BSS as usize..], bss_offset);
+    write_file(path, ET_EXEC, base(TEXT) + machine_code.addresses[start], elf_flags(compressed), &program_headers, sections)
+}
@@ -1,11 +1,50 @@
-use std::{collections::HashMap, cmp::min};
-use std::io::{self, Write};
+use std::cmp::min;
+use std::io::{self, BufRead, Write};
 
-use crate::{memory::Memory, registers::Registers};
+use crate::builtins;
+use crate::data;
+use crate::memory::{Memory, MemoryAccess};
+use crate::registers::{RegisterValues, Registers};
+
+// A system call made by an ecall instruction, recorded for tracing.
+#[derive(Clone, Debug)]
+pub struct Syscall {
+    pub number: i64,
+    pub arguments: [i64; 3],
+    pub result: i64
+}
+
+// Everything a single instruction did, collected while tracking is enabled.
+pub struct Step {
+    pub pc: usize,
+    pub reads: RegisterValues,
+    pub writes: RegisterValues,
+    pub accesses: Vec<MemoryAccess>,
+    pub syscall: Option<Syscall>
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CallKind {
+    Call,
+    Return,
+    Other
+}
 
 pub struct Evaluator {
     pub registers: Registers, 
     pub memory: Memory, 
+    // Where the read and write syscalls get their data from and send it to.
+    pub input: Box<dyn BufRead>,
+    pub output: Box<dyn Write>,
+    // The syscall done by the last ecall, taken by the tracer.
+    pub last_syscall: Option<Syscall>,
+    // How far eip moves past the current instruction, 1 when eip is a program index and the
+    // byte size of the instruction when it is fetched from memory.
+    pub instruction_size: i64,
+    // Set by the exit syscalls, the program ends after the instruction.
+    pub exit_code: Option<i64>,
+    // Whether calls to undefined functions run the builtin with that name.
+    pub builtins: bool,
     verbose: bool
 }
 
@@ -14,16 +53,69 @@ impl Evaluator {
         Evaluator {
             memory: Memory::new(verbose),
             registers: Registers::new(),
+            input: Box::new(io::BufReader::new(io::stdin())),
+            output: Box::new(io::stdout()),
+            last_syscall: None,
+            instruction_size: 1,
+            exit_code: None,
+            builtins: false,
             verbose
         }
     }
 
+    // Record register and memory accesses so they can be collected with take_step.
+    pub fn set_tracking(&mut self, tracking: bool) {
+        self.registers.tracking = tracking;
+        self.memory.tracking = tracking;
+    }
+
+    // Collect what the instruction at pc did since the previous call.
+    pub fn take_step(&mut self, pc: usize) -> Step {
+        let (reads, writes) = self.registers.take_accesses();
+        Step {
+            pc,
+            reads,
+            writes,
+            accesses: self.memory.take_accesses(),
+            syscall: self.last_syscall.take()
+        }
+    }
+
+    // Whether an instruction calls a function (jumps and links ra) or returns to ra.
+    pub fn call_kind(instruction: &str) -> CallKind {
+        let (instruction_name, params) = instruction.split_once(' ').unwrap_or((instruction, ""));
+        let link = params.split(',').next().unwrap_or("").trim();
+        let target = params.rsplit(',').next().unwrap_or("").trim();
+        match instruction_name {
+            // Builtins return right away.
+            "jal" if target.parse::<i64>().is_err() => CallKind::Other,
+            "jal" | "jalr" | "call" if link == "ra" => CallKind::Call,
+            "ret" => CallKind::Return,
+            "jr" if link == "ra" => CallKind::Return,
+            _ => CallKind::Other
+        }
+    }
+
+    // Whether an instruction is a conditional branch (beq, bnez, bltu, ...).
+    pub fn is_branch(instruction: &str) -> bool {
+        let instruction_name = instruction.split_once(' ').map_or(instruction, |split| split.0);
+        matches!(instruction_name, "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" | "ble" | "bgt" | "bleu" | "bgtu"
+            | "beqz" | "bnez" | "blez" | "bgez" | "bltz" | "bgtz")
+    }
+
+    // Program index a conditional branch jumps to when it is taken.
+    pub fn branch_target(instruction: &str) -> Option<usize> {
+        if !Self::is_branch(instruction) {
+            return None;
+        }
+        instruction.rsplit(',').next()?.trim().parse().ok()
+    }
+
     pub fn evaluate(&mut self, instruction: &str) -> Result<(), String> {
         let mut instruction_name = instruction;
         let mut params_string = "";
         let s = instruction.split_once(" ");
-        if s != None {
-            let split = s.unwrap();
+        if let Some(split) = s {
             instruction_name = split.0;
             params_string = split.1;
         }
@@ -32,28 +124,35 @@ impl Evaluator {
     
         match instruction_name {
             "mv" => self.registers[params[0]] = self.registers[params[1].trim()],
-            "ld" =>self.registers[params[0]] = self.memory.load_from(Self::parse_memory_location(&self.registers, params[1].trim())),
+            "ld" =>self.registers[params[0]] = self.memory.load(Self::parse_memory_location(&self.registers, params[1].trim()), 8),
             //"lw" => variables[params[0]] = (memory.load_from(parse_memory_location(variables, params[1].trim())) as i32) as i64,
             "lw" => {
-                let address = Self::parse_memory_location(&self.registers, params[1].trim()) as usize;
-                let mut value: [u8; 4] = Default::default();
-                value.copy_from_slice(&self.memory[address..address + 4]);
-               self.registers[params[0]] = i32::from_le_bytes(value) as i64
+                let address = Self::parse_memory_location(&self.registers, params[1].trim());
+               self.registers[params[0]] = self.memory.load(address, 4) as i32 as i64
             },
-            "lwu" =>self.registers[params[0]] = (self.memory.load_from(Self::parse_memory_location(&self.registers, params[1].trim())) as u32) as i64,
+            "lwu" =>self.registers[params[0]] = self.memory.load(Self::parse_memory_location(&self.registers, params[1].trim()), 4),
             "lbu" => {
-                let address = Self::parse_memory_location(&self.registers, params[1].trim()) as usize;
-               self.registers[params[0]] = (self.memory[address] as i64) & 0xff; //Remove sign extension
+                let address = Self::parse_memory_location(&self.registers, params[1].trim());
+               self.registers[params[0]] = self.memory.load(address, 1); //Remove sign extension
             },
             "lb" => {
-                let address = Self::parse_memory_location(&self.registers, params[1].trim()) as usize;
-               self.registers[params[0]] = self.memory[address] as i64; //Sign extend to i64
+                let address = Self::parse_memory_location(&self.registers, params[1].trim());
+               self.registers[params[0]] = self.memory.load(address, 1) as i8 as i64;
+            },
+            "lh" => {
+                let address = Self::parse_memory_location(&self.registers, params[1].trim());
+               self.registers[params[0]] = self.memory.load(address, 2) as i16 as i64;
             },
             "lhu" => {
-                let address = Self::parse_memory_location(&self.registers, params[1].trim()) as usize;
-               self.registers[params[0]] = ((self.memory[address] as u64 | (self.memory[address+1] as u64) << 8) as i64) & 0xffff; //Remove sign extension
+                let address = Self::parse_memory_location(&self.registers, params[1].trim());
+               self.registers[params[0]] = self.memory.load(address, 2); //Remove sign extension
             },
             "li" | "lla" =>self.registers[params[0]] = Self::parse_immediate(params[1].trim()),
+            "lui" => self.registers[params[0]] = (Self::parse_immediate(params[1].trim()) << 12) as i32 as i64,
+            "auipc" => {
+                let upper = (Self::parse_immediate(params[1].trim()) << 12) as i32 as i64;
+                self.registers[params[0]] = self.registers["eip"] + upper;
+            },
             "sd" => self.memory.store_to(Self::parse_memory_location(&self.registers, params[1].trim()),self.registers[params[0]], 8),
             "sw" => self.memory.store_to(Self::parse_memory_location(&self.registers, params[1].trim()),self.registers[params[0]], 4),
             "sh" => self.memory.store_to(Self::parse_memory_location(&self.registers, params[1].trim()),self.registers[params[0]], 2),
@@ -63,59 +162,143 @@ impl Evaluator {
             "add" | "addw" => {
                 let a =self.registers[params[1].trim()];
                 let b =self.registers[params[2].trim()];
-               self.registers[params[0]] = a + b;
+               self.registers[params[0]] = Self::word_result(instruction_name, a.wrapping_add(b));
             },
             "addi" | "addiw" => {
                 let a =self.registers[params[1].trim()];
                 let b = params[2].trim().parse::<i64>().unwrap();
-               self.registers[params[0]] = a + b;
+               self.registers[params[0]] = Self::word_result(instruction_name, a.wrapping_add(b));
             },
             "sub" | "subw" => {
                 let a =self.registers[params[1].trim()];
                 let b =self.registers[params[2].trim()];
-                let result = a - b;
-               self.registers[params[0]] = result;
+                let result = a.wrapping_sub(b);
+               self.registers[params[0]] = Self::word_result(instruction_name, result);
             },
             "mul" | "mulw" => {
                 let a =self.registers[params[1].trim()];
                 let b =self.registers[params[2].trim()];
-               self.registers[params[0]] = a * b;
+               self.registers[params[0]] = Self::word_result(instruction_name, a.wrapping_mul(b));
             },
-            "divw" => {
+            "mulh" | "mulhsu" | "mulhu" => {
+                let a =self.registers[params[1].trim()];
+                let b =self.registers[params[2].trim()];
+                let product = match instruction_name {
+                    "mulh" => (a as i128) * (b as i128),
+                    "mulhsu" => (a as i128) * (b as u64 as i128),
+                    _ => ((a as u64 as u128) * (b as u64 as u128)) as i128
+                };
+               self.registers[params[0]] = (product >> 64) as i64;
+            },
+            // Division by zero does not trap, the quotient is all ones and the remainder the dividend.
+            "div" | "divu" | "rem" | "remu" | "divw" | "divuw" | "remw" | "remuw" => {
                 let a =self.registers[params[1].trim()];
                 let b =self.registers[params[2].trim()];
-               self.registers[params[0]] = a / b;
+                let result = match instruction_name {
+                    "div" if b == 0 => -1,
+                    "div" => a.wrapping_div(b),
+                    "rem" if b == 0 => a,
+                    "rem" => a.wrapping_rem(b),
+                    "divu" => (a as u64).checked_div(b as u64).map_or(-1, |quotient| quotient as i64),
+                    "remu" => (a as u64).checked_rem(b as u64).map_or(a, |remainder| remainder as i64),
+                    "divw" if b as i32 == 0 => -1,
+                    "divw" => (a as i32).wrapping_div(b as i32) as i64,
+                    "remw" if b as i32 == 0 => a as i32 as i64,
+                    "remw" => (a as i32).wrapping_rem(b as i32) as i64,
+                    "divuw" => (a as u32).checked_div(b as u32).map_or(-1, |quotient| quotient as i32 as i64),
+                    _ => (a as u32).checked_rem(b as u32).map_or(a as i32 as i64, |remainder| remainder as i32 as i64)
+                };
+               self.registers[params[0]] = result;
             },
-            "negw" => {
+            "neg" | "negw" => {
                 let a =self.registers[params[1].trim()];
-               self.registers[params[0]] = -a; //subw rd, x0, rs
+               self.registers[params[0]] = Self::word_result(instruction_name, a.wrapping_neg()); //subw rd, x0, rs
+            },
+            "not" => {
+                let a = self.registers[params[1].trim()];
+                self.registers[params[0]] = !a;
+            },
+            "seqz" | "snez" | "sltz" | "sgtz" => {
+                let a = self.registers[params[1].trim()];
+                let result = match instruction_name {
+                    "seqz" => a == 0,
+                    "snez" => a != 0,
+                    "sltz" => a < 0,
+                    _ => a > 0
+                };
+                self.registers[params[0]] = result as i64;
+            },
+            "slt" | "sltu" | "slti" | "sltiu" => {
+                let a = self.registers[params[1].trim()];
+                let b = if instruction_name == "slti" || instruction_name == "sltiu" {
+                    Self::parse_immediate(params[2].trim())
+                } else {
+                    self.registers[params[2].trim()]
+                };
+                let result = if instruction_name.ends_with('u') { (a as u64) < (b as u64) } else { a < b };
+                self.registers[params[0]] = result as i64;
+            },
+            "xor" => {
+                let a = self.registers[params[1].trim()];
+                let b = self.registers[params[2].trim()];
+                self.registers[params[0]] = a ^ b;
+            },
+            "xori" => {
+                let a = self.registers[params[1].trim()];
+                let b = Self::parse_immediate(params[2].trim());
+                self.registers[params[0]] = a ^ b;
             },
+            "fence" | "fence.tso" | "fence.i" => (),
             "nop" => (),
             "j" => {
-                let jump_pos;
-                if self.registers.has_register(params[0]) {
-                    jump_pos =self.registers[params[0].trim()];
+                let jump_pos = if self.registers.has_register(params[0]) {
+                    self.registers[params[0].trim()]
                 }
                 else {
-                    jump_pos = params[0].trim().parse().expect("Expected number!");
-                }
-               self.registers["eip"] = jump_pos - 1;
+                    params[0].trim().parse().expect("Expected number!")
+                };
+               self.registers["eip"] = jump_pos - self.instruction_size;
             },
             "jr" => {
                 let jump_pos =self.registers[params[0].trim()];
-               self.registers["eip"] = jump_pos - 1;
+               self.registers["eip"] = jump_pos - self.instruction_size;
             }
+            // "jalr rs", "jalr rd,offset(rs)" or "jalr rd,rs,offset".
+            "jalr" => {
+                let (link, jump_pos) = match params.len() {
+                    1 => ("ra", self.registers[params[0].trim()]),
+                    2 => (params[0], Self::parse_memory_location(&self.registers, params[1].trim())),
+                    _ => (params[0], self.registers[params[1].trim()] + Self::parse_immediate(params[2].trim()))
+                };
+               self.registers[link] =self.registers["eip"] + self.instruction_size;
+               self.registers["eip"] = (jump_pos & !1) - self.instruction_size;
+            },
             "jal" => {
-                let jump_pos: i64 = params[params.len()-1].trim().parse().expect("Expected address!");
-               self.registers[params[0]] =self.registers["eip"] + 1;
-               self.registers["eip"] = jump_pos - 1;
+                let link = if params.len() == 2 { params[0].trim() } else { "ra" };
+               self.registers[link] =self.registers["eip"] + self.instruction_size;
+                let target = params[params.len()-1].trim();
+                match target.parse::<i64>() {
+                    Ok(jump_pos) => self.registers["eip"] = jump_pos - self.instruction_size,
+                    Err(_) => self.call_builtin(target)?
+                }
+            },
+            // A jump to a function that returns to the caller's ra.
+            "tail" => {
+                let target = params[0].trim();
+                match target.parse::<i64>() {
+                    Ok(jump_pos) => self.registers["eip"] = jump_pos - self.instruction_size,
+                    Err(_) => {
+                        self.call_builtin(target)?;
+                        self.registers["eip"] = self.registers["ra"] - self.instruction_size;
+                    }
+                }
             },
             "bne" => {
                 let a =self.registers[params[0].trim()];
                 let b =self.registers[params[1].trim()];
                 if a != b {
                     let jump_pos: i64 = params[2].trim().parse().expect("Expected address!");
-                   self.registers["eip"] = jump_pos - 1;
+                   self.registers["eip"] = jump_pos - self.instruction_size;
                 }
             },
             "beq" => {
@@ -123,7 +306,7 @@ impl Evaluator {
                 let b =self.registers[params[1].trim()];
                 if a == b {
                     let jump_pos: i64 = params[2].trim().parse().expect("Expected address!");
-                   self.registers["eip"] = jump_pos - 1;
+                   self.registers["eip"] = jump_pos - self.instruction_size;
                 }
             },
             "ble" => {
@@ -131,7 +314,7 @@ impl Evaluator {
                 let b =self.registers[params[1].trim()];
                 if a <= b {
                     let jump_pos: i64 = params[2].trim().parse().expect("Expected address!");
-                   self.registers["eip"] = jump_pos - 1;
+                   self.registers["eip"] = jump_pos - self.instruction_size;
                 }
             },
             "blt" => {
@@ -139,14 +322,34 @@ impl Evaluator {
                 let b =self.registers[params[1].trim()];
                 if a < b {
                     let jump_pos: i64 = params[2].trim().parse().expect("Expected address!");
-                   self.registers["eip"] = jump_pos - 1;
+                   self.registers["eip"] = jump_pos - self.instruction_size;
+                }
+            },
+            "beqz" | "blez" | "bltz" => {
+                let a =self.registers[params[0].trim()];
+                let taken = match instruction_name {
+                    "beqz" => a == 0,
+                    "blez" => a <= 0,
+                    _ => a < 0
+                };
+                if taken {
+                    let jump_pos: i64 = params[1].trim().parse().expect("Expected address!");
+                   self.registers["eip"] = jump_pos - self.instruction_size;
+                }
+            },
+            "bgeu" | "bleu" => {
+                let a =self.registers[params[0].trim()] as u64;
+                let b =self.registers[params[1].trim()] as u64;
+                if (instruction_name == "bgeu" && a >= b) || (instruction_name == "bleu" && a <= b) {
+                    let jump_pos: i64 = params[2].trim().parse().expect("Expected address!");
+                   self.registers["eip"] = jump_pos - self.instruction_size;
                 }
             },
             "bnez" => {
                 let a =self.registers[params[0].trim()];
                 if a != 0 {
                     let jump_pos: i64 = params[1].trim().parse().expect("Expected address!");
-                   self.registers["eip"] = jump_pos - 1;
+                   self.registers["eip"] = jump_pos - self.instruction_size;
                 }
             },
             "bge" => {
@@ -154,21 +357,21 @@ impl Evaluator {
                 let b =self.registers[params[1].trim()];
                 if a >= b {
                     let jump_pos: i64 = params[2].trim().parse().expect("Expected address!");
-                   self.registers["eip"] = jump_pos - 1;
+                   self.registers["eip"] = jump_pos - self.instruction_size;
                 }
             },
             "bgtz" => {
                 let a =self.registers[params[0].trim()];
                 if a > 0 {
                     let jump_pos: i64 = params[1].trim().parse().expect("Expected address!");
-                   self.registers["eip"] = jump_pos - 1;
+                   self.registers["eip"] = jump_pos - self.instruction_size;
                 }
             },
             "bgez" => {
                 let a =self.registers[params[0].trim()];
                 if a >= 0 {
                     let jump_pos: i64 = params[1].trim().parse().expect("Expected address!");
-                   self.registers["eip"] = jump_pos - 1;
+                   self.registers["eip"] = jump_pos - self.instruction_size;
                 }
             },
             "bgtu" => {
@@ -176,7 +379,7 @@ impl Evaluator {
                 let b =self.registers[params[1].trim()] as u64;
                 if a > b {
                     let jump_pos: i64 = params[2].trim().parse().expect("Expected address!");
-                   self.registers["eip"] = jump_pos - 1;
+                   self.registers["eip"] = jump_pos - self.instruction_size;
                 }
             },
             "bgt" => {
@@ -184,7 +387,7 @@ impl Evaluator {
                 let b =self.registers[params[1].trim()];
                 if a > b {
                     let jump_pos: i64 = params[2].trim().parse().expect("Expected address!");
-                   self.registers["eip"] = jump_pos - 1;
+                   self.registers["eip"] = jump_pos - self.instruction_size;
                 }
             },
             "bltu" => {
@@ -192,26 +395,31 @@ impl Evaluator {
                 let b =self.registers[params[1].trim()] as u64;
                 if a < b {
                     let jump_pos: i64 = params[2].trim().parse().expect("Expected address!");
-                   self.registers["eip"] = jump_pos - 1;
+                   self.registers["eip"] = jump_pos - self.instruction_size;
                 }
             },
             "ret" => {
-               self.registers["eip"] =self.registers["ra"] - 1;
-            },
-            "slli" | "slliw" => {
-                let a =self.registers[params[1].trim()] as u64;
-                let b = Self::parse_immediate(params[2].trim()) & 0b11111;
-               self.registers[params[0]] = (a << b) as i64;
-            },
-            "srli" | "slriw" => {
-                let a =self.registers[params[1].trim()] as u64;
-                let b = Self::parse_immediate(params[2].trim()) & 0b11111;
-               self.registers[params[0]] = a.checked_shr(b as u32).unwrap_or(0) as i64;
+               self.registers["eip"] =self.registers["ra"] - self.instruction_size;
             },
-            "sraiw" => {
+            // Shifts by an immediate or a register, the word variants shift the lower 32 bits.
+            "slli" | "srli" | "srai" | "sll" | "srl" | "sra" | "slliw" | "srliw" | "slriw" | "sraiw" | "sllw" | "srlw" | "sraw" => {
                 let a =self.registers[params[1].trim()];
-                let b = Self::parse_immediate(params[2].trim()) & 0b11111;
-               self.registers[params[0]] = a.checked_shr(b as u32).unwrap_or(0);
+                let b = if self.registers.has_register(params[2].trim()) {
+                    self.registers[params[2].trim()]
+                } else {
+                    Self::parse_immediate(params[2].trim())
+                };
+                let word = instruction_name.ends_with('w');
+                let shift = (b & if word { 0b11111 } else { 0b111111 }) as u32;
+                let result = match (&instruction_name[..3], word) {
+                    ("sll", false) => a << shift,
+                    ("srl", false) => ((a as u64) >> shift) as i64,
+                    ("sra", false) => a >> shift,
+                    ("sll", true) => ((a as i32) << shift) as i64,
+                    ("srl", true) | ("slr", true) => ((a as u32) >> shift) as i32 as i64,
+                    _ => ((a as i32) >> shift) as i64
+                };
+               self.registers[params[0]] = result;
             },
             "or" => {
                 let a =self.registers[params[1].trim()];
@@ -233,19 +441,16 @@ impl Evaluator {
                 let b = Self::parse_immediate(params[2].trim());
                 self.registers[params[0]] = a & b;
             },
-            "remw" => {
-                let a = self.registers[params[1].trim()];
-                let b = self.registers[params[2].trim()];
-                self.registers[params[0]] = a % b;
-            },
             "sext.w" => {
                 let a = self.registers[params[1].trim()];
-                self.registers[params[0]] = a;
+                self.registers[params[0]] = a as i32 as i64;
             },
             "ecall" => {
                 let syscall_nr = self.registers["a7"];
+                let arguments = [self.registers["a0"], self.registers["a1"], self.registers["a2"]];
+                // 3, 4 and 45 are the numbers compiled programs use, the others are the Linux ones.
                 match syscall_nr {
-                    3 => {
+                    3 | 63 => {
                         let fd = self.registers["a0"];
                         let buf = self.registers["a1"];
                         let count = self.registers["a2"];
@@ -256,7 +461,7 @@ impl Evaluator {
                         }
                         
                         let mut input = String::new();
-                        std::io::stdin().read_line(&mut input).unwrap();
+                        self.input.read_line(&mut input).unwrap();
                         let max = min(count as usize, input.len());
                         for (i, c) in input.chars().enumerate() {
                             if i >= max {
@@ -266,7 +471,7 @@ impl Evaluator {
                         }
                         self.registers["a0"] = max as i64;
                     }
-                    4 => {
+                    4 | 64 => {
                         let fd = self.registers["a0"];
                         let buf = self.registers["a1"];
                         let count = self.registers["a2"];
@@ -278,9 +483,12 @@ impl Evaluator {
     
                         for i in buf..buf+count {
                             let c = self.memory[i as usize] as char;
-                            print!("{}", c);
+                            write!(self.output, "{}", c).unwrap();
+                        }
+                        self.output.flush().unwrap();
+                        if syscall_nr == 64 {
+                            self.registers["a0"] = count;
                         }
-                        io::stdout().flush().unwrap();
                     },
                     45 => {
                         let addr = self.registers["a0"] as usize;
@@ -289,50 +497,63 @@ impl Evaluator {
                             print!("syscall: brk(*addr = {:#x})", addr);
                             println!("\x1b[0m");
                         }
-                        self.memory.program_break = addr as usize;
+                        self.memory.program_break = addr;
                         self.memory.heap_memory.resize(addr, 0);
                     },
+                    // Linux brk only moves the break up to the stack and always returns the current break.
+                    214 => {
+                        let addr = self.registers["a0"] as usize;
+                        if self.verbose {
+                            print!("\x1b[34m");
+                            print!("syscall: brk(*addr = {:#x})", addr);
+                            println!("\x1b[0m");
+                        }
+                        if addr >= self.memory.program_break && addr <= self.memory.virtual_memory_size - self.memory.stack_memory.len() {
+                            self.memory.program_break = addr;
+                            self.memory.heap_memory.resize(addr, 0);
+                        }
+                        self.registers["a0"] = self.memory.program_break as i64;
+                    },
+                    93 | 94 => {
+                        if self.verbose {
+                            print!("\x1b[34m");
+                            print!("syscall: exit(status = {})", self.registers["a0"]);
+                            println!("\x1b[0m");
+                        }
+                        self.exit_code = Some(self.registers["a0"]);
+                    },
                     _ => return Err(format!("Syscall {} is not supported", syscall_nr))
                 }
+                self.last_syscall = Some(Syscall { number: syscall_nr, arguments, result: self.registers["a0"] });
             }
             _ => return Err(format!("Instruction \"{}\" does not exist!", instruction_name))
         }
     
-        self.registers["eip"] += 1;
+        // Writes to the zero register are discarded.
+        self.registers.variables.insert("zero".to_owned(), 0);
+        self.registers["eip"] += self.instruction_size;
         Ok(())
     }
-    
-    fn parse_immediate(str: &str) -> i64 {
-        if str.starts_with("0x") {
-            return Self::parse_hex_string(str);
+
+    fn call_builtin(&mut self, name: &str) -> Result<(), String> {
+        if !self.builtins {
+            return Err(format!("\"{}\" is not defined, run with --builtins to call the builtin", name));
+        }
+        if self.verbose {
+            print!("\x1b[34m");
+            print!("builtin: {}(a0 = {}, a1 = {}, a2 = {})", name, self.registers["a0"], self.registers["a1"], self.registers["a2"]);
+            println!("\x1b[0m");
         }
-        str.parse::<i64>().expect("Expected a numeric value!")
+        builtins::call(self, name)
+    }
+
+    // The W variants of instructions compute on the lower 32 bits and sign extend the result.
+    fn word_result(instruction_name: &str, value: i64) -> i64 {
+        if instruction_name.ends_with('w') { value as i32 as i64 } else { value }
     }
     
-    fn parse_hex_string(str: &str) -> i64 {
-        let str = &str[2..];
-        let mut total = 0;
-        let mut map: HashMap<char, i64> = HashMap::new();
-        map.insert('0', 0);
-        map.insert('1', 1);
-        map.insert('2', 2);
-        map.insert('3', 3);
-        map.insert('4', 4);
-        map.insert('5', 5);
-        map.insert('6', 6);
-        map.insert('7', 7);
-        map.insert('8', 8);
-        map.insert('9', 9);
-        map.insert('a', 10);
-        map.insert('b', 11);
-        map.insert('c', 12);
-        map.insert('d', 13);
-        map.insert('e', 14);
-        map.insert('f', 15);
-        for (i, c) in str.char_indices() {
-            total |= map[&c] << (str.len()-i-1)*4;
-        }
-        total
+    fn parse_immediate(str: &str) -> i64 {
+        data::parse_integer(str).expect("Expected a numeric value!")
     }
     
     // Take a String and parse it into a index for a block of memory.
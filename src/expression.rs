@@ -0,0 +1,195 @@
+// Integer expressions in operands and directives like `BUF_SIZE*4`, `(1 << 12) - 1` or `'a' + 1`,
+// with the operators of C and their precedence. Symbols are looked up by the caller, so the same
+// evaluator works for .equ constants, labels and the location counter ".".
+use std::fmt;
+
+use crate::data;
+
+fn is_word_char(char: char) -> bool {
+    char.is_ascii_alphanumeric() || matches!(char, '_' | '.' | '$')
+}
+
+// Replace the words of text (labels, numbers and names) for which replace returns Some.
+pub fn replace_words(text: &str, mut replace: impl FnMut(&str) -> Option<String>) -> String {
+    let mut replaced = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(is_word_char) {
+        replaced.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest.find(|char| !is_word_char(char)).unwrap_or(rest.len());
+        replaced.push_str(&replace(&rest[..end]).unwrap_or_else(|| rest[..end].to_owned()));
+        rest = &rest[end..];
+    }
+    replaced.push_str(rest);
+    replaced
+}
+
+#[derive(Clone)]
+enum Token {
+    Number(i64),
+    Symbol(String),
+    Operator(&'static str)
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Number(value) => write!(f, "{}", value),
+            Token::Symbol(name) => write!(f, "{}", name),
+            Token::Operator(operator) => write!(f, "{}", operator)
+        }
+    }
+}
+
+// Split operands at the commas that are not in a character or string literal like ','.
+pub fn split_operands(operands: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, char) in operands.char_indices() {
+        match (char, quote) {
+            (_, Some(_)) if escaped => escaped = false,
+            ('\\', Some(_)) => escaped = true,
+            (_, Some(open)) if char == open => quote = None,
+            ('"', None) | ('\'', None) => quote = Some(char),
+            (',', None) => {
+                parts.push(&operands[start..i]);
+                start = i + 1;
+            },
+            _ => {}
+        }
+    }
+    parts.push(&operands[start..]);
+    parts
+}
+
+// The operators, two character ones first so "<<" is not read as "<".
+const OPERATORS: [&str; 22] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||",
+    "<", ">", "+", "-", "*", "/", "%", "&", "|", "^", "~", "!", "(", ")"
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(char) = rest.chars().next() {
+        if char == '\'' {
+            // A character literal, which may be an escape sequence like '\n' or '\''.
+            let mut escaped = false;
+            let end = rest.char_indices().skip(1).find(|&(_, char)| {
+                let end = char == '\'' && !escaped;
+                escaped = char == '\\' && !escaped;
+                end
+            }).map(|(i, _)| i + 1).ok_or_else(|| format!("the character literal in \"{}\" is not terminated", text))?;
+            let value = data::parse_integer(&rest[..end]).ok_or_else(|| format!("\"{}\" is not a character", &rest[..end]))?;
+            tokens.push(Token::Number(value));
+            rest = &rest[end..];
+        } else if is_word_char(char) {
+            let end = rest.find(|char| !is_word_char(char)).unwrap_or(rest.len());
+            let word = &rest[..end];
+            if char.is_ascii_digit() {
+                tokens.push(Token::Number(data::parse_integer(word).ok_or_else(|| format!("\"{}\" is not a number", word))?));
+            } else {
+                tokens.push(Token::Symbol(word.to_owned()));
+            }
+            rest = &rest[end..];
+        } else {
+            let operator = OPERATORS.iter().find(|operator| rest.starts_with(**operator)).ok_or_else(|| format!("unexpected \"{}\" in \"{}\"", char, text))?;
+            tokens.push(Token::Operator(operator));
+            rest = &rest[operator.len()..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+// The binary operators from the lowest to the highest precedence, comparisons give 1 or 0.
+const PRECEDENCE: [&[&str]; 10] = [
+    &["||"], &["&&"], &["|"], &["^"], &["&"], &["==", "!="], &["<", ">", "<=", ">="], &["<<", ">>"], &["+", "-"], &["*", "/", "%"]
+];
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    symbol: &'a dyn Fn(&str) -> Option<i64>
+}
+
+impl Parser<'_> {
+    fn next_operator(&self, operators: &[&str]) -> Option<&'static str> {
+        match self.tokens.get(self.position) {
+            Some(Token::Operator(operator)) if operators.contains(operator) => Some(operator),
+            _ => None
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut value = self.binary(level + 1)?;
+        while let Some(operator) = self.next_operator(PRECEDENCE[level]) {
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            value = match operator {
+                "||" => (value != 0 || right != 0) as i64,
+                "&&" => (value != 0 && right != 0) as i64,
+                "==" => (value == right) as i64,
+                "!=" => (value != right) as i64,
+                "<" => (value < right) as i64,
+                ">" => (value > right) as i64,
+                "<=" => (value <= right) as i64,
+                ">=" => (value >= right) as i64,
+                "|" => value | right,
+                "^" => value ^ right,
+                "&" => value & right,
+                "<<" => value.wrapping_shl(right as u32),
+                ">>" => value.wrapping_shr(right as u32),
+                "+" => value.wrapping_add(right),
+                "-" => value.wrapping_sub(right),
+                "*" => value.wrapping_mul(right),
+                _ if right == 0 => return Err("division by zero".to_owned()),
+                "/" => value.wrapping_div(right),
+                _ => value.wrapping_rem(right)
+            };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        let token = self.tokens.get(self.position).cloned().ok_or("expected a value at the end of the expression")?;
+        self.position += 1;
+        match token {
+            Token::Number(value) => Ok(value),
+            Token::Symbol(name) => (self.symbol)(&name).ok_or_else(|| format!("undefined symbol \"{}\"", name)),
+            Token::Operator("-") => Ok(self.unary()?.wrapping_neg()),
+            Token::Operator("+") => self.unary(),
+            Token::Operator("~") => Ok(!self.unary()?),
+            Token::Operator("!") => Ok((self.unary()? == 0) as i64),
+            Token::Operator("(") => {
+                let value = self.binary(0)?;
+                match self.tokens.get(self.position) {
+                    Some(Token::Operator(")")) => {
+                        self.position += 1;
+                        Ok(value)
+                    },
+                    _ => Err("expected \")\"".to_owned())
+                }
+            },
+            Token::Operator(operator) => Err(format!("expected a value, found \"{}\"", operator))
+        }
+    }
+}
+
+// The value of an expression, symbol gives the values of the names in it.
+pub fn evaluate(text: &str, symbol: &dyn Fn(&str) -> Option<i64>) -> Result<i64, String> {
+    let mut parser = Parser { tokens: tokenize(text)?, position: 0, symbol };
+    if parser.tokens.is_empty() {
+        return Err("expected an expression".to_owned());
+    }
+    let value = parser.binary(0)?;
+    match parser.tokens.get(parser.position) {
+        Some(token) => Err(format!("unexpected \"{}\" in \"{}\"", token, text.trim())),
+        None => Ok(value)
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+
+use crate::assembler::MachineCode;
+use crate::decoder;
+use crate::disassembler::{self, Line, Symbols};
+use crate::line_table::{LineInfo, LineTable};
+use crate::memory::{Memory, Segment};
+
+// Where the machine code is loaded when instructions are fetched from memory.
+pub const TEXT_BASE: u64 = 0x10000;
+// The return address of the entry point, returning there ends the program.
+pub const EXIT_ADDRESS: u64 = 0;
+
+// Gives every instruction address that was fetched a program index, so everything that works on
+// program indices (tracer, profiler, coverage, pipeline, ...) keeps working when eip is an address.
+pub struct CodeMap {
+    // The decoded instruction at each index as it is evaluated, with jump targets as addresses.
+    decoded: Vec<String>,
+    addresses: Vec<u64>,
+    sizes: Vec<u64>,
+    index_of: HashMap<u64, usize>,
+    // Line information by start address, an entry applies up to the start of the next one.
+    lines: Vec<(u64, LineInfo)>
+}
+
+impl CodeMap {
+    pub fn new(mut lines: Vec<(u64, LineInfo)>) -> Self {
+        lines.sort_by_key(|(address, _)| *address);
+        CodeMap { decoded: Vec::new(), addresses: Vec::new(), sizes: Vec::new(), index_of: HashMap::new(), lines }
+    }
+
+    // Map the machine code of an assembled program as an executable segment at TEXT_BASE and decode it.
+    pub fn load(memory: &mut Memory, machine_code: &MachineCode, line_table: &LineTable) -> Self {
+        memory.map_segment(Segment { start: TEXT_BASE as usize, data: machine_code.code.clone(), writable: false, executable: true });
+        let lines = line_table.iter().enumerate().map(|(i, info)| (TEXT_BASE + machine_code.addresses[i], info.clone())).collect();
+        let mut code_map = CodeMap::new(lines);
+        code_map.decode_range(memory, TEXT_BASE, TEXT_BASE + machine_code.code.len() as u64);
+        code_map
+    }
+
+    // Decode the code between start and end ahead of time, so that jump targets already have an
+    // index. What can not be decoded (padding, data) is skipped.
+    pub fn decode_range(&mut self, memory: &Memory, start: u64, end: u64) {
+        let mut address = start;
+        while address < end {
+            match memory.fetch(address as usize).and_then(|word| decoder::decode(word, address)) {
+                Ok(decoded) => {
+                    self.insert(address, decoded.text, decoded.size);
+                    address += decoded.size;
+                },
+                Err(_) => address += 2
+            }
+        }
+    }
+
+    fn insert(&mut self, address: u64, text: String, size: u64) -> usize {
+        let index = self.decoded.len();
+        self.index_of.insert(address, index);
+        self.decoded.push(text);
+        self.addresses.push(address);
+        self.sizes.push(size);
+        index
+    }
+
+    // The decoded program with jump targets as program indices and its line table.
+    pub fn program(&self) -> (Vec<String>, LineTable) {
+        let program = self.decoded.iter().map(|text| self.with_indices(text)).collect();
+        let mut line_table = LineTable::default();
+        for &address in &self.addresses {
+            line_table.push(self.line_info(address));
+        }
+        (program, line_table)
+    }
+
+    pub fn index(&self, address: u64) -> Option<usize> {
+        self.index_of.get(&address).copied()
+    }
+
+    pub fn decoded(&self, index: usize) -> &str {
+        &self.decoded[index]
+    }
+
+    pub fn size(&self, index: usize) -> u64 {
+        self.sizes[index]
+    }
+
+    // The instruction at index as a line of the disassembly listing.
+    pub fn disassembly_line(&self, memory: &Memory, index: usize) -> Line {
+        let (address, size) = (self.addresses[index], self.sizes[index]);
+        let word = memory.fetch(address as usize).unwrap_or(0);
+        Line { address, raw: if size == 2 { word & 0xffff } else { word }, size, text: Some(self.decoded[index].clone()) }
+    }
+
+    // The labels of the line information as symbols, each at the first address it applies to.
+    pub fn symbols(&self) -> Symbols {
+        let mut symbols = Vec::new();
+        for (i, (address, info)) in self.lines.iter().enumerate() {
+            if let Some(label) = &info.label {
+                if i == 0 || self.lines[i - 1].1.label.as_ref() != Some(label) {
+                    symbols.push((*address, label.clone()));
+                }
+            }
+        }
+        Symbols::new(symbols)
+    }
+
+    // Fetch and decode the instruction at address and return its index. Instructions at new
+    // addresses are added to the program, changed instructions replace the old ones.
+    pub fn fetch(&mut self, memory: &Memory, address: u64, program: &mut Vec<String>, line_table: &mut LineTable) -> Result<usize, String> {
+        let decoded = decoder::decode(memory.fetch(address as usize)?, address)?;
+        let index = match self.index(address) {
+            Some(index) if self.decoded[index] == decoded.text => return Ok(index),
+            Some(index) => index,
+            None => {
+                program.push(String::new());
+                line_table.push(self.line_info(address));
+                self.insert(address, String::new(), 0)
+            }
+        };
+        program[index] = self.with_indices(&decoded.text);
+        self.decoded[index] = decoded.text;
+        self.sizes[index] = decoded.size;
+        Ok(index)
+    }
+
+    // Where the code at address came from, code before the first entry gets a line without a label.
+    fn line_info(&self, address: u64) -> LineInfo {
+        match self.lines.partition_point(|(start, _)| *start <= address) {
+            0 => LineInfo { file: format!("{:#x}", address), line: 0, column: 0, label: None, source: None },
+            entry => self.lines[entry - 1].1.clone()
+        }
+    }
+
+    // The instruction with its jump or branch target address replaced by the target's index.
+    // Targets that were never decoded keep their address.
+    fn with_indices(&self, text: &str) -> String {
+        match disassembler::jump_target(text).and_then(|(at, target)| Some((at, self.index(target)?))) {
+            Some((at, index)) => format!("{}{}", &text[..at], index),
+            None => text.to_owned()
+        }
+    }
+}
@@ -0,0 +1,221 @@
+// Precompiled images, the compiled program as written to output.s, which can be run without
+// compiling it again. An image is a text file with these sections:
+//
+//   header:                  "key = value" lines
+//   version = 4              missing in images of iasm versions before the format had one (0)
+//   entry_point = 12         program index of the first instruction
+//   code_references = 3 7    indices of lla instructions that take the address of code
+//   code:                    one instruction per line, labels resolved to program indices
+//   lines:                   per instruction "line column label file", label is - if there is none
+//   sources:                 per instruction compiled from C with .loc "index line column file", the
+//                            position in the C source
+//   operands:                per %hi, %lo, %pcrel_hi or %pcrel_lo operand "index kind symbol addend
+//                            value code|data auipc", see AddressOperand
+//   data:                    per section "section name address size align", followed by a line
+//                            with its bytes in hexadecimal, except for .bss which is all zeros
+//
+// Version 0 images have no code_references and no lines section, images before version 3 no
+// operands section and images before version 4 no sources section. Versions 0 and 1 have a
+// data_segment_size header field and a stack: section with the data bytes at the top of memory
+// instead of the data: section.
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+
+use crate::assembler::{AddressOperand, RelocationKind};
+use crate::compile::{self, Assembly, DataSection, DATA_SECTIONS};
+use crate::line_table::{LineInfo, SourcePosition};
+use crate::memory::Memory;
+
+pub const VERSION: u32 = 4;
+
+// Whether the file starts like an image instead of assembly.
+pub fn is_image(path: &str) -> bool {
+    let mut start = [0; 8];
+    File::open(path).and_then(|mut file| file.read_exact(&mut start)).is_ok() && &start == b"header:\n"
+}
+
+pub fn write(path: &str, assembly: &Assembly) -> io::Result<()> {
+    let mut output = BufWriter::new(File::create(path)?);
+    writeln!(output, "header:")?;
+    writeln!(output, "version = {}", VERSION)?;
+    writeln!(output, "entry_point = {}", assembly.entry_point)?;
+    let mut code_references: Vec<usize> = assembly.code_references.iter().copied().collect();
+    code_references.sort();
+    writeln!(output, "code_references = {}", code_references.iter().map(usize::to_string).collect::<Vec<_>>().join(" "))?;
+    writeln!(output, "code:")?;
+    for line in &assembly.program {
+        writeln!(output, "{}", line)?;
+    }
+    writeln!(output, "lines:")?;
+    for info in assembly.line_table.iter() {
+        writeln!(output, "{} {} {} {}", info.line, info.column, info.label.as_deref().unwrap_or("-"), info.file)?;
+    }
+    writeln!(output, "sources:")?;
+    for (index, info) in assembly.line_table.iter().enumerate() {
+        if let Some(source) = &info.source {
+            writeln!(output, "{} {} {} {}", index, source.line, source.column, source.file)?;
+        }
+    }
+    writeln!(output, "operands:")?;
+    let mut operands: Vec<(&usize, &AddressOperand)> = assembly.address_operands.iter().collect();
+    operands.sort_by_key(|(index, _)| **index);
+    for (index, operand) in operands {
+        let target = if operand.code { "code" } else { "data" };
+        writeln!(output, "{} {:?} {} {} {} {} {}", index, operand.kind, operand.symbol, operand.addend, operand.value, target, operand.auipc)?;
+    }
+    writeln!(output, "data:")?;
+    for section in assembly.sections.iter().filter(|section| !section.data.is_empty()) {
+        writeln!(output, "section {} {:#x} {} {}", section.name, section.address, section.data.len(), section.align)?;
+        if section.name != ".bss" {
+            for byte in &section.data {
+                write!(output, "{:#04x} ", byte)?;
+            }
+            writeln!(output)?;
+        }
+    }
+    output.flush()
+}
+
+fn parse<T: std::str::FromStr>(value: &str, number: usize) -> Result<T, String> {
+    value.parse().map_err(|_| format!("line {}: \"{}\" is not a valid number", number, value))
+}
+
+fn parse_bytes(line: &str, number: usize) -> Result<Vec<u8>, String> {
+    line.split_whitespace().map(|byte| u8::from_str_radix(byte.trim_start_matches("0x"), 16).map_err(|_| format!("line {}: \"{}\" is not a byte", number, byte))).collect()
+}
+
+// An "index kind symbol addend value code|data auipc" line of the operands: section.
+fn parse_operand(line: &str, number: usize) -> Result<(usize, AddressOperand), String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [index, kind, symbol, addend, value, target, auipc] = fields[..] else {
+        return Err(format!("line {}: expected \"index kind symbol addend value code|data auipc\"", number));
+    };
+    let kind = RelocationKind::ALL.iter().copied().find(|known| format!("{:?}", known) == kind).ok_or_else(|| format!("line {}: unknown operand kind \"{}\"", number, kind))?;
+    let code = match target {
+        "code" => true,
+        "data" => false,
+        _ => return Err(format!("line {}: expected code or data, found \"{}\"", number, target))
+    };
+    let operand = AddressOperand { kind, symbol: symbol.to_owned(), addend: parse(addend, number)?, value: parse(value, number)?, code, auipc: parse(auipc, number)? };
+    Ok((parse(index, number)?, operand))
+}
+
+// The section of a "section name address size align" line of the data: section.
+fn parse_section(line: &str, number: usize) -> Result<DataSection, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let ["section", name, address, size, align] = fields[..] else {
+        return Err(format!("line {}: expected \"section name address size align\"", number));
+    };
+    let name = DATA_SECTIONS.iter().map(|(name, _)| *name).find(|known| *known == name).ok_or_else(|| format!("line {}: unknown section \"{}\"", number, name))?;
+    let address = usize::from_str_radix(address.trim_start_matches("0x"), 16).map_err(|_| format!("line {}: \"{}\" is not an address", number, address))?;
+    let size = parse(size, number)?;
+    compile::check_section_size(name, size).map_err(|err| format!("line {}: {}", number, err))?;
+    Ok(DataSection { name, address, data: vec![0; size], align: parse(align, number)? })
+}
+
+// Read an image and map its data sections, old images have their data at the top of memory.
+pub fn read(path: &str, memory: &mut Memory) -> Result<Assembly, String> {
+    let content = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let mut assembly = Assembly::default();
+    let mut section = "";
+    let mut code_lines = Vec::new();
+    let mut lines = Vec::new();
+    let mut sources = Vec::new();
+    let mut data = Vec::new();
+    // Whether the bytes of the last section of data: have been read.
+    let mut filled = true;
+    for (i, line) in content.lines().enumerate() {
+        let number = i + 1;
+        if matches!(line, "header:" | "code:" | "lines:" | "sources:" | "operands:" | "stack:" | "data:") {
+            section = line;
+            continue;
+        }
+        match section {
+            "header:" => {
+                let (key, value) = line.split_once(" = ").ok_or_else(|| format!("line {}: expected \"key = value\"", number))?;
+                match key {
+                    "version" => {
+                        let version: u32 = parse(value, number)?;
+                        if version > VERSION {
+                            return Err(format!("the image has version {}, this iasm reads up to version {}", version, VERSION));
+                        }
+                    },
+                    "entry_point" => assembly.entry_point = parse(value, number)?,
+                    "data_segment_size" => assembly.data_segment_size = parse(value, number)?,
+                    "code_references" => for index in value.split_whitespace() {
+                        assembly.code_references.insert(parse(index, number)?);
+                    },
+                    _ => return Err(format!("line {}: unknown header field \"{}\"", number, key))
+                }
+            },
+            "code:" => {
+                assembly.program.push(line.to_owned());
+                code_lines.push(number);
+            },
+            "lines:" => {
+                let fields: Vec<&str> = line.splitn(4, ' ').collect();
+                let [line, column, label, file] = fields[..] else {
+                    return Err(format!("line {}: expected \"line column label file\"", number));
+                };
+                let label = Some(label.to_owned()).filter(|label| label != "-");
+                lines.push(LineInfo { file: file.to_owned(), line: parse(line, number)?, column: parse(column, number)?, label, source: None });
+            },
+            "sources:" => {
+                let fields: Vec<&str> = line.splitn(4, ' ').collect();
+                let [index, line, column, file] = fields[..] else {
+                    return Err(format!("line {}: expected \"index line column file\"", number));
+                };
+                let source = SourcePosition { file: file.to_owned(), line: parse(line, number)?, column: parse(column, number)? };
+                sources.push((number, parse::<usize>(index, number)?, source));
+            },
+            "operands:" => {
+                let (index, operand) = parse_operand(line, number)?;
+                assembly.address_operands.insert(index, operand);
+            },
+            "stack:" => data.extend(parse_bytes(line, number)?),
+            "data:" if line.starts_with("section ") => {
+                let section = parse_section(line, number)?;
+                filled = section.name == ".bss";
+                assembly.sections.push(section);
+            },
+            "data:" => match assembly.sections.last_mut() {
+                Some(section) if !filled => {
+                    let bytes = parse_bytes(line, number)?;
+                    if bytes.len() != section.data.len() {
+                        return Err(format!("line {}: {} has {} bytes, the section line says {}", number, section.name, bytes.len(), section.data.len()));
+                    }
+                    section.data = bytes;
+                    filled = true;
+                },
+                _ => return Err(format!("line {}: expected \"section name address size align\"", number))
+            },
+            _ => return Err(format!("line {}: expected a section like \"header:\"", number))
+        }
+    }
+
+    // Without line information, errors point into the image itself.
+    if lines.is_empty() {
+        lines = code_lines.iter().map(|&line| LineInfo { file: path.to_owned(), line, column: 1, label: None, source: None }).collect();
+    }
+    if lines.len() != assembly.program.len() {
+        return Err(format!("the image has {} instructions but line information for {}", assembly.program.len(), lines.len()));
+    }
+    for (number, index, source) in sources {
+        let info = lines.get_mut(index).ok_or_else(|| format!("line {}: there is no instruction {}", number, index))?;
+        info.source = Some(source);
+    }
+    for info in lines {
+        assembly.line_table.push(info);
+    }
+    if data.len() != assembly.data_segment_size {
+        return Err(format!("the image has {} bytes of data, the header says {}", data.len(), assembly.data_segment_size));
+    }
+    if !filled {
+        return Err(format!("the bytes of {} are missing", assembly.sections.last().unwrap().name));
+    }
+    let data_start = memory.virtual_memory_size.checked_sub(data.len())
+        .ok_or_else(|| format!("the image has {} bytes of data, more than the {} bytes of memory", data.len(), memory.virtual_memory_size))?;
+    memory.write_bytes(data_start, &data);
+    compile::map_sections(&assembly.sections, memory);
+    Ok(assembly)
+}
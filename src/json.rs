@@ -0,0 +1,269 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+// Minimal JSON value, just enough for the debug adapter protocol messages.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>)
+}
+
+impl Json {
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(fields.into_iter().map(|(k, v)| (k.to_owned(), v)).collect())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Number(n) => Some(*n as i64),
+            _ => None
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match self {
+            Json::Array(a) => Some(a),
+            _ => None
+        }
+    }
+
+    pub fn parse(str: &str) -> Result<Json, String> {
+        let mut chars = str.chars().peekable();
+        let value = Self::parse_value(&mut chars)?;
+        Self::skip_whitespace(&mut chars);
+        match chars.next() {
+            None => Ok(value),
+            Some(c) => Err(format!("Unexpected character '{}' after JSON value", c))
+        }
+    }
+
+    fn skip_whitespace(chars: &mut Peekable<Chars>) {
+        while let Some(c) = chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            chars.next();
+        }
+    }
+
+    fn expect_word(chars: &mut Peekable<Chars>, word: &str, value: Json) -> Result<Json, String> {
+        for expected in word.chars() {
+            if chars.next() != Some(expected) {
+                return Err(format!("Expected \"{}\"", word));
+            }
+        }
+        Ok(value)
+    }
+
+    fn parse_value(chars: &mut Peekable<Chars>) -> Result<Json, String> {
+        Self::skip_whitespace(chars);
+        match chars.peek() {
+            Some('n') => Self::expect_word(chars, "null", Json::Null),
+            Some('t') => Self::expect_word(chars, "true", Json::Bool(true)),
+            Some('f') => Self::expect_word(chars, "false", Json::Bool(false)),
+            Some('"') => Ok(Json::String(Self::parse_string(chars)?)),
+            Some('[') => {
+                chars.next();
+                let mut items = Vec::new();
+                Self::skip_whitespace(chars);
+                if chars.peek() == Some(&']') {
+                    chars.next();
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(Self::parse_value(chars)?);
+                    Self::skip_whitespace(chars);
+                    match chars.next() {
+                        Some(',') => continue,
+                        Some(']') => return Ok(Json::Array(items)),
+                        _ => return Err("Expected ',' or ']' in array".to_owned())
+                    }
+                }
+            },
+            Some('{') => {
+                chars.next();
+                let mut fields = Vec::new();
+                Self::skip_whitespace(chars);
+                if chars.peek() == Some(&'}') {
+                    chars.next();
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    Self::skip_whitespace(chars);
+                    let key = Self::parse_string(chars)?;
+                    Self::skip_whitespace(chars);
+                    if chars.next() != Some(':') {
+                        return Err("Expected ':' in object".to_owned());
+                    }
+                    fields.push((key, Self::parse_value(chars)?));
+                    Self::skip_whitespace(chars);
+                    match chars.next() {
+                        Some(',') => continue,
+                        Some('}') => return Ok(Json::Object(fields)),
+                        _ => return Err("Expected ',' or '}' in object".to_owned())
+                    }
+                }
+            },
+            Some(c) if *c == '-' || c.is_ascii_digit() => {
+                let mut number = String::new();
+                while let Some(c) = chars.peek() {
+                    if !(c.is_ascii_digit() || "+-.eE".contains(*c)) {
+                        break;
+                    }
+                    number.push(*c);
+                    chars.next();
+                }
+                number.parse::<f64>().map(Json::Number).map_err(|_| format!("Invalid number \"{}\"", number))
+            },
+            Some(c) => Err(format!("Unexpected character '{}'", c)),
+            None => Err("Unexpected end of input".to_owned())
+        }
+    }
+
+    fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+        if chars.next() != Some('"') {
+            return Err("Expected string".to_owned());
+        }
+        let mut str = String::new();
+        loop {
+            match chars.next() {
+                Some('"') => return Ok(str),
+                Some('\\') => match chars.next() {
+                    Some('n') => str.push('\n'),
+                    Some('t') => str.push('\t'),
+                    Some('r') => str.push('\r'),
+                    Some('b') => str.push('\u{8}'),
+                    Some('f') => str.push('\u{c}'),
+                    Some('u') => {
+                        let hex: String = chars.take(4).collect();
+                        let code = u32::from_str_radix(&hex, 16).map_err(|_| format!("Invalid escape \"\\u{}\"", hex))?;
+                        str.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                    },
+                    Some(c) => str.push(c),
+                    None => return Err("Unterminated string".to_owned())
+                },
+                Some(c) => str.push(c),
+                None => return Err("Unterminated string".to_owned())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => {
+                if n.fract() == 0.0 && n.abs() < 1e15 {
+                    write!(f, "{}", *n as i64)
+                } else {
+                    write!(f, "{}", n)
+                }
+            },
+            Json::String(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\t' => write!(f, "\\t")?,
+                        c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                        c => write!(f, "{}", c)?
+                    }
+                }
+                write!(f, "\"")
+            },
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", Json::String(key.clone()), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_owned())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::String(s)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl From<i32> for Json {
+    fn from(n: i32) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(n: i64) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(items: Vec<Json>) -> Self {
+        Json::Array(items)
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::ops::Index;
+
+// A position in the original (C) source file, taken from the .loc debug directives.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourcePosition {
+    pub file: String,
+    pub line: usize,
+    pub column: usize
+}
+
+// Where an instruction in the compiled program came from.
+#[derive(Clone, Debug)]
+pub struct LineInfo {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    // The closest label above the instruction that is not a local (".") label, usually the function.
+    pub label: Option<String>,
+    pub source: Option<SourcePosition>
+}
+
+impl fmt::Display for LineInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.source {
+            Some(source) => {
+                write!(f, "{}:{}:{} (", source.file, source.line, source.column)?;
+                if let Some(label) = &self.label {
+                    write!(f, "{}, ", label)?;
+                }
+                write!(f, "{}:{})", self.file, self.line)
+            },
+            None => {
+                write!(f, "{}:{}:{}", self.file, self.line, self.column)?;
+                if let Some(label) = &self.label {
+                    write!(f, " ({})", label)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Maps every program index back to the line in the input file it was compiled from.
+#[derive(Clone, Debug, Default)]
+pub struct LineTable {
+    entries: Vec<LineInfo>
+}
+
+impl LineTable {
+    pub fn push(&mut self, info: LineInfo) {
+        self.entries.push(info);
+    }
+
+    pub fn get(&self, index: usize) -> Option<&LineInfo> {
+        self.entries.get(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &LineInfo> {
+        self.entries.iter()
+    }
+
+    // The lines of every C source file referenced by the table that could be read.
+    pub fn read_sources(&self) -> HashMap<String, Vec<String>> {
+        let mut sources = HashMap::new();
+        for source in self.entries.iter().filter_map(|info| info.source.as_ref()) {
+            if !sources.contains_key(&source.file) {
+                if let Ok(content) = fs::read_to_string(&source.file) {
+                    sources.insert(source.file.clone(), content.lines().map(str::to_owned).collect());
+                }
+            }
+        }
+        sources
+    }
+
+    // Name of the function an instruction belongs to, falls back to the program index.
+    pub fn function_name(&self, index: usize) -> String {
+        match self.get(index).and_then(|info| info.label.as_ref()) {
+            Some(label) => label.clone(),
+            None => format!("{:#x}", index)
+        }
+    }
+}
+
+impl Index<usize> for LineTable {
+    type Output = LineInfo;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.entries[index]
+    }
+}
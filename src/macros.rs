@@ -0,0 +1,350 @@
+// The front end of compile, which expands macros (.macro/.endm), repetitions (.rept, .irp and
+// .irpc, ended by .endr), conditional assembly (.if and its variants, .else, .endif) and
+// .include into plain lines, like the GNU assembler does before it assembles anything.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use crate::compile;
+use crate::expression;
+
+// How deep macro calls, repetitions and includes can be nested, to stop runaway recursion.
+const MAX_DEPTH: usize = 100;
+
+// An expanded line with where it came from. The lines of a macro are on the line of the call.
+#[derive(Clone)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: usize,
+    pub text: String
+}
+
+impl SourceLine {
+    fn error(&self, message: &str) -> String {
+        format!("{}:{}: {}", self.file, self.line, message)
+    }
+}
+
+#[derive(Clone)]
+struct Parameter {
+    name: String,
+    default: Option<String>,
+    required: bool,
+    // Takes all the remaining arguments.
+    vararg: bool
+}
+
+#[derive(Clone)]
+struct Macro {
+    parameters: Vec<Parameter>,
+    body: Vec<SourceLine>
+}
+
+// An .if whose .endif was not reached yet.
+struct Condition {
+    // Where the .if is, for the error when it has no .endif.
+    start: usize,
+    // Whether the lines of the current branch are assembled.
+    active: bool,
+    // Whether one of the branches so far was assembled, so the ones after it are not.
+    taken: bool
+}
+
+#[derive(Default)]
+struct Expander {
+    macros: HashMap<String, Macro>,
+    // Constants and labels defined so far, for .if and .ifdef.
+    constants: HashMap<String, i64>,
+    labels: HashSet<String>,
+    // How many macros were called so far, for \@.
+    calls: usize,
+    output: Vec<SourceLine>
+}
+
+// The first word of a line, a directive or a macro name, and the rest without the comment.
+fn split_directive(text: &str) -> (&str, &str) {
+    let text = text[..compile::comment_start(text)].trim();
+    match text.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (text, "")
+    }
+}
+
+// The index of the line that ends the block that starts after lines[start - 1], nested blocks
+// that the same line would end are skipped.
+fn block_end(lines: &[SourceLine], start: usize, open: &[&str], close: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, source) in lines.iter().enumerate().skip(start) {
+        let (directive, _) = split_directive(compile::split_labels(source.text.trim()).1);
+        if open.contains(&directive) {
+            depth += 1;
+        } else if directive == close {
+            if depth == 0 {
+                return Some(i);
+            }
+            depth -= 1;
+        }
+    }
+    None
+}
+
+// Split macro arguments at the commas that are not in quotes or parentheses, or at spaces when
+// there are no such commas, as in "push a0, a1" and "push a0 a1".
+fn split_arguments(text: &str) -> Vec<String> {
+    let split = |separator: fn(char) -> bool| {
+        let mut arguments = Vec::new();
+        let mut current = String::new();
+        let (mut quoted, mut depth) = (false, 0);
+        for char in text.chars() {
+            match char {
+                '"' => quoted = !quoted,
+                '(' if !quoted => depth += 1,
+                ')' if !quoted => depth -= 1,
+                _ if separator(char) && !quoted && depth == 0 => {
+                    arguments.push(current.trim().to_owned());
+                    current.clear();
+                    continue;
+                },
+                _ => {}
+            }
+            current.push(char);
+        }
+        arguments.push(current.trim().to_owned());
+        arguments
+    };
+    if text.trim().is_empty() {
+        return Vec::new();
+    }
+    let arguments = split(|char| char == ',');
+    if arguments.len() > 1 {
+        arguments
+    } else {
+        split(char::is_whitespace).into_iter().filter(|argument| !argument.is_empty()).collect()
+    }
+}
+
+// Replace \name in the lines by the values of the names, \() by nothing and \@ by the number of
+// the macro call.
+fn substitute(lines: &[SourceLine], values: &HashMap<String, String>, call: usize) -> Vec<SourceLine> {
+    lines.iter().map(|source| {
+        let mut text = String::new();
+        let mut rest = &source.text[..];
+        while let Some(start) = rest.find('\\') {
+            text.push_str(&rest[..start]);
+            rest = &rest[start + 1..];
+            let end = rest.find(|char: char| !(char.is_ascii_alphanumeric() || char == '_')).unwrap_or(rest.len());
+            if let Some(value) = values.get(&rest[..end]) {
+                text.push_str(value);
+                rest = &rest[end..];
+            } else if let Some(after) = rest.strip_prefix("()") {
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix('@') {
+                text.push_str(&call.to_string());
+                rest = after;
+            } else {
+                text.push('\\');
+            }
+        }
+        text.push_str(rest);
+        SourceLine { text, ..source.clone() }
+    }).collect()
+}
+
+// The name and parameters of `.macro name a, b=1, c:req, rest:vararg`.
+fn parse_macro(operands: &str) -> Result<(String, Vec<Parameter>), String> {
+    let (name, parameters) = operands.split_once([' ', '\t', ',']).unwrap_or((operands, ""));
+    if name.is_empty() {
+        return Err("expected the name of the macro after .macro".to_owned());
+    }
+    let parameters = split_arguments(parameters).into_iter().map(|parameter| {
+        let (parameter, default) = match parameter.split_once('=') {
+            Some((parameter, default)) => (parameter.trim(), Some(default.trim().to_owned())),
+            None => (&parameter[..], None)
+        };
+        let (name, qualifier) = parameter.split_once(':').unwrap_or((parameter, ""));
+        match qualifier {
+            "" | "req" | "vararg" => Ok(Parameter { name: name.trim().to_owned(), default, required: qualifier == "req", vararg: qualifier == "vararg" }),
+            _ => Err(format!("unknown parameter qualifier \":{}\"", qualifier))
+        }
+    }).collect::<Result<_, String>>()?;
+    Ok((name.to_owned(), parameters))
+}
+
+// The values of a macro's parameters for the arguments of a call, by position or as name=value.
+fn bind_arguments(name: &str, parameters: &[Parameter], arguments: &str) -> Result<HashMap<String, String>, String> {
+    let mut values: HashMap<String, String> = HashMap::new();
+    let arguments = split_arguments(arguments);
+    let mut position = 0;
+    for (i, argument) in arguments.iter().enumerate() {
+        let named = argument.split_once('=').filter(|(name, _)| parameters.iter().any(|parameter| parameter.name == name.trim()));
+        if let Some((parameter, value)) = named {
+            values.insert(parameter.trim().to_owned(), value.trim().to_owned());
+            continue;
+        }
+        let parameter = parameters.get(position).ok_or_else(|| format!("too many arguments for the macro {}, it takes {}", name, parameters.len()))?;
+        if parameter.vararg {
+            values.insert(parameter.name.clone(), arguments[i..].join(", "));
+            break;
+        }
+        // An empty argument takes the default.
+        position += 1;
+        if argument.is_empty() {
+            continue;
+        }
+        values.insert(parameter.name.clone(), argument.clone());
+    }
+    for parameter in parameters {
+        if values.contains_key(&parameter.name) {
+            continue;
+        }
+        if parameter.required {
+            return Err(format!("the macro {} needs a value for {}", name, parameter.name));
+        }
+        values.insert(parameter.name.clone(), parameter.default.clone().unwrap_or_default());
+    }
+    Ok(values)
+}
+
+impl Expander {
+    // Whether the condition of an .if variant holds.
+    fn condition(&self, directive: &str, operands: &str) -> Result<bool, String> {
+        let compare = || operands.split_once(',').map(|(a, b)| a.trim() == b.trim()).ok_or_else(|| format!("{} expects two strings separated by a comma", directive));
+        Ok(match directive {
+            ".if" | ".ifne" => self.evaluate(operands)? != 0,
+            ".ifeq" => self.evaluate(operands)? == 0,
+            ".ifdef" => self.is_defined(operands),
+            ".ifndef" | ".ifnotdef" => !self.is_defined(operands),
+            ".ifb" => operands.is_empty(),
+            ".ifnb" => !operands.is_empty(),
+            ".ifc" => compare()?,
+            _ => !compare()?
+        })
+    }
+
+    fn evaluate(&self, expression: &str) -> Result<i64, String> {
+        expression::evaluate(expression, &|name| self.constants.get(name).copied())
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.constants.contains_key(name) || self.labels.contains(name)
+    }
+
+    // Expand lines into output. Returns true when an .exitm ends the macro the lines are in.
+    fn expand(&mut self, lines: &[SourceLine], depth: usize) -> Result<bool, String> {
+        if depth > MAX_DEPTH {
+            let source = lines.first().map_or_else(String::new, |source| source.error(""));
+            return Err(format!("{}macros, repetitions and includes are nested more than {} deep", source, MAX_DEPTH));
+        }
+        let mut conditions: Vec<Condition> = Vec::new();
+        let mut i = 0;
+        while i < lines.len() {
+            let source = &lines[i];
+            let (labels, rest) = compile::split_labels(source.text.trim());
+            let (directive, operands) = split_directive(rest);
+            let active = conditions.iter().all(|condition| condition.active);
+            i += 1;
+            match directive {
+                ".if" | ".ifne" | ".ifeq" | ".ifdef" | ".ifndef" | ".ifnotdef" | ".ifb" | ".ifnb" | ".ifc" | ".ifnc" => {
+                    let holds = active && self.condition(directive, operands).map_err(|err| source.error(&err))?;
+                    conditions.push(Condition { start: i - 1, active: holds, taken: holds || !active });
+                    continue;
+                },
+                ".elseif" | ".else" => {
+                    let parent_active = conditions.len() < 2 || conditions[..conditions.len() - 1].iter().all(|condition| condition.active);
+                    let taken = conditions.last().ok_or_else(|| source.error(&format!("{} without .if", directive)))?.taken;
+                    let holds = !taken && (directive == ".else" || parent_active && self.condition(".if", operands).map_err(|err| source.error(&err))?);
+                    let condition = conditions.last_mut().unwrap();
+                    condition.active = holds;
+                    condition.taken |= holds;
+                    continue;
+                },
+                ".endif" => {
+                    conditions.pop().ok_or_else(|| source.error(".endif without .if"))?;
+                    continue;
+                },
+                _ if !active => continue,
+                _ => {}
+            }
+            let is_block = matches!(directive, ".macro" | ".rept" | ".irp" | ".irpc" | ".include") || self.macros.contains_key(directive);
+            if is_block && !labels.is_empty() {
+                self.output.push(SourceLine { text: format!("{}:", labels.join(": ")), ..source.clone() });
+            }
+            match directive {
+                ".macro" => {
+                    let (name, parameters) = parse_macro(operands).map_err(|err| source.error(&err))?;
+                    let end = block_end(lines, i, &[".macro"], ".endm").ok_or_else(|| source.error(&format!("the macro {} has no .endm", name)))?;
+                    self.macros.insert(name, Macro { parameters, body: lines[i..end].to_vec() });
+                    i = end + 1;
+                },
+                ".purgem" => {
+                    self.macros.remove(operands).ok_or_else(|| source.error(&format!("there is no macro {}", operands)))?;
+                },
+                ".rept" | ".irp" | ".irpc" => {
+                    let end = block_end(lines, i, &[".rept", ".irp", ".irpc"], ".endr").ok_or_else(|| source.error(&format!("{} has no .endr", directive)))?;
+                    let body = &lines[i..end];
+                    i = end + 1;
+                    // The values of the symbol of .irp and .irpc for each repetition, none for .rept.
+                    let repetitions: Vec<HashMap<String, String>> = if directive == ".rept" {
+                        let count = self.evaluate(operands).map_err(|err| source.error(&err))?;
+                        (0..count.max(0)).map(|_| HashMap::new()).collect()
+                    } else {
+                        let (symbol, values) = operands.split_once([',', ' ']).unwrap_or((operands, ""));
+                        let values: Vec<String> = match directive {
+                            ".irp" => split_arguments(values),
+                            _ => values.trim().chars().map(String::from).collect()
+                        };
+                        values.into_iter().map(|value| HashMap::from([(symbol.trim().to_owned(), value)])).collect()
+                    };
+                    for values in repetitions {
+                        if self.expand(&substitute(body, &values, self.calls), depth + 1)? {
+                            return Ok(true);
+                        }
+                    }
+                },
+                ".include" => {
+                    // Relative to the including file, or else to the working directory.
+                    let name = operands.trim_matches('"');
+                    let path = Path::new(&source.file).parent().unwrap_or_else(|| Path::new("")).join(name);
+                    let path = if path.exists() { path } else { Path::new(name).to_path_buf() };
+                    let content = fs::read_to_string(&path).map_err(|err| source.error(&format!("could not include \"{}\": {}", name, err)))?;
+                    let file = path.to_string_lossy().into_owned();
+                    let included: Vec<SourceLine> = content.split('\n').enumerate().map(|(i, text)| SourceLine { file: file.clone(), line: i + 1, text: text.to_owned() }).collect();
+                    self.expand(&included, depth + 1)?;
+                },
+                ".exitm" => return Ok(true),
+                ".endm" | ".endr" => return Err(source.error(&format!("{} without a block to end", directive))),
+                name if self.macros.contains_key(name) => {
+                    let Macro { parameters, body } = self.macros[name].clone();
+                    let values = bind_arguments(name, &parameters, operands).map_err(|err| source.error(&err))?;
+                    let body: Vec<SourceLine> = body.into_iter().map(|line| SourceLine { file: source.file.clone(), line: source.line, ..line }).collect();
+                    let call = self.calls;
+                    self.calls += 1;
+                    self.expand(&substitute(&body, &values, call), depth + 1)?;
+                },
+                _ => {
+                    // Remember what is defined for .if and .ifdef, compile defines it for real.
+                    self.labels.extend(labels.iter().map(|label| label.to_string()));
+                    if let Some((name, value)) = compile::parse_assignment(rest) {
+                        match self.evaluate(value) {
+                            Ok(value) => self.constants.insert(name.to_owned(), value),
+                            Err(_) => self.constants.remove(name)
+                        };
+                    }
+                    self.output.push(source.clone());
+                }
+            }
+        }
+        match conditions.first() {
+            Some(condition) => Err(lines[condition.start].error(".if without .endif")),
+            None => Ok(false)
+        }
+    }
+}
+
+// The lines of a file with everything expanded.
+pub fn expand(file: &str, content: &str) -> Result<Vec<SourceLine>, String> {
+    let lines: Vec<SourceLine> = content.split('\n').enumerate().map(|(i, text)| SourceLine { file: file.to_owned(), line: i + 1, text: text.to_owned() }).collect();
+    let mut expander = Expander::default();
+    expander.expand(&lines, 0)?;
+    Ok(expander.output)
+}
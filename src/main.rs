@@ -1,20 +1,52 @@
 extern crate unescape;
 
 use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
 use std::{fs, process};
 use std::time::Instant;
 use std::env;
-use crate::fs::File;
 
 use crate::memory::Memory;
-use crate::compile::compile_files;
+use crate::compile::{compile_files, Assembly};
 use crate::registers::Registers;
 use crate::evaluator::Evaluator;
+use crate::trace::{TraceFormat, Tracer};
+use crate::difftest::DiffTester;
+use crate::profiler::Profiler;
+use crate::coverage::Coverage;
+use crate::cache::{CacheConfig, CacheHierarchy};
+use crate::branch_predictor::BranchSimulator;
+use crate::pipeline::Pipeline;
+use crate::pipeline_diagram::PipelineDiagram;
+use crate::fetch::CodeMap;
+use crate::elf::Elf;
 
 mod compile;
+mod data;
 mod memory;
 mod registers;
 mod evaluator;
+mod json;
+mod dap;
+mod line_table;
+mod trace;
+mod difftest;
+mod profiler;
+mod coverage;
+mod cache;
+mod branch_predictor;
+mod pipeline;
+mod pipeline_diagram;
+mod assembler;
+mod decoder;
+mod disassembler;
+mod fetch;
+mod elf;
+mod elf_writer;
+mod image;
+mod expression;
+mod macros;
+mod builtins;
 
 fn prompt(message: &str) -> String {
     print!("{}", message);
@@ -29,7 +61,7 @@ fn prompt(message: &str) -> String {
 
 fn print_stack(registers: &Registers, memory: &Memory) {
     let stack_offset = (memory.virtual_memory_size - memory.stack_memory.len()) as i64;
-    for i in (((registers["sp"] - stack_offset)/8))..(memory.stack_memory.len()/8) as i64 {
+    for i in ((registers["sp"] - stack_offset)/8)..(memory.stack_memory.len()/8) as i64 {
         let address = (i*8 + stack_offset) as usize;
         print!("{:#04x} {:020}", i*8 + stack_offset, memory.load_from(i*8 + stack_offset));
         print!(" ");
@@ -50,81 +82,447 @@ fn print_stack(registers: &Registers, memory: &Memory) {
     }
 }
 
+fn option_value(options: &mut std::slice::Iter<String>, name: &str) -> String {
+    match options.next() {
+        Some(value) => value.clone(),
+        None => {
+            eprintln!("Expected a value after \"{}\"!", name);
+            process::exit(1);
+        }
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     let mut debug = false;
     let mut verbose = false;
-    let mut files = &args[1..];
     if args.len() < 2 {
         eprintln!("Expected at least one argument!");
         process::exit(1);
     }
 
-    if args.len() >= 3 {
-        match &args[args.len()-1][..] {
+    if args[1] == "disasm" {
+        disassembler::run(&args[2..]).unwrap_or_else(|err| {
+            eprintln!("\x1b[31mCould not disassemble: {}\x1b[0m", err);
+            process::exit(1);
+        });
+        return;
+    }
+
+    if args[1] == "--dap" {
+        dap::run();
+        return;
+    }
+
+    // Options of the form "--name value", everything else is an input file or the run mode.
+    let mut trace_file = None;
+    let mut trace_format = TraceFormat::JsonLines;
+    let mut difftest_file = None;
+    let mut difftest_start = None;
+    let mut profile = false;
+    let mut profile_memory = false;
+    let mut profile_folded_file = None;
+    let mut coverage_file = None;
+    let mut coverage_report_file = None;
+    let mut simulate_caches = false;
+    let mut predictor_names: Vec<String> = Vec::new();
+    let mut predictor_bits = 10;
+    let mut pipeline_predictor = None;
+    let mut diagram_window = None;
+    let mut print_diagram = false;
+    let mut diagram_html_file = None;
+    let mut binary_file = None;
+    let mut object_file = None;
+    let mut executable_file = None;
+    let mut compressed = false;
+    let mut listing = false;
+    let mut fetch = false;
+    let mut builtins = false;
+    let mut l1i_config = CacheConfig::new(1024, 2, 16);
+    let mut l1d_config = CacheConfig::new(1024, 2, 16);
+    let mut l2_config = Some(CacheConfig::new(8192, 4, 32));
+    let mut positional = Vec::new();
+    let mut options = args[1..].iter();
+    while let Some(arg) = options.next() {
+        match &arg[..] {
+            "--trace" => trace_file = Some(option_value(&mut options, arg)),
+            "--trace-format" => {
+                let name = option_value(&mut options, arg);
+                trace_format = TraceFormat::parse(&name).unwrap_or_else(|| {
+                    eprintln!("Unknown trace format \"{}\", expected \"json\" or \"binary\"!", name);
+                    process::exit(1);
+                });
+            },
+            "--difftest" => difftest_file = Some(option_value(&mut options, arg)),
+            "--difftest-start" => {
+                let address = option_value(&mut options, arg);
+                difftest_start = Some(u64::from_str_radix(address.trim_start_matches("0x"), 16).unwrap_or_else(|_| {
+                    eprintln!("Expected a hexadecimal address after \"{}\"!", arg);
+                    process::exit(1);
+                }));
+            },
+            "--profile" => profile = true,
+            "--profile-memory" => {
+                profile = true;
+                profile_memory = true;
+            },
+            "--profile-folded" => {
+                profile = true;
+                profile_folded_file = Some(option_value(&mut options, arg));
+            },
+            "--coverage" => coverage_file = Some(option_value(&mut options, arg)),
+            "--coverage-report" => coverage_report_file = Some(option_value(&mut options, arg)),
+            "--cache" => simulate_caches = true,
+            "--l1i" | "--l1d" | "--l2" => {
+                simulate_caches = true;
+                let spec = option_value(&mut options, arg);
+                if arg == "--l2" && spec == "none" {
+                    l2_config = None;
+                    continue;
+                }
+                let config = CacheConfig::parse(&spec).unwrap_or_else(|err| {
+                    eprintln!("Invalid cache configuration for \"{}\": {}!", arg, err);
+                    process::exit(1);
+                });
+                match &arg[..] {
+                    "--l1i" => l1i_config = config,
+                    "--l1d" => l1d_config = config,
+                    _ => l2_config = Some(config)
+                }
+            },
+            "--predictor" => {
+                let names = option_value(&mut options, arg);
+                for name in names.split(',') {
+                    if name == "all" {
+                        predictor_names.extend(branch_predictor::PREDICTOR_NAMES.iter().map(|name| name.to_string()));
+                    } else {
+                        predictor_names.push(name.to_owned());
+                    }
+                }
+            },
+            "--predictor-bits" => {
+                predictor_bits = option_value(&mut options, arg).parse().ok().filter(|bits| (1..=24).contains(bits)).unwrap_or_else(|| {
+                    eprintln!("Expected a table size between 1 and 24 bits after \"{}\"!", arg);
+                    process::exit(1);
+                });
+            },
+            "--pipeline" => pipeline_predictor = Some(pipeline_predictor.unwrap_or_else(|| "not-taken".to_owned())),
+            "--pipeline-predictor" => pipeline_predictor = Some(option_value(&mut options, arg)),
+            "--pipeline-diagram" | "--pipeline-html" => {
+                pipeline_predictor = Some(pipeline_predictor.unwrap_or_else(|| "not-taken".to_owned()));
+                if arg == "--pipeline-html" {
+                    diagram_html_file = Some(option_value(&mut options, arg));
+                    continue;
+                }
+                print_diagram = true;
+                let window = option_value(&mut options, arg);
+                let parsed = window.split_once(':').and_then(|(first, count)| Some((first.parse::<usize>().ok()?, count.parse::<usize>().ok()?)));
+                diagram_window = Some(parsed.unwrap_or_else(|| {
+                    eprintln!("Expected \"first:count\" after \"{}\"!", arg);
+                    process::exit(1);
+                }));
+            },
+            "--emit-binary" => binary_file = Some(option_value(&mut options, arg)),
+            "--emit-object" => object_file = Some(option_value(&mut options, arg)),
+            "--emit-executable" => executable_file = Some(option_value(&mut options, arg)),
+            "--compress" => compressed = true,
+            "--listing" => listing = true,
+            "--fetch" => fetch = true,
+            "--builtins" => builtins = true,
+            _ => positional.push(arg.clone())
+        }
+    }
+
+    let mut files = &positional[..];
+    if positional.len() >= 2 {
+        match &positional[positional.len()-1][..] {
             "debug" => {
                 debug = true;
                 verbose = true;
-                files = &args[1..args.len()-1];
+                files = &positional[..positional.len()-1];
             },
             "verbose" => {
                 verbose = true;
-                files = &args[1..args.len()-1];
+                files = &positional[..positional.len()-1];
             },
             _ => {}
         }
     }
 
+    if builtins && fetch {
+        eprintln!("\x1b[31mBuiltins are only available in the interpreter, not with \"--fetch\"\x1b[0m");
+        process::exit(1);
+    }
     let mut evaluator = Evaluator::new(verbose);
-    let (program, entry_point, data_segment_size) = compile_files(files, &mut evaluator.memory, verbose);
+    evaluator.builtins = builtins;
+    let mut program;
+    let mut line_table;
+    let mut code_map = None;
+    if let Some(path) = files.first().filter(|file| elf::is_elf(file)) {
+        // An executable runs from memory, its arguments are the remaining file names.
+        let executable = Elf::read(path).unwrap_or_else(|err| {
+            eprintln!("\x1b[31mCould not load \"{}\": {}\x1b[0m", path, err);
+            process::exit(1);
+        });
+        executable.load(&mut evaluator.memory).unwrap_or_else(|err| {
+            eprintln!("\x1b[31mCould not load \"{}\": {}\x1b[0m", path, err);
+            process::exit(1);
+        });
+        let mut map = CodeMap::new(executable.symbol_lines(path));
+        for (start, end) in executable.code_ranges() {
+            map.decode_range(&evaluator.memory, start, end);
+        }
+        (program, line_table) = map.program();
+        code_map = Some(map);
+        evaluator.registers["sp"] = executable.build_stack(&mut evaluator.memory, files) as i64;
+        evaluator.registers["eip"] = executable.entry as i64;
+        evaluator.registers["ra"] = fetch::EXIT_ADDRESS as i64;
+        println!("Loaded \"{}\", entry point {:#x}, {} symbols", path, executable.entry, executable.symbols.len());
+    } else {
+        let assembly = match files.first().filter(|file| image::is_image(file)) {
+            // A precompiled image skips the compile step.
+            Some(path) => {
+                if files.len() > 1 || object_file.is_some() || executable_file.is_some() {
+                    eprintln!("\x1b[31mAn image can not be combined with other files or written as an ELF file, use the assembly instead\x1b[0m");
+                    process::exit(1);
+                }
+                let assembly = image::read(path, &mut evaluator.memory).unwrap_or_else(|err| {
+                    eprintln!("\x1b[31mCould not load image \"{}\": {}\x1b[0m", path, err);
+                    process::exit(1);
+                });
+                println!("Loaded image \"{}\", {} instructions", path, assembly.program.len());
+                assembly
+            },
+            None => {
+                let assembly = compile_files(files, &mut evaluator.memory, verbose).unwrap_or_else(|errors| {
+                    for error in &errors {
+                        eprintln!("\x1b[31m{}\x1b[0m", error);
+                    }
+                    process::exit(1);
+                });
+                // Write compiled program to file
+                image::write("output.s", &assembly).unwrap_or_else(|err| {
+                    eprintln!("Could not write \"output.s\": {}", err);
+                    process::exit(1);
+                });
+                println!("Write finished");
+                assembly
+            }
+        };
+        let Assembly { entry_point, data_segment_size, ref code_references, ref address_operands, .. } = assembly;
+        program = assembly.program.clone();
+        line_table = assembly.line_table.clone();
+
+        evaluator.registers["eip"] = entry_point;
+        if let Some(path) = &object_file {
+            let size = elf_writer::write_object(path, &assembly, compressed).unwrap_or_else(|err| {
+                eprintln!("\x1b[31mCould not write object file \"{}\": {}\x1b[0m", path, err);
+                process::exit(1);
+            });
+            println!("Wrote {} bytes of object file to \"{}\"", size, path);
+        }
+        if let Some(path) = &executable_file {
+            let size = elf_writer::write_executable(path, &assembly, compressed).unwrap_or_else(|err| {
+                eprintln!("\x1b[31mCould not write executable \"{}\": {}\x1b[0m", path, err);
+                process::exit(1);
+            });
+            println!("Wrote {} bytes of executable to \"{}\"", size, path);
+        }
+        // Object files leave undefined symbols to the linker, running needs all of them.
+        let undefined: Vec<_> = assembly.external_references.iter().filter(|reference| !(builtins && reference.call && builtins::is_builtin(&reference.symbol))).collect();
+        if !undefined.is_empty() {
+            for reference in &undefined {
+                eprintln!("\x1b[31m{}\x1b[0m", reference);
+            }
+            if !builtins && undefined.iter().any(|reference| reference.call && builtins::is_builtin(&reference.symbol)) {
+                eprintln!("\x1b[31mRun with \"--builtins\" to call the functions of the C library iasm provides\x1b[0m");
+            }
+            process::exit(1);
+        }
+        if binary_file.is_some() || listing || fetch {
+            let machine_code = assembler::assemble(&program, code_references, address_operands, None, fetch::TEXT_BASE, compressed).unwrap_or_else(|(i, err)| {
+                eprintln!("\x1b[31mCould not encode {} at {}: {}\x1b[0m", program[i], line_table[i], err);
+                process::exit(1);
+            });
+            if listing {
+                machine_code.print_listing(&program);
+            }
+            if let Some(path) = &binary_file {
+                fs::write(path, &machine_code.code).unwrap_or_else(|err| {
+                    eprintln!("Could not write \"{}\": {}", path, err);
+                    process::exit(1);
+                });
+                println!("Wrote {} bytes of machine code to \"{}\"", machine_code.code.len(), path);
+            }
+            // Run the machine code from memory, eip becomes a byte address and returning from the
+            // entry point ends the program.
+            if fetch {
+                for (address, bytes) in assembly.data_reference_values(&|index| (fetch::TEXT_BASE + machine_code.addresses[index]) as i64) {
+                    evaluator.memory.write_bytes(address, &bytes);
+                }
+                let map = CodeMap::load(&mut evaluator.memory, &machine_code, &line_table);
+                (program, line_table) = map.program();
+                code_map = Some(map);
+                evaluator.registers["eip"] = (fetch::TEXT_BASE + machine_code.addresses[entry_point as usize]) as i64;
+                evaluator.registers["ra"] = fetch::EXIT_ADDRESS as i64;
+            }
+        }
     
-    // Write compiled program to file
-    let mut output = File::create("output.s").unwrap();
-    writeln!(output, "header:").unwrap();
-    writeln!(output, "entry_point = {}", entry_point).unwrap();
-    writeln!(output, "data_segment_size = {}", data_segment_size).unwrap();
-    writeln!(output, "code:").unwrap();
-    for line in &program {
-        writeln!(output, "{}", line).unwrap();
-    }
-    writeln!(output, "stack:").unwrap();
-    for i in 0..data_segment_size {
-        //write!(output, "{} ", memory[memory.virtual_memory_size - data_segment_size + i] as char).unwrap();
-        write!(output, "{:#04x} ", evaluator.memory[evaluator.memory.virtual_memory_size - data_segment_size + i]).unwrap();
+        evaluator.registers["sp"] = (evaluator.memory.virtual_memory_size - data_segment_size) as i64;
     }
-    
-    println!("Write finished");
-    
-    let digit_count = (program.len() -1).to_string().len();
 
-    evaluator.registers["sp"] = (evaluator.memory.virtual_memory_size - data_segment_size) as i64;
-    evaluator.registers["eip"] = entry_point;
+    let digit_count = program.len().saturating_sub(1).to_string().len();
+    let line_digit_count = line_table.iter().map(|info| info.line).max().unwrap_or(0).to_string().len();
+    let sources = if debug { line_table.read_sources() } else { Default::default() };
+    // Fetched code is listed like the disassembler does.
+    let symbols = code_map.as_ref().map(CodeMap::symbols).unwrap_or_default();
+
+    let mut tracer = trace_file.map(|path| Tracer::create(&path, trace_format).unwrap_or_else(|err| {
+        eprintln!("Could not create trace file \"{}\": {}", path, err);
+        process::exit(1);
+    }));
+    let mut difftester = difftest_file.map(|path| DiffTester::open(&path, difftest_start, &evaluator.memory).unwrap_or_else(|err| {
+        eprintln!("Could not read reference log \"{}\": {}", path, err);
+        process::exit(1);
+    }));
+    if let Some(difftester) = &mut difftester {
+        difftester.seed(&mut evaluator.registers);
+    }
+    let mut profiler = if profile { Some(Profiler::new()) } else { None };
+    let mut coverage = if coverage_file.is_some() || coverage_report_file.is_some() { Some(Coverage::new(program.len())) } else { None };
+    let mut caches = if simulate_caches { Some(CacheHierarchy::new(l1i_config, l1d_config, l2_config)) } else { None };
+    let mut branch_simulator = if predictor_names.is_empty() { None } else {
+        Some(BranchSimulator::new(predictor_names.iter().map(|name| branch_predictor::create(name, predictor_bits).unwrap_or_else(|| {
+            eprintln!("Unknown branch predictor \"{}\", expected one of {} or all!", name, branch_predictor::PREDICTOR_NAMES.join(", "));
+            process::exit(1);
+        })).collect()))
+    };
+    let mut pipeline = pipeline_predictor.map(|name| Pipeline::new(branch_predictor::create(&name, predictor_bits).unwrap_or_else(|| {
+        eprintln!("Unknown branch predictor \"{}\", expected one of {}!", name, branch_predictor::PREDICTOR_NAMES.join(", "));
+        process::exit(1);
+    })));
+    let mut diagram = if print_diagram || diagram_html_file.is_some() {
+        let (first, count) = diagram_window.unwrap_or((0, 20));
+        Some(PipelineDiagram::new(first, count))
+    } else {
+        None
+    };
+    let tracking = tracer.is_some() || difftester.is_some() || profile_memory || caches.is_some() || pipeline.is_some();
+    evaluator.set_tracking(tracking);
 
     let start = Instant::now();
     let mut ins_executed = 0;
     let mut eip = evaluator.registers["eip"]  as usize;
-    while eip < program.len() {
-        let ins = &program[eip][..];
+    loop {
+        // The program index of the instruction, eip itself when the program runs from the list.
+        let index = match &mut code_map {
+            Some(code_map) if evaluator.memory.is_executable(eip) => {
+                code_map.fetch(&evaluator.memory, eip as u64, &mut program, &mut line_table).unwrap_or_else(|err| {
+                    eprintln!("\x1b[31mError at {:#x}: {}\x1b[0m", eip, err);
+                    process::exit(1);
+                })
+            },
+            Some(_) => break,
+            None if eip < program.len() => eip,
+            None => break
+        };
+        let ins = &program[index][..];
+        // Fetched instructions are evaluated with their jump targets as addresses.
+        let evaluated = code_map.as_ref().map_or(ins, |code_map| code_map.decoded(index));
+        evaluator.instruction_size = code_map.as_ref().map_or(1, |code_map| code_map.size(index) as i64);
         if verbose {
-            println!("{}", ins);
+            println!("{}", evaluated);
+        }
+        let error = match panic::catch_unwind(AssertUnwindSafe(|| evaluator.evaluate(evaluated))) {
+            Ok(Ok(())) => None,
+            Ok(Err(err)) => Some(err),
+            Err(_) => Some(format!("evaluating \"{}\" panicked", evaluated))
+        };
+        let step = if tracking { Some(evaluator.take_step(index)) } else { None };
+        if let (Some(tracer), Some(step)) = (&mut tracer, &step) {
+            tracer.record(step, ins, line_table.get(index)).expect("Could not write trace!");
+        }
+        if let Some(err) = error {
+            if let Some(tracer) = &mut tracer {
+                tracer.finish().expect("Could not write trace!");
+            }
+            eprintln!("\x1b[31mError at {}: {}\x1b[0m", line_table[index], err);
+            process::exit(1);
         }
-        evaluator.evaluate(ins).expect("Error");
+        if let (Some(difftester), Some(step)) = (&mut difftester, &step) {
+            if let Err(divergence) = difftester.check(step, ins, eip) {
+                if let Some(tracer) = &mut tracer {
+                    tracer.finish().expect("Could not write trace!");
+                }
+                eprintln!("\x1b[31mDivergence at {}: {}\x1b[0m", line_table[index], divergence);
+                process::exit(1);
+            }
+        }
+        if let Some(profiler) = &mut profiler {
+            profiler.record(index, ins, step.as_ref(), &line_table);
+        }
+        let (mut fetch_latency, mut data_latency) = (0, 0);
+        if let (Some(caches), Some(step)) = (&mut caches, &step) {
+            let function = line_table.function_name(index);
+            fetch_latency = match &code_map {
+                Some(code_map) => caches.fetch(eip as u64, code_map.size(index), &function),
+                None => caches.fetch(index as u64 * cache::INSTRUCTION_SIZE, cache::INSTRUCTION_SIZE, &function)
+            };
+            for access in &step.accesses {
+                data_latency += caches.data(access, &function);
+            }
+        }
+        let pc = index;
         eip = evaluator.registers["eip"]  as usize;
+        // Where execution continues as a program index, instructions that were not fetched yet
+        // count as taken jumps.
+        let next = match &code_map {
+            Some(code_map) => code_map.index(eip as u64).unwrap_or(usize::MAX),
+            None => eip
+        };
+        if let (Some(pipeline), Some(step)) = (&mut pipeline, &step) {
+            let timing = pipeline.record(step, ins, next, fetch_latency, data_latency, &line_table.function_name(pc));
+            if let Some(diagram) = &mut diagram {
+                diagram.record(ins_executed, ins, &timing);
+            }
+        }
+        if let Some(coverage) = &mut coverage {
+            coverage.record(pc, ins, next, &line_table);
+        }
+        if let (Some(branch_simulator), Some(target)) = (&mut branch_simulator, Evaluator::branch_target(ins)) {
+            branch_simulator.record(pc, target, next != pc + 1);
+        }
         ins_executed += 1;
+        if evaluator.exit_code.is_some() {
+            break;
+        }
 
         if debug {
-            for i in 0..program.len() {
-                if i == eip {
+            for (i, line) in program.iter().enumerate() {
+                let info = &line_table[i];
+                if i == 0 || line_table[i - 1].label != info.label || line_table[i - 1].file != info.file {
+                    println!("{}:", info.label.as_deref().unwrap_or(&info.file));
+                }
+                if let Some(source) = &info.source {
+                    if i == 0 || line_table[i - 1].source.as_ref() != Some(source) {
+                        let text = sources.get(&source.file).and_then(|lines| lines.get(source.line - 1)).map_or("", |text| text.trim());
+                        println!("\x1b[36m{:width$}{}:{}: {}\x1b[0m", "", source.file, source.line, text, width=digit_count + line_digit_count + 5);
+                    }
+                }
+                if i == next {
                     print!("-> ") 
                 }
                 else {
                     print!("   ");
                 }   
                 //│ != |
-                println!("{:width$}│{}", i, program[i], width=digit_count);
+                match &code_map {
+                    Some(code_map) => println!("{}", disassembler::format_line(&code_map.disassembly_line(&evaluator.memory, i), &symbols)),
+                    None => println!("{:width$}│{:line_width$}│{}", i, info.line, line, width=digit_count, line_width=line_digit_count)
+                }
             }
             let mut input = prompt("$ ");
-            while input != "stop" && input != "continue" && input != "" {
+            while input != "stop" && input != "continue" && !input.is_empty() {
                 if evaluator.registers.has_register(&input[..]) {
                     println!("{}", evaluator.registers[&input[..]]);
                 }
@@ -142,7 +540,55 @@ fn main() {
         }
     }
     let duration = start.elapsed();
+    if let Some(tracer) = &mut tracer {
+        tracer.finish().expect("Could not write trace!");
+    }
+    if let Some(difftester) = &difftester {
+        match difftester.finish() {
+            Ok(checked) => println!("\x1b[32mNo divergence from the reference in {} instructions\x1b[0m", checked),
+            Err(divergence) => {
+                eprintln!("\x1b[31mDivergence: {}\x1b[0m", divergence);
+                process::exit(1);
+            }
+        }
+    }
+    evaluator.set_tracking(false);
     println!("Total time elapsed: {}ms, {}ns | Executed {} instructions", duration.as_millis(), duration.as_nanos(), ins_executed);
+    if let Some(code) = evaluator.exit_code {
+        println!("Program exited with code {}", code);
+    }
+    if let Some(profiler) = &profiler {
+        profiler.print_report(profile_memory);
+        if let Some(path) = &profile_folded_file {
+            profiler.write_folded(path).unwrap_or_else(|err| eprintln!("Could not write folded stacks to \"{}\": {}", path, err));
+        }
+    }
+    if let Some(caches) = &caches {
+        caches.print_report();
+    }
+    if let Some(branch_simulator) = &branch_simulator {
+        branch_simulator.print_report(&line_table);
+    }
+    if let Some(pipeline) = &pipeline {
+        pipeline.print_report();
+    }
+    if let Some(diagram) = &diagram {
+        if print_diagram {
+            print!("{}", diagram.text());
+        }
+        if let Some(path) = &diagram_html_file {
+            diagram.write_html(path).unwrap_or_else(|err| eprintln!("Could not write pipeline diagram to \"{}\": {}", path, err));
+        }
+    }
+    if let Some(coverage) = &coverage {
+        println!("{}", coverage.summary());
+        if let Some(path) = &coverage_file {
+            coverage.write_lcov(path, &program, &line_table).unwrap_or_else(|err| eprintln!("Could not write coverage to \"{}\": {}", path, err));
+        }
+        if let Some(path) = &coverage_report_file {
+            coverage.write_report(path, &program, &line_table).unwrap_or_else(|err| eprintln!("Could not write coverage report to \"{}\": {}", path, err));
+        }
+    }
 
     loop {
         match &prompt("$ ")[..] {
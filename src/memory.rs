@@ -1,12 +1,49 @@
+use std::cell::RefCell;
 use std::ops::{Index, IndexMut};
 use std::ops::Range;
 use std::cmp;
 
+use crate::decoder;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write
+}
+
+// A load or store done by an instruction, recorded for tracing.
+#[derive(Clone, Debug)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    pub address: usize,
+    pub size: usize,
+    pub value: i64
+}
+
+// A block of memory mapped at a fixed address, such as the machine code of the program.
+pub struct Segment {
+    pub start: usize,
+    pub data: Vec<u8>,
+    pub writable: bool,
+    pub executable: bool
+}
+
+impl Segment {
+    fn contains(&self, address: usize) -> bool {
+        address >= self.start && address - self.start < self.data.len()
+    }
+}
+
 pub struct Memory {
     pub stack_memory: Vec<u8>,
     pub program_break: usize,
     pub heap_memory: Vec<u8>,
     pub virtual_memory_size: usize,
+    // Mapped segments take precedence over the stack and the heap.
+    pub segments: Vec<Segment>,
+    // When enabled, every load and store is recorded for tracing.
+    pub tracking: bool,
+    accesses: RefCell<Vec<MemoryAccess>>,
     verbose: bool
 }
 
@@ -17,10 +54,71 @@ impl Memory {
             program_break: 0,
             heap_memory:  Vec::new(),
             virtual_memory_size: 4096,
+            segments: Vec::new(),
+            tracking: false,
+            accesses: RefCell::new(Vec::new()),
             verbose
         }
     }
 
+    // Load a little endian value of byte_count bytes without sign extension.
+    pub fn load(&self, address: i64, byte_count: usize) -> i64 {
+        let start = address as usize;
+        let mut value: [u8; 8] = Default::default();
+        value[..byte_count].copy_from_slice(&self[start..start + byte_count]);
+        let value = i64::from_le_bytes(value);
+        if self.tracking {
+            self.accesses.borrow_mut().push(MemoryAccess { kind: AccessKind::Read, address: start, size: byte_count, value });
+        }
+        value
+    }
+
+    pub fn map_segment(&mut self, segment: Segment) {
+        self.segments.push(segment);
+    }
+
+    // The instruction at address, compressed instructions in the lower 16 bits. Only executable
+    // segments can be fetched from and fetches are not recorded as memory accesses.
+    pub fn fetch(&self, address: usize) -> Result<u32, String> {
+        let segment = self.segments.iter().find(|segment| segment.executable && segment.contains(address))
+            .ok_or_else(|| format!("Address {:#x} is not executable", address))?;
+        let offset = address - segment.start;
+        let low = u16::from_le_bytes([segment.data[offset], *segment.data.get(offset + 1).unwrap_or(&0)]);
+        if !decoder::is_full_size(low) {
+            return Ok(low as u32);
+        }
+        match segment.data.get(offset..offset + 4) {
+            Some(bytes) => Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+            None => Err(format!("Instruction at {:#x} runs past the end of its segment", address))
+        }
+    }
+
+    // Copy bytes into memory without recording them as accesses, like a loader does it can write
+    // to read only segments.
+    pub fn write_bytes(&mut self, address: usize, bytes: &[u8]) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            match self.segment_index(address + i) {
+                Some(index) => {
+                    let segment = &mut self.segments[index];
+                    segment.data[address + i - segment.start] = byte;
+                },
+                None => self[address + i] = byte
+            }
+        }
+    }
+
+    pub fn is_executable(&self, address: usize) -> bool {
+        self.segments.iter().any(|segment| segment.executable && segment.contains(address))
+    }
+
+    fn segment_index(&self, address: usize) -> Option<usize> {
+        self.segments.iter().position(|segment| segment.contains(address))
+    }
+
+    pub fn take_accesses(&mut self) -> Vec<MemoryAccess> {
+        self.accesses.take()
+    }
+
     pub fn load_from(&self, address: i64) -> i64 {
         let address = address as usize;
         let mut value: [u8; 8] = Default::default();
@@ -30,6 +128,10 @@ impl Memory {
 
     pub fn store_to(&mut self, address: i64, value: i64, byte_count: usize) {
         let address = address as usize;
+        if self.tracking {
+            let value = if byte_count < 8 { value & ((1 << (byte_count * 8)) - 1) } else { value };
+            self.accesses.borrow_mut().push(MemoryAccess { kind: AccessKind::Write, address, size: byte_count, value });
+        }
         let bytes = value.to_le_bytes();
         //println!("{} {} {} {}", bytes[0], bytes[1], bytes[2], bytes[3]);
         for i in 0..byte_count {
@@ -69,6 +171,9 @@ impl Memory {
     }
 
     fn is_address_valid(&self, address: usize) -> bool {
+        if self.segment_index(address).is_some() {
+            return true;
+        }
         if self.is_stack_address(address as i32) {
             return address - (self.virtual_memory_size - self.stack_memory.len()) < self.stack_memory.len()
         }
@@ -79,6 +184,9 @@ impl Memory {
 impl Index<usize> for Memory {
     type Output = u8;
     fn index(&self, address: usize) -> &Self::Output {
+        if let Some(i) = self.segment_index(address) {
+            return &self.segments[i].data[address - self.segments[i].start];
+        }
         assert!(self.is_address_valid(address), "Address {:#x} is not in allocated memory space!", address);
         let index = self.address_to_index(address as i32);
         &self.get_segment_for_address(address as i32)[index]
@@ -88,6 +196,11 @@ impl Index<usize> for Memory {
 impl Index<Range<usize>> for Memory {
     type Output = [u8];
     fn index(&self, range: Range<usize>) -> &Self::Output {
+        if let Some(i) = self.segment_index(range.start) {
+            let segment = &self.segments[i];
+            assert!(range.end <= segment.start + segment.data.len(), "Range {:#x}..{:#x} has to be in the same segment!", range.start, range.end);
+            return &segment.data[range.start - segment.start..range.end - segment.start];
+        }
         assert!(self.is_address_valid(range.start), "Address {:#x} (start of range) is not in allocated memory space!", range.start);
         assert!(self.is_address_valid(range.end-1), "Address {:#x} (end of range) is not in allocated memory space!", range.end);
         let segment = self.get_segment_for_address(range.start as i32);
@@ -102,6 +215,11 @@ impl Index<Range<usize>> for Memory {
 
 impl IndexMut<usize> for Memory {
     fn index_mut(&mut self, address: usize) -> &mut Self::Output {
+        if let Some(i) = self.segment_index(address) {
+            let segment = &mut self.segments[i];
+            assert!(segment.writable, "Address {:#x} is not writable!", address);
+            return &mut segment.data[address - segment.start];
+        }
         assert!(self.is_address_valid(address), "Address {:#x} is not in allocated memory space!", address);
         let index = self.address_to_index(address as i32);
         &mut self.get_segment_for_address_mut(address as i32)[index]
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+
+use crate::branch_predictor::Predictor;
+use crate::evaluator::{Evaluator, Step};
+use crate::memory::AccessKind;
+
+pub const STAGE_NAMES: [&str; 5] = ["IF", "ID", "EX", "MEM", "WB"];
+const IF: usize = 0;
+const ID: usize = 1;
+const EX: usize = 2;
+const MEM: usize = 3;
+const WB: usize = 4;
+
+pub const MULTIPLY_LATENCY: u64 = 3;
+pub const DIVIDE_LATENCY: u64 = 20;
+
+// When an instruction went through the pipeline.
+#[derive(Clone)]
+pub struct Timing {
+    pub pc: usize,
+    // The cycle the instruction entered each stage, it stays in a stage until it enters the next one.
+    pub entered: [u64; 5],
+    // Cycles each stage needs without stalls (cache misses, multiply and divide latency).
+    pub durations: [u64; 5],
+    // Wrong path instructions fetched after this one and flushed when it was resolved.
+    pub flushed: u64
+}
+
+impl Timing {
+    pub fn left(&self, stage: usize) -> u64 {
+        if stage == WB { self.entered[WB] + 1 } else { self.entered[stage + 1] }
+    }
+
+    // The stage the instruction is in during a cycle, and whether it is stalled there.
+    pub fn stage_at(&self, cycle: u64) -> Option<(usize, bool)> {
+        (IF..=WB).find(|&stage| self.entered[stage] <= cycle && cycle < self.left(stage))
+            .map(|stage| (stage, cycle - self.entered[stage] >= self.durations[stage]))
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct FunctionCycles {
+    instructions: u64,
+    cycles: u64
+}
+
+// Estimates the cycles a classic five-stage in-order pipeline with full forwarding needs for the
+// executed instruction stream. Branches are resolved in EX, direct jumps in ID. Correctly predicted
+// taken branches cost nothing, as if the target came from a branch target buffer.
+pub struct Pipeline {
+    predictor: Box<dyn Predictor>,
+    previous: Option<Timing>,
+    // First cycle the value of a register can be forwarded to EX.
+    ready: HashMap<String, u64>,
+    // Registers written by loads, to tell load-use hazards from other data hazards.
+    loaded: HashMap<String, bool>,
+    // Earliest cycle the next instruction can be fetched after a taken or mispredicted jump.
+    redirect: u64,
+    functions: HashMap<String, FunctionCycles>,
+    instructions: u64,
+    load_use_stalls: u64,
+    data_stalls: u64,
+    mispredictions: u64,
+    flushed: u64,
+    memory_stalls: u64,
+    execute_cycles: u64
+}
+
+impl Pipeline {
+    pub fn new(predictor: Box<dyn Predictor>) -> Self {
+        Pipeline {
+            predictor,
+            previous: None,
+            ready: HashMap::new(),
+            loaded: HashMap::new(),
+            redirect: 0,
+            functions: HashMap::new(),
+            instructions: 0,
+            load_use_stalls: 0,
+            data_stalls: 0,
+            mispredictions: 0,
+            flushed: 0,
+            memory_stalls: 0,
+            execute_cycles: 0
+        }
+    }
+
+    fn execute_latency(instruction: &str) -> u64 {
+        let instruction_name = instruction.split_once(' ').map_or(instruction, |split| split.0);
+        if instruction_name.starts_with("mul") {
+            MULTIPLY_LATENCY
+        } else if instruction_name.starts_with("div") || instruction_name.starts_with("rem") {
+            DIVIDE_LATENCY
+        } else {
+            1
+        }
+    }
+
+    // Account for an executed instruction, next_pc is where execution continued. The latencies are
+    // the extra cycles the instruction fetch and the memory accesses waited for the caches.
+    pub fn record(&mut self, step: &Step, instruction: &str, next_pc: usize, fetch_latency: u64, data_latency: u64, function: &str) -> Timing {
+        let pc = step.pc;
+        let is_load = step.accesses.iter().any(|access| access.kind == AccessKind::Read);
+        let durations = [1 + fetch_latency, 1, Self::execute_latency(instruction), 1 + data_latency, 1];
+        self.memory_stalls += fetch_latency + data_latency;
+        self.execute_cycles += durations[EX] - 1;
+
+        // A stage is free once the previous instruction moved on to the next stage.
+        let previous = self.previous.clone();
+        let free = |stage: usize| previous.as_ref().map_or(0, |previous| previous.left(stage));
+        let mut entered = [0; 5];
+        entered[IF] = free(IF).max(self.redirect);
+        entered[ID] = (entered[IF] + durations[IF]).max(free(ID));
+
+        let mut operands = 0;
+        let mut waits_for_load = false;
+        for (register, _) in &step.reads {
+            if let Some(&ready) = self.ready.get(register) {
+                if ready > operands {
+                    operands = ready;
+                    waits_for_load = self.loaded[register];
+                }
+            }
+        }
+        let structural = (entered[ID] + durations[ID]).max(free(EX));
+        entered[EX] = structural.max(operands);
+        if operands > structural {
+            if waits_for_load {
+                self.load_use_stalls += operands - structural;
+            } else {
+                self.data_stalls += operands - structural;
+            }
+        }
+        entered[MEM] = (entered[EX] + durations[EX]).max(free(MEM));
+        entered[WB] = (entered[MEM] + durations[MEM]).max(free(WB));
+
+        // Loads forward their value after MEM, everything else after EX.
+        let ready = if is_load { entered[MEM] + durations[MEM] } else { entered[EX] + durations[EX] };
+        for (register, _) in &step.writes {
+            if register != "zero" {
+                self.ready.insert(register.clone(), ready);
+                self.loaded.insert(register.clone(), is_load);
+            }
+        }
+
+        let instruction_name = instruction.split_once(' ').map_or(instruction, |split| split.0);
+        let taken = next_pc != pc + 1;
+        let redirect = if let Some(target) = Evaluator::branch_target(instruction) {
+            let predicted = self.predictor.predict(pc, target);
+            self.predictor.update(pc, target, taken);
+            if predicted != taken {
+                self.mispredictions += 1;
+                Some(entered[EX] + durations[EX])
+            } else {
+                None
+            }
+        } else if matches!(instruction_name, "j" | "jal" | "call" | "tail") {
+            Some(entered[ID] + durations[ID])
+        } else if taken {
+            // Indirect jumps (jr, jalr, ret) need the register value from EX.
+            Some(entered[EX] + durations[EX])
+        } else {
+            None
+        };
+        let mut flushed = 0;
+        if let Some(redirect) = redirect {
+            flushed = redirect.saturating_sub(entered[IF] + 1);
+            self.flushed += flushed;
+            self.redirect = redirect;
+        }
+
+        let timing = Timing { pc, entered, durations, flushed };
+        let cycles = match &self.previous {
+            Some(previous) => timing.left(WB) - previous.left(WB),
+            None => timing.left(WB)
+        };
+        let counts = self.functions.entry(function.to_owned()).or_default();
+        counts.instructions += 1;
+        counts.cycles += cycles;
+        self.instructions += 1;
+        self.previous = Some(timing.clone());
+        timing
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.previous.as_ref().map_or(0, |previous| previous.left(WB))
+    }
+
+    pub fn print_report(&self) {
+        let cpi = |cycles: u64, instructions: u64| if instructions == 0 { 0.0 } else { cycles as f64 / instructions as f64 };
+        println!("Pipeline ({} predictor): {} cycles for {} instructions, CPI {:.3}",
+            self.predictor.name(), self.cycles(), self.instructions, cpi(self.cycles(), self.instructions));
+        println!("{:>10} cycles stalled on load-use hazards", self.load_use_stalls);
+        println!("{:>10} cycles stalled on other data hazards", self.data_stalls);
+        println!("{:>10} mispredicted branches", self.mispredictions);
+        println!("{:>10} instructions flushed after branches and jumps", self.flushed);
+        println!("{:>10} extra cycles in multiply and divide", self.execute_cycles);
+        println!("{:>10} cycles waiting for cache misses", self.memory_stalls);
+
+        let mut functions: Vec<(&String, &FunctionCycles)> = self.functions.iter().collect();
+        functions.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(b.0)));
+        println!();
+        println!("{:>12} {:>12} {:>8}  function", "cycles", "instructions", "CPI");
+        for (name, counts) in functions {
+            println!("{:>12} {:>12} {:>8.3}  {}", counts.cycles, counts.instructions, cpi(counts.cycles, counts.instructions), name);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use std::fs;
+use std::io;
+
+use crate::pipeline::{Timing, STAGE_NAMES};
+
+// What a row of the diagram shows in one cycle.
+#[derive(Clone, Copy, PartialEq)]
+enum Cell {
+    Empty,
+    Stage(usize),
+    // The instruction waits in a stage longer than the stage needs.
+    Stall(usize),
+    // A wrong path instruction that is thrown away when the branch before it is resolved.
+    Flushed(usize)
+}
+
+struct Row {
+    // Number in the executed instruction stream and program index, None for flushed instructions.
+    position: Option<(usize, usize)>,
+    instruction: String,
+    cells: Vec<Cell>
+}
+
+impl Row {
+    fn label(&self) -> String {
+        match self.position {
+            Some((number, index)) => format!("{:>5} {:>5}  {}", number, index, self.instruction),
+            None => format!("{:>5} {:>5}  {}", "", "", self.instruction)
+        }
+    }
+}
+
+// The cycles a window of executed instructions spent in each pipeline stage.
+pub struct PipelineDiagram {
+    first: usize,
+    count: usize,
+    instructions: Vec<(usize, String, Timing)>
+}
+
+impl PipelineDiagram {
+    // Collect the instructions first..first + count of the executed instruction stream.
+    pub fn new(first: usize, count: usize) -> Self {
+        PipelineDiagram { first, count, instructions: Vec::new() }
+    }
+
+    pub fn record(&mut self, number: usize, instruction: &str, timing: &Timing) {
+        if number >= self.first && number < self.first + self.count {
+            self.instructions.push((number, instruction.to_owned(), timing.clone()));
+        }
+    }
+
+    // The first cycle and the rows of the diagram, including the flushed wrong path instructions.
+    fn rows(&self) -> (u64, Vec<Row>) {
+        let first_cycle = self.instructions.first().map_or(0, |(_, _, timing)| timing.entered[0]);
+        let last_cycle = self.instructions.last().map_or(0, |(_, _, timing)| timing.left(4));
+        let columns = (last_cycle - first_cycle) as usize;
+
+        let mut rows = Vec::new();
+        for (number, instruction, timing) in &self.instructions {
+            let cells = (first_cycle..last_cycle).map(|cycle| match timing.stage_at(cycle) {
+                Some((stage, false)) => Cell::Stage(stage),
+                Some((stage, true)) => Cell::Stall(stage),
+                None => Cell::Empty
+            }).collect();
+            rows.push(Row { position: Some((*number, timing.pc)), instruction: instruction.clone(), cells });
+
+            // Wrong path instruction k is fetched k cycles after the jump and advances a stage every
+            // cycle, staying k stages behind the jump, until the fetch is redirected.
+            let redirect = timing.entered[0] + timing.flushed + 1;
+            for k in 1..=timing.flushed {
+                let fetched = timing.entered[0] + k;
+                let mut cells = vec![Cell::Empty; columns];
+                for cycle in fetched..redirect.min(last_cycle) {
+                    let behind_jump = timing.stage_at(cycle).map_or(0, |(stage, _)| stage).saturating_sub(k as usize);
+                    cells[(cycle - first_cycle) as usize] = Cell::Flushed(((cycle - fetched) as usize).min(behind_jump));
+                }
+                rows.push(Row { position: None, instruction: "(flushed)".to_owned(), cells });
+            }
+        }
+        (first_cycle, rows)
+    }
+
+    // Stage names per cycle, stalls in lower case and flushed instructions crossed out with x.
+    pub fn text(&self) -> String {
+        let (first_cycle, rows) = self.rows();
+        let label_width = rows.iter().map(|row| row.label().chars().count()).max().unwrap_or(0);
+        let columns = rows.first().map_or(0, |row| row.cells.len());
+
+        let mut text = format!("{:label_width$}", "    # index  instruction");
+        for column in 0..columns {
+            text += &format!(" {:>4}", first_cycle + column as u64);
+        }
+        text += "\n";
+        for row in rows {
+            text += &format!("{:label_width$}", row.label());
+            for cell in row.cells {
+                let content = match cell {
+                    Cell::Empty => String::new(),
+                    Cell::Stage(stage) => STAGE_NAMES[stage].to_owned(),
+                    Cell::Stall(stage) => STAGE_NAMES[stage].to_lowercase(),
+                    Cell::Flushed(stage) => format!("x{}", STAGE_NAMES[stage])
+                };
+                text += &format!(" {:>4}", content);
+            }
+            text = text.trim_end().to_owned() + "\n";
+        }
+        text
+    }
+
+    // A self-contained HTML page with the diagram as a table.
+    pub fn write_html(&self, path: &str) -> io::Result<()> {
+        let escape = |text: &str| text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+        let (first_cycle, rows) = self.rows();
+        let columns = rows.first().map_or(0, |row| row.cells.len());
+
+        let mut html = String::from(r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Pipeline diagram</title>
+<style>
+body { font-family: sans-serif; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #ccc; padding: 2px 6px; text-align: center; font-size: 13px; }
+td.label { text-align: left; font-family: monospace; white-space: pre; }
+.IF { background: #cfe2ff; } .ID { background: #d1e7dd; } .EX { background: #fff3cd; }
+.MEM { background: #f8d7da; } .WB { background: #e2d9f3; }
+.stall { background: repeating-linear-gradient(45deg, #eee, #eee 4px, #fff 4px, #fff 8px); color: #888; }
+.flushed { background: #eee; color: #999; text-decoration: line-through; }
+tr.flushed td.label { color: #999; }
+</style>
+</head>
+<body>
+<h1>Pipeline diagram</h1>
+<p>Instructions "#);
+        html += &format!("{} to {}", self.first, self.first + self.instructions.len().saturating_sub(1));
+        html += r#" of the executed instruction stream. Hatched cells are stalls, crossed out rows are wrong path instructions flushed after a jump or a mispredicted branch.</p>
+<table>
+<tr><th>#</th><th>index</th><th>instruction</th>"#;
+        for column in 0..columns {
+            html += &format!("<th>{}</th>", first_cycle + column as u64);
+        }
+        html += "</tr>\n";
+
+        for row in rows {
+            match row.position {
+                Some((number, index)) => html += &format!("<tr><td>{}</td><td>{}</td>", number, index),
+                None => html += "<tr class=\"flushed\"><td></td><td></td>"
+            }
+            html += &format!("<td class=\"label\">{}</td>", escape(&row.instruction));
+            for cell in row.cells {
+                html += &match cell {
+                    Cell::Empty => "<td></td>".to_owned(),
+                    Cell::Stage(stage) => format!("<td class=\"{0}\">{0}</td>", STAGE_NAMES[stage]),
+                    Cell::Stall(stage) => format!("<td class=\"stall\">{}</td>", STAGE_NAMES[stage]),
+                    Cell::Flushed(stage) => format!("<td class=\"flushed\">{}</td>", STAGE_NAMES[stage])
+                };
+            }
+            html += "</tr>\n";
+        }
+        html += "</table>\n</body>\n</html>\n";
+        fs::write(path, html)
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::evaluator::{CallKind, Evaluator, Step};
+use crate::line_table::LineTable;
+
+#[derive(Clone, Copy, Default)]
+struct Counts {
+    instructions: u64,
+    memory_accesses: u64
+}
+
+// Attributes executed instructions to functions (the labels in the line table) and call stacks.
+pub struct Profiler {
+    // Function names from the outermost to the current function.
+    call_stack: Vec<String>,
+    // The call stack joined with ';', the key for the folded stacks.
+    stack_key: String,
+    exclusive: HashMap<String, Counts>,
+    inclusive: HashMap<String, Counts>,
+    folded: HashMap<String, u64>,
+    total: Counts
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            call_stack: Vec::new(),
+            stack_key: String::new(),
+            exclusive: HashMap::new(),
+            inclusive: HashMap::new(),
+            folded: HashMap::new(),
+            total: Counts::default()
+        }
+    }
+
+    // Account for the instruction that was just executed at pc, step is only there when memory
+    // accesses are tracked.
+    pub fn record(&mut self, pc: usize, instruction: &str, step: Option<&Step>, line_table: &LineTable) {
+        // Jumps between functions without a call (tail calls) replace the current frame.
+        let function = line_table.function_name(pc);
+        if self.call_stack.last() != Some(&function) {
+            self.call_stack.pop();
+            self.call_stack.push(function.clone());
+            self.stack_key = self.call_stack.join(";");
+        }
+
+        let memory_accesses = step.map_or(0, |step| step.accesses.len() as u64);
+        let add = |counts: &mut Counts| {
+            counts.instructions += 1;
+            counts.memory_accesses += memory_accesses;
+        };
+        add(&mut self.total);
+        add(self.exclusive.entry(function).or_default());
+        for (i, name) in self.call_stack.iter().enumerate() {
+            // Recursive functions are only counted once per instruction.
+            if !self.call_stack[..i].contains(name) {
+                add(self.inclusive.entry(name.clone()).or_default());
+            }
+        }
+        *self.folded.entry(self.stack_key.clone()).or_insert(0) += 1;
+
+        match Evaluator::call_kind(instruction) {
+            // The name of the new frame is filled in by the first instruction of the callee.
+            CallKind::Call => self.call_stack.push(String::new()),
+            CallKind::Return if self.call_stack.len() > 1 => {
+                self.call_stack.pop();
+                self.stack_key = self.call_stack.join(";");
+            },
+            _ => {}
+        }
+    }
+
+    // Print the flat profile sorted by the instructions executed in each function itself.
+    pub fn print_report(&self, show_memory: bool) {
+        let mut functions: Vec<(&String, &Counts)> = self.exclusive.iter().collect();
+        functions.sort_by(|a, b| b.1.instructions.cmp(&a.1.instructions).then(a.0.cmp(b.0)));
+
+        let percentage = |count: u64, total: u64| if total == 0 { 0.0 } else { count as f64 * 100.0 / total as f64 };
+        print!("{:>7} {:>12} {:>7} {:>12}", "self %", "self", "incl %", "inclusive");
+        if show_memory {
+            print!(" {:>12} {:>12}", "self mem", "incl mem");
+        }
+        println!("  function");
+        for (name, exclusive) in functions {
+            let inclusive = self.inclusive.get(name).copied().unwrap_or_default();
+            print!("{:>6.2}% {:>12} {:>6.2}% {:>12}",
+                percentage(exclusive.instructions, self.total.instructions), exclusive.instructions,
+                percentage(inclusive.instructions, self.total.instructions), inclusive.instructions);
+            if show_memory {
+                print!(" {:>12} {:>12}", exclusive.memory_accesses, inclusive.memory_accesses);
+            }
+            println!("  {}", name);
+        }
+        print!("{:>7} {:>12} {:>7} {:>12}", "", self.total.instructions, "", "");
+        if show_memory {
+            print!(" {:>12} {:>12}", self.total.memory_accesses, "");
+        }
+        println!("  total");
+    }
+
+    // Write the call stacks in the folded format used by flamegraph tools ("main;sort;swap 42").
+    pub fn write_folded(&self, path: &str) -> io::Result<()> {
+        let mut stacks: Vec<(&String, &u64)> = self.folded.iter().collect();
+        stacks.sort();
+        let mut writer = BufWriter::new(File::create(path)?);
+        for (stack, count) in stacks {
+            writeln!(writer, "{} {}", stack, count)?;
+        }
+        writer.flush()
+    }
+}
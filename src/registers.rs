@@ -1,15 +1,32 @@
-use std::{collections::HashMap, ops::{Index, IndexMut}};
+use std::{cell::RefCell, collections::HashMap, ops::{Index, IndexMut}};
+
+// Register names with their values, in the order they were accessed.
+pub type RegisterValues = Vec<(String, i64)>;
 
 pub struct Registers {
-    pub variables: HashMap<String, i64>
+    pub variables: HashMap<String, i64>,
+    // When enabled, the names of all registers read and written are recorded for tracing.
+    pub tracking: bool,
+    reads: RefCell<RegisterValues>,
+    writes: Vec<String>
 }
 
 impl Registers {
+    // All general purpose registers in their architectural order, NAMES[n] is register xn.
+    pub const NAMES: [&str; 32] = [
+        "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1",
+        "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7",
+        "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11",
+        "t3", "t4", "t5", "t6"
+    ];
+
     pub fn new() -> Self {
         let mut variables: HashMap<String, i64> = HashMap::new();
         variables.insert("zero".to_owned(), 0);
         variables.insert("ra".to_owned(), Self::random_data());
         variables.insert("sp".to_owned(), Self::random_data());
+        variables.insert("gp".to_owned(), Self::random_data());
+        variables.insert("tp".to_owned(), Self::random_data());
         variables.insert("eip".to_owned(), Self::random_data());
 
         variables.insert("t0".to_owned(), Self::random_data());
@@ -45,30 +62,62 @@ impl Registers {
         variables.insert("t6".to_owned(), Self::random_data());
 
         Registers {
-            variables: variables
+            variables,
+            tracking: false,
+            reads: RefCell::new(Vec::new()),
+            writes: Vec::new()
         }
     }
 
     fn random_data() -> i64 {
         let ptr = Box::into_raw(Box::new(123));
-        return ptr as i64;
+        ptr as i64
     }
 
     pub fn has_register(&self, register: &str) -> bool {
         self.variables.contains_key(register)
     }
+
+    // Registers read (with the value at the time) and written (with their current value) since
+    // the last call, without duplicates and excluding eip.
+    pub fn take_accesses(&mut self) -> (RegisterValues, RegisterValues) {
+        let mut reads = self.reads.take();
+        let mut seen = Vec::new();
+        reads.retain(|(register, _)| {
+            if seen.contains(register) {
+                return false;
+            }
+            seen.push(register.clone());
+            true
+        });
+
+        let mut writes: RegisterValues = Vec::new();
+        for register in std::mem::take(&mut self.writes) {
+            if !writes.iter().any(|(written, _)| *written == register) {
+                let value = self.variables[&register];
+                writes.push((register, value));
+            }
+        }
+        (reads, writes)
+    }
 }
 
 impl Index<&str> for Registers {
     type Output = i64;
 
     fn index(&self, register: &str) -> &Self::Output {
+        if self.tracking && register != "eip" {
+            self.reads.borrow_mut().push((register.to_owned(), self.variables[register]));
+        }
         &self.variables[register]
     }
 }
 
 impl IndexMut<&str> for Registers {
     fn index_mut(&mut self, register: &str) -> &mut Self::Output {
+        if self.tracking && register != "eip" {
+            self.writes.push(register.to_owned());
+        }
         &mut *self.variables.get_mut(register).unwrap()
     }
 }
@@ -0,0 +1,125 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::evaluator::Step;
+use crate::json::Json;
+use crate::line_table::LineInfo;
+use crate::memory::AccessKind;
+use crate::registers::{RegisterValues, Registers};
+
+const BINARY_MAGIC: &[u8; 8] = b"IASMTRC\0";
+const BINARY_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    // One JSON object per executed instruction.
+    JsonLines,
+    // Little endian records, after an 8 byte magic and a u32 version:
+    //   u64 pc, u16 length + instruction text,
+    //   u8 count + (u8 register, i64 value) for the registers read, the same for the registers written,
+    //   u16 count + (u8 kind (0 = read, 1 = write), u64 address, u8 size, i64 value) for memory accesses,
+    //   u8 flag, followed by i64 number, 3 x i64 arguments and i64 result when a syscall was made.
+    // Registers are numbered like their architectural name (x0 - x31).
+    Binary
+}
+
+impl TraceFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "json" | "jsonl" => Some(TraceFormat::JsonLines),
+            "binary" | "bin" => Some(TraceFormat::Binary),
+            _ => None
+        }
+    }
+}
+
+// Writes a record of every executed instruction to a file.
+pub struct Tracer {
+    writer: BufWriter<File>,
+    format: TraceFormat
+}
+
+impl Tracer {
+    pub fn create(path: &str, format: TraceFormat) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        if format == TraceFormat::Binary {
+            writer.write_all(BINARY_MAGIC)?;
+            writer.write_all(&BINARY_VERSION.to_le_bytes())?;
+        }
+        Ok(Tracer { writer, format })
+    }
+
+    pub fn record(&mut self, step: &Step, instruction: &str, location: Option<&LineInfo>) -> io::Result<()> {
+        let pc = step.pc;
+        match self.format {
+            TraceFormat::JsonLines => {
+                let registers = |registers: &RegisterValues| Json::Object(registers.iter().map(|(name, value)| (name.clone(), Json::from(*value))).collect());
+                let mut record = vec![
+                    ("pc", Json::from(pc)),
+                    ("instruction", Json::from(instruction))
+                ];
+                if let Some(location) = location {
+                    record.push(("location", Json::from(format!("{}:{}:{}", location.file, location.line, location.column))));
+                    if let Some(source) = &location.source {
+                        record.push(("source", Json::from(format!("{}:{}:{}", source.file, source.line, source.column))));
+                    }
+                    if let Some(label) = &location.label {
+                        record.push(("function", Json::from(&label[..])));
+                    }
+                }
+                record.push(("reads", registers(&step.reads)));
+                record.push(("writes", registers(&step.writes)));
+                record.push(("memory", Json::from(step.accesses.iter().map(|access| Json::object(vec![
+                    ("kind", Json::from(if access.kind == AccessKind::Read { "read" } else { "write" })),
+                    ("address", Json::from(access.address)),
+                    ("size", Json::from(access.size)),
+                    ("value", Json::from(access.value))
+                ])).collect::<Vec<Json>>())));
+                if let Some(syscall) = &step.syscall {
+                    record.push(("syscall", Json::object(vec![
+                        ("number", Json::from(syscall.number)),
+                        ("arguments", Json::from(syscall.arguments.iter().map(|&argument| Json::from(argument)).collect::<Vec<Json>>())),
+                        ("result", Json::from(syscall.result))
+                    ])));
+                }
+                writeln!(self.writer, "{}", Json::object(record))
+            },
+            TraceFormat::Binary => {
+                let w = &mut self.writer;
+                w.write_all(&(pc as u64).to_le_bytes())?;
+                w.write_all(&(instruction.len() as u16).to_le_bytes())?;
+                w.write_all(instruction.as_bytes())?;
+                for registers in [&step.reads, &step.writes] {
+                    w.write_all(&[registers.len() as u8])?;
+                    for (name, value) in registers {
+                        let number = Registers::NAMES.iter().position(|register| register == name).unwrap_or(u8::MAX as usize);
+                        w.write_all(&[number as u8])?;
+                        w.write_all(&value.to_le_bytes())?;
+                    }
+                }
+                w.write_all(&(step.accesses.len() as u16).to_le_bytes())?;
+                for access in &step.accesses {
+                    w.write_all(&[if access.kind == AccessKind::Read { 0 } else { 1 }])?;
+                    w.write_all(&(access.address as u64).to_le_bytes())?;
+                    w.write_all(&[access.size as u8])?;
+                    w.write_all(&access.value.to_le_bytes())?;
+                }
+                match &step.syscall {
+                    Some(syscall) => {
+                        w.write_all(&[1])?;
+                        w.write_all(&syscall.number.to_le_bytes())?;
+                        for argument in syscall.arguments {
+                            w.write_all(&argument.to_le_bytes())?;
+                        }
+                        w.write_all(&syscall.result.to_le_bytes())
+                    },
+                    None => w.write_all(&[0])
+                }
+            }
+        }
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}